@@ -7,6 +7,7 @@ mod tests {
     use muter::chain::Chain;
     use muter::codec::registry::CodecRegistry;
     use muter::codec::tests::BYTE_SEQ;
+    use std::collections::BTreeMap;
     use test::Bencher;
 
     const BUFFER_SIZE: usize = 8192;
@@ -24,14 +25,14 @@ mod tests {
 
     fn benchmark_forward(b: &mut Bencher, chain: &str) {
         let reg = CodecRegistry::new();
-        let c = Chain::new(&reg, &chain, BUFFER_SIZE, false);
+        let c = Chain::new(&reg, chain, BUFFER_SIZE, false);
         let inp = large_sequence();
         b.iter(|| c.transform(inp.clone()).unwrap());
     }
 
     fn benchmark_reverse(b: &mut Bencher, chain: &str, strict: bool) {
         let reg = CodecRegistry::new();
-        let c = Chain::new(&reg, &chain, BUFFER_SIZE, strict);
+        let c = Chain::new(&reg, chain, BUFFER_SIZE, strict);
         let seq = large_sequence();
         let inp = c.transform(seq).unwrap();
 
@@ -40,6 +41,36 @@ mod tests {
         b.iter(|| c.transform(inp.clone()).unwrap());
     }
 
+    #[bench]
+    fn compiled_small_values(b: &mut Bencher) {
+        let reg = CodecRegistry::new();
+        let c = Chain::new(&reg, "-hex:base64", BUFFER_SIZE, true)
+            .compile()
+            .unwrap();
+        let inp: Vec<Vec<u8>> = (0..256u32)
+            .map(|i| format!("{:08x}", i * 0x0101_0101).into_bytes())
+            .collect();
+        b.iter(|| {
+            for v in &inp {
+                c.transform(v).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    fn one_shot_small_values(b: &mut Bencher) {
+        let reg = CodecRegistry::new();
+        let inp: Vec<Vec<u8>> = (0..256u32)
+            .map(|i| format!("{:08x}", i * 0x0101_0101).into_bytes())
+            .collect();
+        b.iter(|| {
+            for v in &inp {
+                let v = reg.decode("hex", BTreeMap::new(), v).unwrap();
+                reg.encode("base64", BTreeMap::new(), &v).unwrap();
+            }
+        });
+    }
+
     macro_rules! benchmark {
         ($chain:expr, $name:ident) => {
             #[cfg(test)]
@@ -78,4 +109,7 @@ mod tests {
     benchmark!("uuencode", uuencode);
     benchmark!("vis", vis);
     benchmark!("xml", xml);
+
+    benchmark!("(base64:wrap)", base64_wrap);
+    benchmark!("(hex:base64:wrap)", hex_base64_wrap);
 }
@@ -1,6 +1,8 @@
 extern crate autocfg;
 
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(rustc_1_37)");
+    println!("cargo:rustc-check-cfg=cfg(has_range_bounds)");
     let ac = autocfg::new();
     ac.emit_rustc_version(1, 37);
     ac.emit_trait_cfg(
@@ -0,0 +1,470 @@
+//! Asynchronous readers and writers for chains.
+//!
+//! Stages implemented by a single codec are driven directly with `AsyncCodecReader`.  Other
+//! stages, such as the compression transforms, aliases, and alternations, are built as usual over
+//! a pipe and run on the task polling them, with more input fed into the pipe whenever they report
+//! that reading would block.  They therefore never block, but like any CPU-bound work, they may
+//! take some time to return.
+
+use super::{Chain, CompiledChain, CompiledStage, Pipe, PipeReader, StageError};
+use codec;
+use codec::asyncio::AsyncCodecReader;
+use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::io::BufRead;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+type AsyncSource<'a> = Box<AsyncBufRead + Unpin + 'a>;
+
+/// Implement `AsyncRead` in terms of `AsyncBufRead`.
+fn poll_read_buffered<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    cx: &mut Context,
+    dst: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    let n = match Pin::new(&mut *r).poll_fill_buf(cx) {
+        Poll::Ready(Ok(data)) => {
+            let n = cmp::min(data.len(), dst.len());
+            dst[..n].copy_from_slice(&data[..n]);
+            n
+        }
+        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+        Poll::Pending => return Poll::Pending,
+    };
+    Pin::new(r).consume(n);
+    Poll::Ready(Ok(n))
+}
+
+/// A stage which is not implemented by a single codec, run synchronously over a pipe.
+struct BlockingStage<'a> {
+    chain: CompiledChain<'a>,
+    stage: CompiledStage,
+    src: AsyncSource<'a>,
+    pipe: Rc<RefCell<Pipe>>,
+    // The output of the stage, or `None` if it could not be built until all the input is present.
+    reader: Option<Box<io::BufRead>>,
+}
+
+impl<'a> BlockingStage<'a> {
+    fn new(
+        chain: CompiledChain<'a>,
+        stage: CompiledStage,
+        src: AsyncSource<'a>,
+    ) -> io::Result<Self> {
+        let mut obj = BlockingStage {
+            chain,
+            stage,
+            src,
+            pipe: Rc::new(RefCell::new(Pipe::default())),
+            reader: None,
+        };
+        obj.reader = match obj.build() {
+            Ok(r) => Some(r),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => return Err(e),
+        };
+        Ok(obj)
+    }
+
+    fn build(&self) -> io::Result<Box<io::BufRead>> {
+        let src = PipeReader {
+            pipe: self.pipe.clone(),
+        };
+        self.chain.build_stage(
+            &self.stage,
+            Box::new(io::BufReader::with_capacity(self.chain.bufsize, src)),
+        )
+    }
+}
+
+impl<'a> AsyncBufRead for BlockingStage<'a> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        loop {
+            if this.reader.is_none() && this.pipe.borrow().closed {
+                match this.build() {
+                    Ok(r) => this.reader = Some(r),
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+            // Check whether the stage has output first so the borrow of its buffer doesn't
+            // conflict with feeding it more input.  Asking a finished stage for more data might
+            // read beyond the end of its input, so don't ask it again.
+            let ready = match this.reader {
+                Some(ref mut r) => match r.fill_buf() {
+                    Ok([]) => return Poll::Ready(Ok(&[])),
+                    Ok(_) => true,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                    Err(e) => return Poll::Ready(Err(e)),
+                },
+                None => false,
+            };
+            if ready {
+                break;
+            }
+            let n = match Pin::new(&mut this.src).poll_fill_buf(cx) {
+                Poll::Ready(Ok(data)) => {
+                    let mut pipe = this.pipe.borrow_mut();
+                    pipe.data.extend(data);
+                    pipe.closed = data.is_empty();
+                    data.len()
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            Pin::new(&mut this.src).consume(n);
+        }
+        Poll::Ready(this.reader.as_mut().unwrap().fill_buf())
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        if let Some(ref mut r) = self.get_mut().reader {
+            r.consume(amt);
+        }
+    }
+}
+
+impl<'a> AsyncRead for BlockingStage<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_read_buffered(self.get_mut(), cx, dst)
+    }
+}
+
+/// A reader which attributes any errors from the stage it reads from to that stage.
+struct AsyncStageReader<'a> {
+    inner: AsyncSource<'a>,
+    stage: CompiledStage,
+    index: usize,
+    chain: Rc<str>,
+}
+
+impl<'a> AsyncBufRead for AsyncStageReader<'a> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(StageError::wrap(
+                &this.stage,
+                this.index,
+                &this.chain,
+                e,
+            ))),
+            p => p,
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+impl<'a> AsyncRead for AsyncStageReader<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_read_buffered(self.get_mut(), cx, dst)
+    }
+}
+
+impl<'a> Chain<'a> {
+    /// Instantiate the codecs in the chain, reading asynchronously from `src`.
+    ///
+    /// See `CompiledChain::async_reader` for details.
+    pub fn async_reader<R: AsyncBufRead + Unpin + 'a>(
+        &self,
+        src: R,
+    ) -> io::Result<Box<AsyncBufRead + Unpin + 'a>> {
+        self.compile()?.async_reader(src)
+    }
+
+    /// Instantiate the codecs in the chain, writing the output asynchronously to `w`.
+    pub fn async_writer<W: AsyncWrite + Unpin + 'a>(
+        &self,
+        w: W,
+    ) -> io::Result<AsyncChainWriter<'a, W>> {
+        self.compile()?.async_writer(w)
+    }
+}
+
+impl<'a> CompiledChain<'a> {
+    /// Instantiate the codecs in the chain, reading asynchronously from `src`.
+    ///
+    /// Errors are wrapped in a `StageError` as for `build`.  Stages which need the entire input
+    /// before producing any output, such as alternations, report that they are pending until the
+    /// input is exhausted.
+    pub fn async_reader<R: AsyncBufRead + Unpin + 'a>(
+        &self,
+        src: R,
+    ) -> io::Result<Box<AsyncBufRead + Unpin + 'a>> {
+        self.async_reader_boxed(Box::new(src))
+    }
+
+    /// Instantiate the codecs in the chain, writing the output asynchronously to `w`.
+    pub fn async_writer<W: AsyncWrite + Unpin + 'a>(
+        &self,
+        w: W,
+    ) -> io::Result<AsyncChainWriter<'a, W>> {
+        let pipe = Rc::new(RefCell::new(Pipe::default()));
+        let src = PipeSource {
+            pipe: pipe.clone(),
+            buf: Vec::new(),
+            pos: 0,
+        };
+        Ok(AsyncChainWriter {
+            pipe,
+            reader: self.async_reader_boxed(Box::new(src))?,
+            w,
+            closed: false,
+        })
+    }
+
+    fn async_reader_boxed(&self, src: AsyncSource<'a>) -> io::Result<AsyncSource<'a>> {
+        let chain: Rc<str> = Rc::from(self.chain.as_str());
+        let mut cur = src;
+        for (i, stage) in self.stages.iter().enumerate() {
+            cur = self
+                .async_stage(stage, cur)
+                .map_err(|e| StageError::wrap(stage, i + 1, &chain, e))?;
+            cur = Box::new(AsyncStageReader {
+                inner: cur,
+                stage: stage.clone(),
+                index: i + 1,
+                chain: chain.clone(),
+            });
+        }
+        Ok(cur)
+    }
+
+    fn async_stage(
+        &self,
+        stage: &CompiledStage,
+        src: AsyncSource<'a>,
+    ) -> io::Result<AsyncSource<'a>> {
+        if let CompiledStage::Transform(ref name, ref settings, _) = *stage {
+            if let Some(c) = self.codecs.codec(name, settings.clone())? {
+                return Ok(Box::new(AsyncCodecReader::new(src, c, settings.bufsize)));
+            }
+        }
+        Ok(Box::new(BlockingStage::new(
+            self.clone(),
+            stage.clone(),
+            src,
+        )?))
+    }
+}
+
+/// The source for a chain being driven by an `AsyncChainWriter`.
+///
+/// When it has no data but the writer has not been closed, it is pending without arranging to be
+/// woken, since only the writer polls it and it knows when there is more data.
+struct PipeSource {
+    pipe: Rc<RefCell<Pipe>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncBufRead for PipeSource {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.buf.len() {
+            let mut pipe = this.pipe.borrow_mut();
+            if pipe.data.is_empty() && !pipe.closed {
+                return Poll::Pending;
+            }
+            this.buf.clear();
+            this.buf.extend(pipe.data.drain(..));
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = cmp::min(this.pos + amt, this.buf.len());
+    }
+}
+
+impl AsyncRead for PipeSource {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_read_buffered(self.get_mut(), cx, dst)
+    }
+}
+
+/// An asynchronous writer which feeds the data written to it through a chain and writes the
+/// result to another writer.
+///
+/// As with `ChainWriter`, chains containing an alternation produce no output until the end.  The
+/// end of the input is signalled by closing the writer.
+pub struct AsyncChainWriter<'a, W: AsyncWrite + Unpin> {
+    pipe: Rc<RefCell<Pipe>>,
+    reader: AsyncSource<'a>,
+    w: W,
+    closed: bool,
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncChainWriter<'a, W> {
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    /// Write all the output the chain can produce from the input so far.
+    ///
+    /// This is pending only if the underlying writer is.
+    fn poll_pump(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            let n = match Pin::new(&mut self.reader).poll_fill_buf(cx) {
+                // The chain needs more input.
+                Poll::Pending => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok([])) => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(data)) => match Pin::new(&mut self.w).poll_write(cx, data) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)))
+                    }
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            };
+            Pin::new(&mut self.reader).consume(n);
+        }
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for AsyncChainWriter<'a, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Err(io::Error::from(codec::Error::ExtraData)));
+        }
+        // Don't accept more data until the output from the last write has been written.
+        match this.poll_pump(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        this.pipe.borrow_mut().data.extend(data);
+        match this.poll_pump(cx) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Ready(Ok(data.len())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_pump(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.w).poll_flush(cx),
+            p => p,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.closed = true;
+        this.pipe.borrow_mut().closed = true;
+        match this.poll_pump(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.w).poll_close(cx),
+            p => p,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chain::Chain;
+    use chain::StageError;
+    use codec::registry::CodecRegistry;
+    use codec::tests;
+    use codec::tests::{block_on, ChoppyReader, ChoppyWriter};
+    use futures_io::{AsyncRead, AsyncWrite};
+    use std::io;
+    use std::pin::Pin;
+
+    fn read(c: &Chain, inp: &[u8]) -> io::Result<Vec<u8>> {
+        let mut r = c.async_reader(ChoppyReader::new(inp))?;
+        let mut out = Vec::new();
+        let mut buf = [0u8; 5];
+        loop {
+            match block_on(|cx| Pin::new(&mut r).poll_read(cx, &mut buf))? {
+                0 => return Ok(out),
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn write(c: &Chain, inp: &[u8]) -> io::Result<Vec<u8>> {
+        let mut w = c.async_writer(ChoppyWriter::default())?;
+        for chunk in inp.chunks(4) {
+            let mut data = chunk;
+            while !data.is_empty() {
+                let n = block_on(|cx| Pin::new(&mut w).poll_write(cx, data))?;
+                data = &data[n..];
+            }
+        }
+        block_on(|cx| Pin::new(&mut w).poll_close(cx))?;
+        let w = w.into_inner();
+        assert!(w.closed);
+        Ok(w.data)
+    }
+
+    #[test]
+    fn transforms_chains_asynchronously() {
+        let mut reg = CodecRegistry::new();
+        reg.insert_alias("double", "hex:base64").unwrap();
+        for chain in &[
+            "hex:base64",
+            "-hex:-(base64|hex)",
+            "gzip:-gzip:uri",
+            "zlib:-zlib:double:-double",
+        ] {
+            for &i in &[5, 6, 7, 8, 512] {
+                let inp = b"666f6f2062617220626171";
+                let c = Chain::new(&reg, *chain, i, true);
+                let expected = c.transform(inp.to_vec()).unwrap();
+                assert_eq!(read(&c, inp).unwrap(), expected, "{}", chain);
+                assert_eq!(write(&c, inp).unwrap(), expected, "{}", chain);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_failing_stage_asynchronously() {
+        let reg = CodecRegistry::new();
+        for chain in &["base64:-base64:-hex", "gzip:-gzip:-hex"] {
+            let c = Chain::new(&reg, *chain, 512, true);
+            for e in &[
+                read(&c, b"666g").unwrap_err(),
+                write(&c, b"666g").unwrap_err(),
+            ] {
+                let s = e.get_ref().unwrap().downcast_ref::<StageError>().unwrap();
+                assert_eq!(s.index, 3, "{}", chain);
+                assert_eq!(s.offset, Some(2), "{}", chain);
+                assert_eq!(
+                    tests::error_position(e).map(|p| p.offset),
+                    Some(2),
+                    "{}",
+                    chain
+                );
+            }
+        }
+    }
+}
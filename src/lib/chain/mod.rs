@@ -1,21 +1,43 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
 
+#[cfg(feature = "async")]
+pub mod asyncio;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::convert;
 use std::error;
 use std::fmt;
 use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::Mutex;
 
+use codec;
 use codec::registry::CodecRegistry;
 use codec::CodecSettings;
 use codec::Direction;
 
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
     MismatchedParentheses(String),
     InvalidName(String),
     InvalidArgument(String),
+    UnexpectedCharacter(char, usize),
+    UnterminatedQuote(usize),
+    TrailingBackslash(usize),
+    InvalidRepetition(usize),
+    NestingTooDeep(usize),
+    NoMatchingAlternative(Vec<(String, String)>),
 }
 
 impl fmt::Display for Error {
@@ -28,6 +50,34 @@ impl fmt::Display for Error {
                 write!(f, "{}", tr!("invalid transform name: {:?}", seq))
             }
             Error::InvalidArgument(ref seq) => write!(f, "{}", tr!("invalid argument: {:?}", seq)),
+            Error::UnexpectedCharacter(c, col) => write!(
+                f,
+                "{}",
+                tr!("unexpected character '{}' at column {}", c, col)
+            ),
+            Error::UnterminatedQuote(col) => write!(
+                f,
+                "{}",
+                tr!("unterminated quotation mark at column {}", col)
+            ),
+            Error::TrailingBackslash(col) => {
+                write!(f, "{}", tr!("backslash at end of chain at column {}", col))
+            }
+            Error::InvalidRepetition(col) => {
+                write!(f, "{}", tr!("invalid repetition count at column {}", col))
+            }
+            Error::NestingTooDeep(col) => write!(
+                f,
+                "{}",
+                tr!("parentheses nested too deeply at column {}", col)
+            ),
+            Error::NoMatchingAlternative(ref errs) => {
+                write!(f, "{}", tr!("no alternative matched"))?;
+                for (alt, err) in errs {
+                    write!(f, "; '{}': {}", alt, err)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -45,37 +95,262 @@ impl error::Error for Error {
 
 impl convert::From<Error> for io::Error {
     fn from(err: Error) -> Self {
-        io::Error::new(io::ErrorKind::InvalidInput, err)
+        match err {
+            Error::NoMatchingAlternative(_) => io::Error::new(io::ErrorKind::InvalidData, err),
+            _ => io::Error::new(io::ErrorKind::InvalidInput, err),
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// The largest number of stages a repetition may expand to, including the stages of any
+/// repetitions within it.
+pub const MAX_REPETITION: usize = 1000;
+
+/// The largest number of groups or alternations which may be nested within one another.
+pub const MAX_NESTING: usize = 100;
+
+/// The range of zero-based character columns a stage occupies in the text of a chain.
+type Span = (usize, usize);
+
+#[derive(Debug)]
 struct ChainTransform<'a> {
     name: &'a str,
     args: BTreeMap<String, Option<String>>,
     dir: Direction,
+    span: Span,
+}
+
+// The span is deliberately ignored, since it describes where the transform was written, not what
+// it does.
+impl<'a> PartialEq for ChainTransform<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.args == other.args && self.dir == other.dir
+    }
+}
+
+impl<'a> Eq for ChainTransform<'a> {}
+
+/// A node in the parsed syntax tree of a chain.
+///
+/// Directions stored in the tree are those written in the chain, relative to the enclosing group;
+/// they are resolved into absolute directions when the tree is flattened.
+#[derive(Debug, PartialEq, Eq)]
+enum ChainNode<'a> {
+    Transform(ChainTransform<'a>),
+    Group(Vec<ChainNode<'a>>, Direction),
+    Repeat(Box<ChainNode<'a>>, usize),
+    Alternation(Vec<(&'a str, Vec<ChainNode<'a>>)>, Direction, Span),
+}
+
+/// A stage of a flattened chain, with all directions resolved.
+#[derive(Debug, PartialEq, Eq)]
+enum ChainStage<'a> {
+    Transform(ChainTransform<'a>),
+    Alternation(Vec<Alternative<'a>>, Span),
+}
+
+/// One alternative of an alternation, along with the text used to report it.
+#[derive(Debug, PartialEq, Eq)]
+struct Alternative<'a> {
+    text: &'a str,
+    stages: Vec<ChainStage<'a>>,
+}
+
+type AlternativeCallback = Arc<Fn(&str) + Send + Sync>;
+
+/// A slot for each stage of a compiled chain holding a codec which can be used again.
+type CodecCache = Arc<Vec<Mutex<Option<Box<codec::Codec + Send>>>>>;
+
+/// The registry used by a chain, which is either borrowed or shared.
+///
+/// A chain using a shared registry borrows nothing, so it can be stored for as long as needed.
+#[derive(Clone)]
+pub enum Registry<'a> {
+    Borrowed(&'a CodecRegistry),
+    Shared(Arc<CodecRegistry>),
+}
+
+impl<'a> Deref for Registry<'a> {
+    type Target = CodecRegistry;
+
+    fn deref(&self) -> &CodecRegistry {
+        match *self {
+            Registry::Borrowed(r) => r,
+            Registry::Shared(ref r) => r,
+        }
+    }
+}
+
+impl<'a> convert::From<&'a CodecRegistry> for Registry<'a> {
+    fn from(r: &'a CodecRegistry) -> Self {
+        Registry::Borrowed(r)
+    }
+}
+
+impl<'a> convert::From<Arc<CodecRegistry>> for Registry<'a> {
+    fn from(r: Arc<CodecRegistry>) -> Self {
+        Registry::Shared(r)
+    }
+}
+
+/// A builder for a linear chain of transforms which owns all of its data.
+///
+/// The chain produced is identical to the one produced by parsing the string form of the builder,
+/// which is available through its `Display` implementation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainBuilder {
+    stages: Vec<(String, BTreeMap<String, Option<String>>, Direction)>,
+}
+
+impl ChainBuilder {
+    pub fn new() -> Self {
+        ChainBuilder { stages: Vec::new() }
+    }
+
+    /// Append a transform to be run forward.
+    #[must_use]
+    pub fn push(self, name: &str, args: BTreeMap<String, Option<String>>) -> Self {
+        self.push_stage(name, args, Direction::Forward)
+    }
+
+    /// Append a transform to be run in reverse.
+    #[must_use]
+    pub fn push_reverse(self, name: &str, args: BTreeMap<String, Option<String>>) -> Self {
+        self.push_stage(name, args, Direction::Reverse)
+    }
+
+    fn push_stage(
+        mut self,
+        name: &str,
+        args: BTreeMap<String, Option<String>>,
+        dir: Direction,
+    ) -> Self {
+        self.stages.push((name.to_string(), args, dir));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Check that each transform name is a single name, which parses back as itself.
+    ///
+    /// Returns `Error::InvalidName` for the first name which is not.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.stages.iter().find(|(name, _, _)| {
+            name.is_empty() || name.starts_with('-') || name.chars().any(Parser::is_special)
+        }) {
+            Some((name, _, _)) => Err(Error::InvalidName(name.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a chain from this builder using the codecs in `codecs`.
+    ///
+    /// If `codecs` is an `Arc<CodecRegistry>`, the chain borrows nothing.  Returns
+    /// `Error::InvalidName` if any transform name is not a single name.
+    pub fn into_chain<'a, R: Into<Registry<'a>>>(
+        self,
+        codecs: R,
+        bufsize: usize,
+        strict: bool,
+    ) -> Result<Chain<'a>, Error> {
+        self.validate()?;
+        Ok(Chain::new(codecs, self.to_string(), bufsize, strict))
+    }
+
+    /// Write an argument key or value, quoting it if it would not otherwise parse as itself.
+    fn write_word(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+        if !s.is_empty() && !s.chars().any(Parser::is_special) {
+            f.write_str(s)
+        } else if !s.contains('\'') {
+            write!(f, "'{}'", s)
+        } else {
+            f.write_str("\"")?;
+            for c in s.chars() {
+                if c == '"' || c == '\\' {
+                    f.write_str("\\")?;
+                }
+                write!(f, "{}", c)?;
+            }
+            f.write_str("\"")
+        }
+    }
+}
+
+impl fmt::Display for ChainBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, args, dir)) in self.stages.iter().enumerate() {
+            if i != 0 {
+                f.write_str(":")?;
+            }
+            if *dir == Direction::Reverse {
+                f.write_str("-")?;
+            }
+            f.write_str(name)?;
+            if args.is_empty() {
+                continue;
+            }
+            f.write_str("(")?;
+            for (j, (k, v)) in args.iter().enumerate() {
+                if j != 0 {
+                    f.write_str(",")?;
+                }
+                Self::write_word(f, k)?;
+                if let Some(v) = v {
+                    f.write_str("=")?;
+                    Self::write_word(f, v)?;
+                }
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct Chain<'a> {
-    chain: &'a str,
+    chain: Cow<'a, str>,
     bufsize: usize,
     strict: bool,
-    codecs: &'a CodecRegistry,
+    codecs: Registry<'a>,
     dir: Direction,
+    on_alternative: Option<AlternativeCallback>,
+    // The arguments to substitute, or `None` if values of the form `$name` are taken literally.
+    args: Option<BTreeMap<String, Option<String>>>,
+    threads: usize,
+    max_output: Option<u64>,
 }
 
 impl<'a> Chain<'a> {
-    pub fn new(codecs: &'a CodecRegistry, chain: &'a str, bufsize: usize, strict: bool) -> Self {
+    /// Create a chain from its string form.
+    ///
+    /// `codecs` may be a reference to a registry or an `Arc<CodecRegistry>`; a chain created
+    /// from the latter and an owned string borrows nothing.
+    pub fn new<R: Into<Registry<'a>>, S: Into<Cow<'a, str>>>(
+        codecs: R,
+        chain: S,
+        bufsize: usize,
+        strict: bool,
+    ) -> Self {
         Chain {
-            chain,
+            chain: chain.into(),
             bufsize,
             strict,
-            codecs,
+            codecs: codecs.into(),
             dir: Direction::Forward,
+            on_alternative: None,
+            args: None,
+            threads: 1,
+            max_output: None,
         }
     }
 
+    /// Returns a builder for constructing a chain without parsing a string.
+    pub fn builder() -> ChainBuilder {
+        ChainBuilder::new()
+    }
+
     #[must_use]
     pub fn reverse(self) -> Self {
         let mut obj = self.clone();
@@ -83,115 +358,1217 @@ impl<'a> Chain<'a> {
         obj
     }
 
+    /// Call `f` with the text of the alternative chosen whenever an alternation matches.
+    ///
+    /// `f` may be called from any thread which uses the chain.
+    #[must_use]
+    pub fn on_alternative<F: Fn(&str) + Send + Sync + 'static>(self, f: F) -> Self {
+        let mut obj = self.clone();
+        obj.on_alternative = Some(Arc::new(f));
+        obj
+    }
+
+    /// Provide arguments for the chain.
+    ///
+    /// An argument value of the form `$name` anywhere in the chain is replaced with the value of
+    /// the argument `name`, as is an argument of that form which has no value.  It is an error to
+    /// provide an argument which is not referenced, or to reference one which is not provided,
+    /// even if `args` is empty.
+    #[must_use]
+    pub fn with_arguments(self, args: BTreeMap<String, Option<String>>) -> Self {
+        let mut obj = self.clone();
+        obj.args = Some(args);
+        obj
+    }
+
+    /// Allow each stage to use up to `threads` threads.
+    ///
+    /// Only stages whose codecs are `Codec::parallel_safe` use more than one thread, and they do
+    /// so by splitting their input into large blocks, so this helps only with large inputs.  The
+    /// output is the same regardless of the number of threads.
+    #[must_use]
+    pub fn with_threads(self, threads: usize) -> Self {
+        let mut obj = self.clone();
+        obj.threads = cmp::max(threads, 1);
+        obj
+    }
+
+    /// Fail with `codec::Error::OutputLimitExceeded` if any stage produces more than `limit`
+    /// bytes, or if the input to an alternation, which is held in memory, is more than `limit`
+    /// bytes.
+    ///
+    /// This guards against inputs, such as compressed data, which expand to far more than their
+    /// own size.
+    #[must_use]
+    pub fn with_max_output(self, limit: u64) -> Self {
+        let mut obj = self.clone();
+        obj.max_output = Some(limit);
+        obj
+    }
+
+    /// Parse and validate the chain, producing an object which can be used to transform many
+    /// inputs without parsing the chain again.
+    pub fn compile(&self) -> io::Result<CompiledChain<'a>> {
+        let stages = self.compile_stages(&self.parse_checked()?)?;
+        Ok(CompiledChain {
+            codecs: self.codecs.clone(),
+            chain: self.chain.to_string(),
+            cache: Arc::new(stages.iter().map(|_| Mutex::new(None)).collect()),
+            stages,
+            bufsize: self.bufsize,
+            max_output: self.max_output,
+            on_alternative: self.on_alternative.clone(),
+        })
+    }
+
+    /// Check the chain and every stage in it against the registry without reading any data.
+    pub fn validate(&self) -> io::Result<()> {
+        self.compile()?.validate()
+    }
+
     pub fn build(&self, src: Box<io::BufRead>) -> io::Result<Box<io::BufRead>> {
-        let start: io::Result<_> = Ok(src);
-        Self::parse(self.chain, self.dir)?
-            .iter()
-            .fold(start, |cur, xfrm| {
-                Ok(self
-                    .codecs
-                    .create(xfrm.name, cur?, self.codec_settings(xfrm))?)
-            })
+        self.compile()?.build(src)
+    }
+
+    /// As for `build`, but for a reader which can be sent to another thread.
+    pub fn build_send(&self, src: Box<io::BufRead + Send>) -> io::Result<Box<io::BufRead + Send>> {
+        self.compile()?.build_send(src)
+    }
+
+    /// Instantiate the codecs in the chain, writing the output to `w`.
+    ///
+    /// See `CompiledChain::build_writer` for details.
+    pub fn build_writer<W: Write + 'a>(&self, w: W) -> io::Result<Box<io::Write + 'a>> {
+        self.compile()?.build_writer(w)
     }
 
     pub fn transform(&self, b: Vec<u8>) -> io::Result<Vec<u8>> {
-        let mut out = io::Cursor::new(Vec::new());
         // Cursor provides a BufRead implementation, but we use a BufReader so we can set the
         // buffer size explicitly for test purposes.
-        let inp = Box::new(io::BufReader::with_capacity(
+        let c = self.compile()?;
+        let mut out = io::Cursor::new(Vec::new());
+        let mut res = c.build(Box::new(io::BufReader::with_capacity(
             self.bufsize,
             io::Cursor::new(b),
-        ));
-        let mut res = self.build(inp)?;
+        )))?;
         io::copy(&mut res, &mut out)?;
         Ok(out.into_inner())
     }
 
-    fn codec_settings(&self, t: &ChainTransform) -> CodecSettings {
-        CodecSettings {
+    /// Returns the names of all the transforms used in the chain.
+    pub fn transform_names(chain: &str) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        Self::collect_names(&Self::parse(chain, Direction::Forward)?, &mut names);
+        Ok(names)
+    }
+
+    fn collect_names(stages: &[ChainStage], names: &mut Vec<String>) {
+        for stage in stages {
+            match *stage {
+                ChainStage::Transform(ref t) => names.push(t.name.to_string()),
+                ChainStage::Alternation(ref alts, _) => alts
+                    .iter()
+                    .for_each(|alt| Self::collect_names(&alt.stages, names)),
+            }
+        }
+    }
+
+    fn collect_references(stages: &[ChainStage], refs: &mut Vec<String>) {
+        for stage in stages {
+            match *stage {
+                ChainStage::Transform(ref t) => refs.extend(
+                    t.args
+                        .iter()
+                        .filter_map(|(k, v)| match *v {
+                            Some(ref v) => Self::reference(v),
+                            None => Self::reference(k),
+                        })
+                        .map(|v| v.to_string()),
+                ),
+                ChainStage::Alternation(ref alts, _) => alts
+                    .iter()
+                    .for_each(|alt| Self::collect_references(&alt.stages, refs)),
+            }
+        }
+    }
+
+    /// Returns the name of the argument referenced by a value, if any.
+    fn reference(value: &str) -> Option<&str> {
+        if value.len() > 1 && value.starts_with('$') {
+            Some(&value[1..])
+        } else {
+            None
+        }
+    }
+
+    /// Parse the chain and verify that every argument provided is used.
+    fn parse_checked(&self) -> io::Result<Vec<ChainStage<'_>>> {
+        let stages = Self::parse(&self.chain, self.dir)?;
+        if let Some(ref args) = self.args {
+            let mut refs = Vec::new();
+            Self::collect_references(&stages, &mut refs);
+            if let Some(k) = args.keys().find(|k| !refs.contains(k)) {
+                return Err(codec::Error::UnknownArgument(k.clone()).into());
+            }
+        }
+        Ok(stages)
+    }
+
+    fn compile_stages(&self, stages: &[ChainStage]) -> Result<Vec<CompiledStage>, codec::Error> {
+        stages
+            .iter()
+            .map(|stage| match *stage {
+                ChainStage::Transform(ref t) => {
+                    let mut settings = self.codec_settings(t)?;
+                    match (self.codecs.get(t.name), t.dir) {
+                        (Some(x), Direction::Reverse) if !x.can_reverse() => {
+                            return Err(codec::Error::ForwardOnly(t.name.to_string()))
+                        }
+                        (Some(x), _) => {
+                            settings.args = x.schema().resolve(settings.args, settings.dir)?
+                        }
+                        (None, _) if !self.codecs.has_alias(t.name) => {
+                            return Err(codec::Error::UnknownCodec(t.name.to_string()))
+                        }
+                        _ => (),
+                    }
+                    Ok(CompiledStage::Transform(
+                        t.name.to_string(),
+                        settings,
+                        t.span,
+                    ))
+                }
+                ChainStage::Alternation(ref alts, span) => {
+                    let alts = alts
+                        .iter()
+                        .map(|alt| Ok((alt.text.to_string(), self.compile_stages(&alt.stages)?)))
+                        .collect::<Result<Vec<_>, codec::Error>>()?;
+                    Ok(CompiledStage::Alternation(alts, span))
+                }
+            })
+            .collect()
+    }
+
+    fn codec_settings(&self, t: &ChainTransform) -> Result<CodecSettings, codec::Error> {
+        let args = if self.args.is_none() {
+            t.args.clone()
+        } else {
+            t.args
+                .iter()
+                .map(|(k, v)| match *v {
+                    Some(ref v) => Ok((k.clone(), Some(self.substitute(v)?))),
+                    None => Ok((self.substitute(k)?, None)),
+                })
+                .collect::<Result<_, codec::Error>>()?
+        };
+        Ok(CodecSettings {
             bufsize: self.bufsize,
             strict: self.strict,
-            args: t.args.clone(),
+            args,
             dir: t.dir,
+            threads: self.threads,
+            max_output: self.max_output,
+        })
+    }
+
+    fn substitute(&self, v: &str) -> Result<String, codec::Error> {
+        let name = match Self::reference(v) {
+            Some(name) => name,
+            None => return Ok(v.to_string()),
+        };
+        match self.args.as_ref().and_then(|args| args.get(name)) {
+            Some(Some(val)) => Ok(val.clone()),
+            _ => Err(codec::Error::MissingArgument(name.to_string())),
         }
     }
 
-    fn parse(chain: &str, dir: Direction) -> Result<Vec<ChainTransform>, Error> {
-        let iter = chain.split(':').map(|s| Self::parse_unit(s, dir));
+    /// Parse the chain and flatten it into the sequence of transforms to be instantiated.
+    fn parse(chain: &str, dir: Direction) -> Result<Vec<ChainStage<'_>>, Error> {
+        let nodes = Parser::new(chain).parse_chain()?;
+        let mut v = Vec::new();
+        Self::flatten(&nodes, dir, &mut v);
+        Ok(v)
+    }
+
+    fn flatten<'b>(nodes: &[ChainNode<'b>], dir: Direction, out: &mut Vec<ChainStage<'b>>) {
         match dir {
-            Direction::Forward => iter.collect(),
-            Direction::Reverse => iter.rev().collect(),
+            Direction::Forward => nodes.iter().for_each(|n| Self::flatten_node(n, dir, out)),
+            Direction::Reverse => nodes
+                .iter()
+                .rev()
+                .for_each(|n| Self::flatten_node(n, dir, out)),
+        }
+    }
+
+    fn flatten_node<'b>(node: &ChainNode<'b>, dir: Direction, out: &mut Vec<ChainStage<'b>>) {
+        match *node {
+            ChainNode::Transform(ref t) => out.push(ChainStage::Transform(ChainTransform {
+                name: t.name,
+                args: t.args.clone(),
+                dir: t.dir.compose(dir),
+                span: t.span,
+            })),
+            ChainNode::Group(ref nodes, d) => Self::flatten(nodes, d.compose(dir), out),
+            ChainNode::Repeat(ref node, count) => {
+                for _ in 0..count {
+                    Self::flatten_node(node, dir, out);
+                }
+            }
+            ChainNode::Alternation(ref alts, d, span) => {
+                let d = d.compose(dir);
+                let alts = alts
+                    .iter()
+                    .map(|&(text, ref nodes)| {
+                        let mut stages = Vec::new();
+                        Self::flatten(nodes, d, &mut stages);
+                        Alternative { text, stages }
+                    })
+                    .collect();
+                out.push(ChainStage::Alternation(alts, span));
+            }
+        }
+    }
+}
+
+/// A stage of a compiled chain, with the settings for each codec fully resolved.
+#[derive(Clone)]
+enum CompiledStage {
+    Transform(String, CodecSettings, Span),
+    Alternation(Vec<(String, Vec<CompiledStage>)>, Span),
+}
+
+impl CompiledStage {
+    fn span(&self) -> Span {
+        match *self {
+            CompiledStage::Transform(_, _, span) | CompiledStage::Alternation(_, span) => span,
+        }
+    }
+
+    /// Returns the name and direction used to describe this stage in errors.
+    fn describe(&self) -> (String, Option<Direction>) {
+        match *self {
+            CompiledStage::Transform(ref name, ref settings, _) => {
+                (name.clone(), Some(settings.dir))
+            }
+            CompiledStage::Alternation(ref alts, _) => {
+                let texts: Vec<&str> = alts.iter().map(|(text, _)| text.as_str()).collect();
+                (format!("({})", texts.join("|")), None)
+            }
+        }
+    }
+}
+
+/// An error which occurred in a particular stage of a chain.
+///
+/// This is the inner error of any `io::Error` produced when building a chain with
+/// `CompiledChain::build` or reading from the result, except for plain I/O errors, such as a
+/// failure to read the original input, which are passed through unchanged.
+#[derive(Debug)]
+pub struct StageError {
+    /// The one-based index of the stage in the flattened chain.
+    pub index: usize,
+    /// The name of the codec, or the text of the alternation in parentheses.
+    pub name: String,
+    /// The direction of the codec, or `None` for an alternation.
+    pub dir: Option<Direction>,
+    /// The text of the chain.
+    pub chain: String,
+    /// The range of zero-based character columns in `chain` which the stage was written in.
+    pub span: (usize, usize),
+    /// The offset in the stage's input at which the error occurred, if known.
+    pub offset: Option<u64>,
+    pub error: io::Error,
+}
+
+impl StageError {
+    fn wrap(stage: &CompiledStage, index: usize, chain: &str, e: io::Error) -> io::Error {
+        match e.get_ref() {
+            Some(inner) if !inner.is::<StageError>() => (),
+            _ => return e,
+        }
+        let offset = match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<codec::Error>())
+        {
+            Some(&codec::Error::AtOffset(pos, _)) => Some(pos.offset),
+            _ => None,
+        };
+        let (name, dir) = stage.describe();
+        let kind = e.kind();
+        let err = StageError {
+            index,
+            name,
+            dir,
+            chain: chain.to_string(),
+            span: stage.span(),
+            offset,
+            error: e,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl StageError {
+    /// Returns a description of the stage, such as "stage 2 ('hex', reverse)", without the chain.
+    pub fn stage(&self) -> String {
+        match self.dir {
+            Some(Direction::Forward) => tr!("stage {} ('{}', forward)", self.index, self.name),
+            Some(Direction::Reverse) => tr!("stage {} ('{}', reverse)", self.index, self.name),
+            None => tr!("stage {} ('{}')", self.index, self.name),
+        }
+    }
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            tr!("{} of chain '{}': {}", self.stage(), self.chain, self.error)
+        )
+    }
+}
+
+impl error::Error for StageError {
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.error)
+    }
+
+    // From libstd.
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+}
+
+/// A reader which attributes any errors from the stage it reads from to that stage.
+struct StageReader<R: BufRead = Box<io::BufRead>> {
+    inner: R,
+    stage: CompiledStage,
+    index: usize,
+    chain: Arc<str>,
+}
+
+impl<R: BufRead> StageReader<R> {
+    fn wrap(&self, e: io::Error) -> io::Error {
+        StageError::wrap(&self.stage, self.index, &self.chain, e)
+    }
+}
+
+impl<R: BufRead> io::Read for StageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => Err(self.wrap(e)),
+        }
+    }
+}
+
+impl<R: BufRead> io::BufRead for StageReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // Borrow the fields separately so that wrapping an error doesn't conflict with the borrow
+        // of the buffer.
+        let (stage, index, chain) = (&self.stage, self.index, &self.chain);
+        self.inner
+            .fill_buf()
+            .map_err(|e| StageError::wrap(stage, index, chain, e))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// A description of a stage of a compiled chain, as returned by `CompiledChain::explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExplainedStage {
+    Transform {
+        name: String,
+        dir: Direction,
+        args: BTreeMap<String, Option<String>>,
+    },
+    /// The alternatives of an alternation, each with its text and its stages.
+    Alternation(Vec<(String, Vec<ExplainedStage>)>),
+}
+
+/// A parsed and validated chain.
+///
+/// Defines `build` or `build_send`, which differ only in whether their readers are `Send`, along
+/// with the method each uses to build a single stage.
+macro_rules! build_fns {
+    ($(#[$attr:meta])* $build:ident, $build_stage:ident, $create:ident $(, $bound:ident)*) => {
+        $(#[$attr])*
+        pub fn $build(
+            &self,
+            src: Box<io::BufRead $(+ $bound)*>,
+        ) -> io::Result<Box<io::BufRead $(+ $bound)*>> {
+            let chain: Arc<str> = Arc::from(self.chain.as_str());
+            let mut cur = src;
+            for (i, stage) in self.stages.iter().enumerate() {
+                cur = self
+                    .$build_stage(stage, cur)
+                    .map_err(|e| StageError::wrap(stage, i + 1, &chain, e))?;
+                cur = Box::new(StageReader {
+                    inner: cur,
+                    stage: stage.clone(),
+                    index: i + 1,
+                    chain: chain.clone(),
+                });
+            }
+            Ok(cur)
+        }
+
+        fn $build_stage(
+            &self,
+            stage: &CompiledStage,
+            src: Box<io::BufRead $(+ $bound)*>,
+        ) -> io::Result<Box<io::BufRead $(+ $bound)*>> {
+            match *stage {
+                CompiledStage::Transform(ref name, ref settings, _) => {
+                    Ok(self.codecs.$create(name, src, settings.clone())?)
+                }
+                CompiledStage::Alternation(ref alts, _) => Ok(Box::new(io::Cursor::new(
+                    self.build_alternation(alts, src)?,
+                ))),
+            }
+        }
+    };
+}
+
+/// This is created by `Chain::compile` and can be used to transform any number of inputs without
+/// parsing the chain each time.  It may be shared between threads, which then share the codecs it
+/// keeps for reuse.
+#[derive(Clone)]
+pub struct CompiledChain<'a> {
+    codecs: Registry<'a>,
+    chain: String,
+    stages: Vec<CompiledStage>,
+    // The codec for each stage kept from the last call to `transform`, shared between clones.
+    cache: CodecCache,
+    bufsize: usize,
+    max_output: Option<u64>,
+    on_alternative: Option<AlternativeCallback>,
+}
+
+impl<'a> CompiledChain<'a> {
+    build_fns!(
+        /// Instantiate the codecs in the chain, reading from `src`.
+        ///
+        /// Errors from each stage, whether in creating it or in reading from it, are wrapped in a
+        /// `StageError` describing the stage.
+        build,
+        build_stage,
+        create
+    );
+
+    build_fns!(
+        /// As for `build`, but for a reader which can be sent to another thread.
+        build_send,
+        build_stage_send,
+        create_send,
+        Send
+    );
+
+    /// Instantiate the codecs in the chain, writing the output to `w`.
+    ///
+    /// The end of the input is signalled when the returned writer is dropped, and any errors at
+    /// that point are ignored.  Use `writer` instead to be able to check them.
+    pub fn build_writer<W: Write + 'a>(&self, w: W) -> io::Result<Box<io::Write + 'a>> {
+        Ok(Box::new(self.writer(w)?))
+    }
+
+    /// Instantiate the codecs in the chain, writing the output to `w`.
+    pub fn writer<W: Write + 'a>(&self, w: W) -> io::Result<ChainWriter<'a, W>> {
+        ChainWriter::new(self.clone(), w)
+    }
+
+    /// Check every stage against the registry without reading any data.
+    ///
+    /// The options for each codec have already been checked against its schema, but each codec is
+    /// also instantiated over an empty input so that it reports any other problems.
+    pub fn validate(&self) -> io::Result<()> {
+        self.validate_stages(&self.stages)
+    }
+
+    fn validate_stages(&self, stages: &[CompiledStage]) -> io::Result<()> {
+        for stage in stages {
+            match *stage {
+                CompiledStage::Transform(ref name, ref settings, _) => {
+                    self.codecs
+                        .create(name, Box::new(io::empty()), settings.clone())?;
+                }
+                CompiledStage::Alternation(ref alts, _) => {
+                    for (_, stages) in alts {
+                        self.validate_stages(stages)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe each stage of the chain, with its direction and its options after substituting
+    /// any arguments.
+    pub fn explain(&self) -> Vec<ExplainedStage> {
+        Self::explain_stages(&self.stages)
+    }
+
+    fn explain_stages(stages: &[CompiledStage]) -> Vec<ExplainedStage> {
+        stages
+            .iter()
+            .map(|stage| match *stage {
+                CompiledStage::Transform(ref name, ref settings, _) => ExplainedStage::Transform {
+                    name: name.clone(),
+                    dir: settings.dir,
+                    args: settings.args.clone(),
+                },
+                CompiledStage::Alternation(ref alts, _) => ExplainedStage::Alternation(
+                    alts.iter()
+                        .map(|(text, stages)| (text.clone(), Self::explain_stages(stages)))
+                        .collect(),
+                ),
+            })
+            .collect()
+    }
+
+    /// Transform a single in-memory value.
+    ///
+    /// Stages implemented by a single codec transform the value directly, and their codecs are
+    /// reset and kept for the next call where possible.  Other stages are read as for `build`.
+    pub fn transform(&self, inp: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = Cow::Borrowed(inp);
+        for (i, stage) in self.stages.iter().enumerate() {
+            let out = self
+                .transform_stage(i, stage, data)
+                .map_err(|e| StageError::wrap(stage, i + 1, &self.chain, e))?;
+            data = Cow::Owned(out);
+        }
+        Ok(data.into_owned())
+    }
+
+    fn transform_stage(
+        &self,
+        index: usize,
+        stage: &CompiledStage,
+        inp: Cow<[u8]>,
+    ) -> io::Result<Vec<u8>> {
+        let (name, settings) = match *stage {
+            CompiledStage::Transform(ref name, ref settings, _) => (name, settings),
+            CompiledStage::Alternation(ref alts, _) => {
+                if let Some(limit) = self.max_output {
+                    if inp.len() as u64 > limit {
+                        return Err(codec::Error::OutputLimitExceeded(limit).into());
+                    }
+                }
+                return self.choose_alternative(alts, inp.into_owned());
+            }
+        };
+        let cached = self.cache[index].try_lock().ok().and_then(|mut c| c.take());
+        let codec = match cached {
+            Some(c) => Some(c),
+            // Leave transforms which may run in parallel to their readers.
+            None if settings.threads > 1 => None,
+            None => self.codecs.codec(name, settings.clone())?,
+        };
+        let mut codec = match codec {
+            Some(c) => c,
+            None => {
+                let src = Box::new(io::Cursor::new(inp.into_owned()));
+                let mut out = Vec::new();
+                self.codecs
+                    .create(name, src, settings.clone())?
+                    .read_to_end(&mut out)?;
+                return Ok(out);
+            }
+        };
+        let res = codec::transform_slice(&mut codec, &inp);
+        if codec.reset() {
+            if let Ok(mut slot) = self.cache[index].try_lock() {
+                *slot = Some(codec);
+            }
+        }
+        Ok(res?)
+    }
+
+    /// Transform an input, reading it through a buffer of the chain's buffer size.
+    fn transform_buffered<R: Read + 'static>(
+        &self,
+        stages: &[CompiledStage],
+        inp: R,
+    ) -> io::Result<Vec<u8>> {
+        let mut out = io::Cursor::new(Vec::new());
+        let inp = Box::new(io::BufReader::with_capacity(self.bufsize, inp));
+        let mut res = self.build_stages(stages, inp)?;
+        io::copy(&mut res, &mut out)?;
+        Ok(out.into_inner())
+    }
+
+    fn build_stages(
+        &self,
+        stages: &[CompiledStage],
+        src: Box<io::BufRead>,
+    ) -> io::Result<Box<io::BufRead>> {
+        let start: io::Result<_> = Ok(src);
+        stages
+            .iter()
+            .fold(start, |cur, stage| self.build_stage(stage, cur?))
+    }
+
+    /// Read the entire input and run each alternative over it in turn, returning the output of
+    /// the first one which succeeds.
+    fn build_alternation<R: Read>(
+        &self,
+        alts: &[(String, Vec<CompiledStage>)],
+        mut src: R,
+    ) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self.max_output {
+            // The buffered input counts towards the limit, since it is held in memory just like
+            // the output of a stage.
+            Some(limit) => {
+                src.by_ref()
+                    .take(limit.saturating_add(1))
+                    .read_to_end(&mut buf)?;
+                if buf.len() as u64 > limit {
+                    return Err(codec::Error::OutputLimitExceeded(limit).into());
+                }
+            }
+            None => {
+                src.read_to_end(&mut buf)?;
+            }
+        }
+        self.choose_alternative(alts, buf)
+    }
+
+    /// Run each alternative over the input in turn, returning the output of the first one which
+    /// succeeds.
+    fn choose_alternative(
+        &self,
+        alts: &[(String, Vec<CompiledStage>)],
+        buf: Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        let buf: Rc<[u8]> = Rc::from(buf);
+
+        let mut errors = Vec::with_capacity(alts.len());
+        for (text, stages) in alts {
+            match self.transform_buffered(stages, io::Cursor::new(buf.clone())) {
+                Ok(out) => {
+                    if let Some(ref f) = self.on_alternative {
+                        f(text);
+                    }
+                    return Ok(out);
+                }
+                Err(e) => errors.push((text.to_string(), e.to_string())),
+            }
         }
+        Err(Error::NoMatchingAlternative(errors).into())
     }
+}
+
+/// Data written to a `ChainWriter` which the chain has not yet read.
+#[derive(Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    closed: bool,
+}
 
-    fn parse_unit(unit: &str, d: Direction) -> Result<ChainTransform, Error> {
-        if unit.is_empty() {
-            return Err(Error::InvalidName(String::from(unit)));
+/// The source for a chain being driven by a `ChainWriter`.
+///
+/// When it has no data but the writer has not been finished, it reports that reading would block,
+/// which every stage passes through without losing its state.
+struct PipeReader {
+    pipe: Rc<RefCell<Pipe>>,
+}
+
+impl io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.pipe.borrow_mut();
+        if pipe.data.is_empty() {
+            return match pipe.closed {
+                true => Ok(0),
+                false => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            };
         }
+        let n = {
+            let (front, _) = pipe.data.as_slices();
+            let n = cmp::min(front.len(), buf.len());
+            buf[..n].copy_from_slice(&front[..n]);
+            n
+        };
+        pipe.data.drain(..n);
+        Ok(n)
+    }
+}
+
+/// A writer which feeds the data written to it through a chain and writes the result to another
+/// writer.
+///
+/// Output is written as soon as the codecs produce it.  Chains containing an alternation need the
+/// entire input before they can choose an alternative, so they produce no output until the end.
+/// The end of the input is signalled by `finish` or `try_finish`, or if neither has been called,
+/// when the writer is dropped, in which case any errors are ignored.
+pub struct ChainWriter<'a, W: Write> {
+    chain: CompiledChain<'a>,
+    pipe: Rc<RefCell<Pipe>>,
+    // The output of the chain, or `None` if it could not be built until all the input is present.
+    reader: Option<Box<io::BufRead>>,
+    w: Option<W>,
+    done: bool,
+}
 
-        let (s, dir) = if &unit[0..1] == "-" {
-            (&unit[1..], Direction::Reverse)
+impl<'a, W: Write> ChainWriter<'a, W> {
+    fn new(chain: CompiledChain<'a>, w: W) -> io::Result<Self> {
+        let pipe = Rc::new(RefCell::new(Pipe::default()));
+        let mut obj = ChainWriter {
+            chain,
+            pipe,
+            reader: None,
+            w: Some(w),
+            done: false,
+        };
+        obj.reader = match obj.build() {
+            Ok(r) => Some(r),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => return Err(e),
+        };
+        Ok(obj)
+    }
+
+    fn build(&self) -> io::Result<Box<io::BufRead>> {
+        let src = PipeReader {
+            pipe: self.pipe.clone(),
+        };
+        self.chain.build(Box::new(io::BufReader::with_capacity(
+            self.chain.bufsize,
+            src,
+        )))
+    }
+
+    /// Write all the output the chain can produce from the input so far.
+    fn pump(&mut self) -> io::Result<()> {
+        let (r, w) = match (&mut self.reader, &mut self.w) {
+            (Some(r), Some(w)) => (r, w),
+            _ => return Ok(()),
+        };
+        loop {
+            let n = match r.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(buf) => {
+                    w.write_all(buf)?;
+                    buf.len()
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            r.consume(n);
+        }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.w.as_ref().unwrap()
+    }
+
+    /// Signal the end of the input to the chain and write all remaining output, without consuming
+    /// the writer.  Writing data afterwards is an error.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        self.pipe.borrow_mut().closed = true;
+        if self.reader.is_none() {
+            self.reader = Some(self.build()?);
+        }
+        self.pump()?;
+        self.w.as_mut().unwrap().flush()
+    }
+
+    /// Signal the end of the input to the chain, write all remaining output, and return the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.w.take().unwrap())
+    }
+}
+
+impl<'a, W: Write> io::Write for ChainWriter<'a, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Err(io::Error::from(codec::Error::ExtraData));
+        }
+        self.pipe.borrow_mut().data.extend(data);
+        self.pump()?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pump()?;
+        self.w.as_mut().unwrap().flush()
+    }
+}
+
+impl<'a, W: Write> Drop for ChainWriter<'a, W> {
+    fn drop(&mut self) {
+        if self.w.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+/// A parser for the chain syntax.
+///
+/// A chain is a colon-separated list of transforms, each of which is a name optionally preceded by
+/// `-` and optionally followed by arguments, either in parentheses or preceded by a single comma.
+/// Argument keys and values may contain special characters if they are quoted with single quotes
+/// (in which case the contents are literal), with double quotes (in which case a backslash escapes
+/// the next character), or if each special character is preceded by a backslash.
+///
+/// A parenthesized sub-chain may be used in place of a transform, in which case it can be reversed
+/// as a unit with `-`.  Either may be followed by a repetition count in braces, such as `{3}`.  A
+/// parenthesized group may also contain several sub-chains separated by `|`, which are tried in
+/// order until one succeeds.
+struct Parser<'a> {
+    chain: &'a str,
+    chars: Vec<(usize, char)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(chain: &'a str) -> Self {
+        Parser {
+            chain,
+            chars: chain.char_indices().collect(),
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).map(|&(_, c)| c)
+    }
+
+    /// The byte offset of the current character in the chain.
+    fn offset(&self) -> usize {
+        match self.chars.get(self.pos) {
+            Some(&(off, _)) => off,
+            None => self.chain.len(),
+        }
+    }
+
+    /// The one-based column of the current character in the chain.
+    fn column(&self) -> usize {
+        self.pos + 1
+    }
+
+    fn unexpected(&self) -> Error {
+        match self.peek() {
+            Some(c) => Error::UnexpectedCharacter(c, self.column()),
+            None => Error::InvalidName(String::new()),
+        }
+    }
+
+    fn is_special(c: char) -> bool {
+        ":,(){}|='\"\\".contains(c)
+    }
+
+    fn parse_chain(&mut self) -> Result<Vec<ChainNode<'a>>, Error> {
+        let v = self.parse_sequence()?;
+        match self.peek() {
+            Some(_) => Err(self.unexpected()),
+            None => Ok(v),
+        }
+    }
+
+    /// Parse a colon-separated sequence of units, stopping at the end of the chain, at a closing
+    /// parenthesis, or at a vertical bar, which are left for the caller.
+    fn parse_sequence(&mut self) -> Result<Vec<ChainNode<'a>>, Error> {
+        let mut v = vec![self.parse_unit()?];
+        while let Some(c) = self.peek() {
+            match c {
+                ':' => {
+                    self.pos += 1;
+                    v.push(self.parse_unit()?);
+                }
+                ')' | '|' => break,
+                _ => return Err(self.unexpected()),
+            }
+        }
+        Ok(v)
+    }
+
+    fn parse_unit(&mut self) -> Result<ChainNode<'a>, Error> {
+        let first = self.pos;
+        let dir = if self.peek() == Some('-') {
+            self.pos += 1;
+            Direction::Reverse
         } else {
-            (unit, Direction::Forward)
+            Direction::Forward
         };
 
-        let dir = match d {
-            Direction::Forward => dir,
-            Direction::Reverse => dir.invert(),
-        };
+        let start = self.offset();
+        let node = if self.peek() == Some('(') {
+            if self.depth == MAX_NESTING {
+                return Err(Error::NestingTooDeep(self.column()));
+            }
+            self.depth += 1;
+            self.pos += 1;
+            let mut alts = Vec::new();
+            loop {
+                let altstart = self.offset();
+                let v = self.parse_sequence()?;
+                alts.push((&self.chain[altstart..self.offset()], v));
+                if self.peek() != Some('|') {
+                    break;
+                }
+                self.pos += 1;
+            }
+            self.depth -= 1;
+            if self.peek() != Some(')') {
+                return Err(Error::MismatchedParentheses(String::from(
+                    &self.chain[start..],
+                )));
+            }
+            self.pos += 1;
+            if alts.len() == 1 {
+                ChainNode::Group(alts.pop().unwrap().1, dir)
+            } else {
+                ChainNode::Alternation(alts, dir, (first, self.pos))
+            }
+        } else {
+            let mut t = self.parse_transform(dir)?;
+            t.span = (first, self.pos);
+            ChainNode::Transform(t)
+        };
+
+        match self.peek() {
+            Some('{') => self.parse_repetition(node),
+            None | Some(':') | Some(')') | Some('|') => Ok(node),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_repetition(&mut self, node: ChainNode<'a>) -> Result<ChainNode<'a>, Error> {
+        let col = self.column();
+        self.pos += 1;
+        let start = self.offset();
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            self.pos += 1;
+        }
+        let count = match self.chain[start..self.offset()].parse::<usize>() {
+            Ok(0) | Err(_) => return Err(Error::InvalidRepetition(col)),
+            Ok(n) if n.saturating_mul(Self::stage_count(&node)) > MAX_REPETITION => {
+                return Err(Error::InvalidRepetition(col))
+            }
+            Ok(n) => n,
+        };
+        if self.peek() != Some('}') {
+            return Err(Error::InvalidRepetition(col));
+        }
+        self.pos += 1;
+        match self.peek() {
+            None | Some(':') | Some(')') | Some('|') => {
+                Ok(ChainNode::Repeat(Box::new(node), count))
+            }
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    /// The number of stages a node expands to.
+    fn stage_count(node: &ChainNode<'a>) -> usize {
+        match *node {
+            ChainNode::Transform(_) => 1,
+            ChainNode::Group(ref nodes, _) => Self::sequence_count(nodes),
+            ChainNode::Repeat(ref node, count) => count.saturating_mul(Self::stage_count(node)),
+            ChainNode::Alternation(ref alts, _, _) => alts.iter().fold(0, |acc, (_, nodes)| {
+                acc.saturating_add(Self::sequence_count(nodes))
+            }),
+        }
+    }
+
+    fn sequence_count(nodes: &[ChainNode<'a>]) -> usize {
+        nodes
+            .iter()
+            .fold(0, |acc, n| acc.saturating_add(Self::stage_count(n)))
+    }
 
-        let (name, args): (&str, Option<&str>) = if let Some(off) = s.find('(') {
-            if &s[s.len() - 1..] != ")" {
-                return Err(Error::MismatchedParentheses(String::from(s)));
-            }
-            (&s[0..off], Some(&s[off + 1..s.len() - 1]))
-        } else {
-            match s.find(',') {
-                Some(off) if off != s.len() - 1 => (&s[0..off], Some(&s[off + 1..])),
-                Some(off) => return Err(Error::InvalidArgument(String::from(&s[off..]))),
-                None => (s, None),
+    fn parse_transform(&mut self, dir: Direction) -> Result<ChainTransform<'a>, Error> {
+        let start = self.offset();
+        while let Some(c) = self.peek() {
+            if Self::is_special(c) {
+                break;
             }
-        };
+            self.pos += 1;
+        }
+        let name = &self.chain[start..self.offset()];
 
         if name.is_empty() {
-            return Err(Error::InvalidName(String::from(name)));
+            return match self.peek() {
+                None | Some(':') | Some(')') | Some('|') | Some(',') => {
+                    Err(Error::InvalidName(String::new()))
+                }
+                Some(_) => Err(self.unexpected()),
+            };
         }
 
-        let map = match args {
-            Some(s) => s
-                .split(',')
-                .map(Self::parse_arg)
-                .collect::<BTreeMap<String, Option<String>>>(),
-            None => BTreeMap::new(),
+        let args = match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                self.parse_parenthesized_args(start)?
+            }
+            Some(',') => {
+                self.pos += 1;
+                match self.peek() {
+                    None | Some(':') | Some(')') | Some('|') => {
+                        return Err(Error::InvalidArgument(String::from(",")))
+                    }
+                    _ => self.parse_comma_args()?,
+                }
+            }
+            _ => BTreeMap::new(),
         };
         Ok(ChainTransform {
             name,
-            args: map,
+            args,
             dir,
+            span: (0, 0),
         })
     }
 
-    fn parse_arg(s: &str) -> (String, Option<String>) {
-        match s.find('=') {
-            Some(off) => (s[0..off].to_string(), Some(s[off + 1..].to_string())),
-            None => (s.to_string(), None),
+    fn parse_parenthesized_args(
+        &mut self,
+        start: usize,
+    ) -> Result<BTreeMap<String, Option<String>>, Error> {
+        let mut map = BTreeMap::new();
+        if self.peek() == Some(')') {
+            self.pos += 1;
+            return Ok(map);
+        }
+        loop {
+            let (k, v) = self.parse_arg()?;
+            map.insert(k, v);
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(')') => {
+                    self.pos += 1;
+                    return Ok(map);
+                }
+                None => {
+                    return Err(Error::MismatchedParentheses(String::from(
+                        &self.chain[start..],
+                    )))
+                }
+                Some(_) => return Err(self.unexpected()),
+            }
+        }
+    }
+
+    fn parse_comma_args(&mut self) -> Result<BTreeMap<String, Option<String>>, Error> {
+        let mut map = BTreeMap::new();
+        loop {
+            let (k, v) = self.parse_arg()?;
+            map.insert(k, v);
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                None | Some(':') | Some(')') | Some('|') | Some('{') => return Ok(map),
+                Some(_) => return Err(self.unexpected()),
+            }
+        }
+    }
+
+    fn parse_arg(&mut self) -> Result<(String, Option<String>), Error> {
+        let key = self.parse_word(true)?;
+        if self.peek() == Some('=') {
+            self.pos += 1;
+            Ok((key, Some(self.parse_word(false)?)))
+        } else {
+            Ok((key, None))
+        }
+    }
+
+    /// Parse a possibly quoted or escaped argument key or value.
+    ///
+    /// If `key` is true, an unquoted equals sign ends the word.
+    fn parse_word(&mut self, key: bool) -> Result<String, Error> {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            let col = self.column();
+            match c {
+                ':' | ',' | '(' | ')' | '{' | '}' | '|' => break,
+                '=' if key => break,
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c) => s.push(c),
+                        None => return Err(Error::TrailingBackslash(col)),
+                    }
+                }
+                '\'' => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            Some('\'') => break,
+                            Some(c) => s.push(c),
+                            None => return Err(Error::UnterminatedQuote(col)),
+                        }
+                        self.pos += 1;
+                    }
+                }
+                '"' => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            Some('"') => break,
+                            Some('\\') => {
+                                self.pos += 1;
+                                match self.peek() {
+                                    Some(c) => s.push(c),
+                                    None => return Err(Error::UnterminatedQuote(col)),
+                                }
+                            }
+                            Some(c) => s.push(c),
+                            None => return Err(Error::UnterminatedQuote(col)),
+                        }
+                        self.pos += 1;
+                    }
+                }
+                c => s.push(c),
+            }
+            self.pos += 1;
         }
+        Ok(s)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use chain::Alternative;
     use chain::Chain;
+    use chain::ChainStage;
     use chain::ChainTransform;
     use chain::Error;
+    use chain::ExplainedStage;
+    use chain::StageError;
+    use chain::MAX_NESTING;
+    use chain::MAX_REPETITION;
+    use codec;
+    use codec::registry::CodecRegistry;
+    use codec::tests;
     use codec::Direction;
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::io::Read;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
 
-    fn xfrm<'a>(
-        s: &'a str,
-        v: Vec<(&'a str, Option<&'a str>)>,
-        forward: bool,
-    ) -> ChainTransform<'a> {
-        ChainTransform {
+    fn xfrm<'a>(s: &'a str, v: Vec<(&'a str, Option<&'a str>)>, forward: bool) -> ChainStage<'a> {
+        ChainStage::Transform(ChainTransform {
             name: s,
             args: v
                 .iter()
@@ -202,7 +1579,8 @@ mod tests {
                 true => Direction::Forward,
                 false => Direction::Reverse,
             },
-        }
+            span: (0, 0),
+        })
     }
 
     #[test]
@@ -320,6 +1698,765 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parses_quoted_arguments() {
+        assert_eq!(
+            Chain::parse("wrap(prefix='> ')", Direction::Forward).unwrap(),
+            vec![xfrm("wrap", vec![("prefix", Some("> "))], true)]
+        );
+        assert_eq!(
+            Chain::parse("hex(sep=':'):base64", Direction::Forward).unwrap(),
+            vec![
+                xfrm("hex", vec![("sep", Some(":"))], true),
+                xfrm("base64", vec![], true)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("hex,sep=\":,()\"", Direction::Forward).unwrap(),
+            vec![xfrm("hex", vec![("sep", Some(":,()"))], true)]
+        );
+        assert_eq!(
+            Chain::parse("x(a=\"\\\"\\\\\",'b=c'=d)", Direction::Forward).unwrap(),
+            vec![xfrm(
+                "x",
+                vec![("a", Some("\"\\")), ("b=c", Some("d"))],
+                true
+            )]
+        );
+        assert_eq!(
+            Chain::parse("x(a=\\:\\,'\\')", Direction::Forward).unwrap(),
+            vec![xfrm("x", vec![("a", Some(":,\\"))], true)]
+        );
+        assert_eq!(
+            Chain::parse("x(a='',b=)", Direction::Forward).unwrap(),
+            vec![xfrm("x", vec![("a", Some("")), ("b", Some(""))], true)]
+        );
+        assert_eq!(
+            Chain::parse("x()", Direction::Forward).unwrap(),
+            vec![xfrm("x", vec![], true)]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_quoting() {
+        assert_eq!(
+            Chain::parse("x(a='b)", Direction::Forward).unwrap_err(),
+            Error::UnterminatedQuote(5)
+        );
+        assert_eq!(
+            Chain::parse("-x,a=\"b\\\"", Direction::Forward).unwrap_err(),
+            Error::UnterminatedQuote(6)
+        );
+        assert_eq!(
+            Chain::parse("x,a\\", Direction::Forward).unwrap_err(),
+            Error::TrailingBackslash(4)
+        );
+        assert_eq!(
+            Chain::parse("x(a:b)", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter(':', 4)
+        );
+        assert_eq!(
+            Chain::parse("x(a)b", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter('b', 5)
+        );
+        assert_eq!(
+            Chain::parse("hex:x'y'", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter('\'', 6)
+        );
+        assert_eq!(
+            Chain::parse("x,a)", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter(')', 4)
+        );
+        assert_eq!(
+            Chain::parse("x(a:b)", Direction::Forward)
+                .unwrap_err()
+                .to_string(),
+            "unexpected character ':' at column 4"
+        );
+    }
+
+    #[test]
+    fn parses_groups() {
+        assert_eq!(
+            Chain::parse("-(gzip:base64):hex", Direction::Forward).unwrap(),
+            vec![
+                xfrm("base64", vec![], false),
+                xfrm("gzip", vec![], false),
+                xfrm("hex", vec![], true)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("-(gzip:base64):hex", Direction::Reverse).unwrap(),
+            vec![
+                xfrm("hex", vec![], false),
+                xfrm("gzip", vec![], true),
+                xfrm("base64", vec![], true)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("(-hex:(xml(html):-(uri,lower)))", Direction::Forward).unwrap(),
+            vec![
+                xfrm("hex", vec![], false),
+                xfrm("xml", vec![("html", None)], true),
+                xfrm("uri", vec![("lower", None)], false)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("-(-hex:xml,html)", Direction::Forward).unwrap(),
+            vec![
+                xfrm("xml", vec![("html", None)], false),
+                xfrm("hex", vec![], true)
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_repetitions() {
+        assert_eq!(
+            Chain::parse("base64{3}", Direction::Forward).unwrap(),
+            vec![
+                xfrm("base64", vec![], true),
+                xfrm("base64", vec![], true),
+                xfrm("base64", vec![], true)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("-base64,nopad{2}:hex(upper){1}", Direction::Forward).unwrap(),
+            vec![
+                xfrm("base64", vec![("nopad", None)], false),
+                xfrm("base64", vec![("nopad", None)], false),
+                xfrm("hex", vec![("upper", None)], true)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("-(hex:base64){2}", Direction::Forward).unwrap(),
+            vec![
+                xfrm("base64", vec![], false),
+                xfrm("hex", vec![], false),
+                xfrm("base64", vec![], false),
+                xfrm("hex", vec![], false)
+            ]
+        );
+        assert_eq!(
+            Chain::parse("(hex:-base64){2}", Direction::Reverse).unwrap(),
+            vec![
+                xfrm("base64", vec![], true),
+                xfrm("hex", vec![], false),
+                xfrm("base64", vec![], true),
+                xfrm("hex", vec![], false)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_groups() {
+        assert_eq!(
+            Chain::parse("-(gzip:base64", Direction::Forward).unwrap_err(),
+            Error::MismatchedParentheses(String::from("(gzip:base64"))
+        );
+        assert_eq!(
+            Chain::parse("hex:base64)", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter(')', 11)
+        );
+        assert_eq!(
+            Chain::parse("()", Direction::Forward).unwrap_err(),
+            Error::InvalidName(String::new())
+        );
+        assert_eq!(
+            Chain::parse("(hex)base64", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter('b', 6)
+        );
+        assert_eq!(
+            Chain::parse("hex{0}", Direction::Forward).unwrap_err(),
+            Error::InvalidRepetition(4)
+        );
+        assert_eq!(
+            Chain::parse("hex:base64{}", Direction::Forward).unwrap_err(),
+            Error::InvalidRepetition(11)
+        );
+        assert_eq!(
+            Chain::parse("hex{2", Direction::Forward).unwrap_err(),
+            Error::InvalidRepetition(4)
+        );
+        assert_eq!(
+            Chain::parse("hex{2}{3}", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter('{', 7)
+        );
+        assert_eq!(
+            Chain::parse("hex{4000000000}", Direction::Forward).unwrap_err(),
+            Error::InvalidRepetition(4)
+        );
+        assert_eq!(
+            Chain::transform_names("hex{99999999999999999999999}").unwrap_err(),
+            Error::InvalidRepetition(4)
+        );
+        assert_eq!(
+            Chain::parse("(hex:base64{10}){91}", Direction::Forward).unwrap_err(),
+            Error::InvalidRepetition(17)
+        );
+        assert_eq!(
+            Chain::parse("(hex:base64{10}){90}", Direction::Forward)
+                .unwrap()
+                .len(),
+            MAX_REPETITION - 10
+        );
+        let deep = format!("{}hex{}", "(".repeat(60000), ")".repeat(60000));
+        assert_eq!(
+            Chain::parse(&deep, Direction::Forward).unwrap_err(),
+            Error::NestingTooDeep(MAX_NESTING + 1)
+        );
+        let deep = format!(
+            "hex:{}-hex{}",
+            "(".repeat(MAX_NESTING),
+            ")".repeat(MAX_NESTING)
+        );
+        assert_eq!(
+            Chain::parse(&deep, Direction::Forward).unwrap(),
+            vec![xfrm("hex", vec![], true), xfrm("hex", vec![], false)]
+        );
+    }
+
+    #[test]
+    fn parses_alternations() {
+        assert_eq!(
+            Chain::parse("-(base64|url64:-gzip|hex)", Direction::Forward).unwrap(),
+            vec![ChainStage::Alternation(
+                vec![
+                    Alternative {
+                        text: "base64",
+                        stages: vec![xfrm("base64", vec![], false)],
+                    },
+                    Alternative {
+                        text: "url64:-gzip",
+                        stages: vec![xfrm("gzip", vec![], true), xfrm("url64", vec![], false)],
+                    },
+                    Alternative {
+                        text: "hex",
+                        stages: vec![xfrm("hex", vec![], false)],
+                    },
+                ],
+                (0, 25)
+            )]
+        );
+        assert_eq!(
+            Chain::parse("-hex:(base64,nopad|url64)", Direction::Reverse).unwrap(),
+            vec![
+                ChainStage::Alternation(
+                    vec![
+                        Alternative {
+                            text: "base64,nopad",
+                            stages: vec![xfrm("base64", vec![("nopad", None)], false)],
+                        },
+                        Alternative {
+                            text: "url64",
+                            stages: vec![xfrm("url64", vec![], false)],
+                        },
+                    ],
+                    (5, 25)
+                ),
+                xfrm("hex", vec![], true),
+            ]
+        );
+        assert_eq!(
+            Chain::parse("hex|base64", Direction::Forward).unwrap_err(),
+            Error::UnexpectedCharacter('|', 4)
+        );
+        assert_eq!(
+            Chain::parse("(hex|)", Direction::Forward).unwrap_err(),
+            Error::InvalidName(String::new())
+        );
+    }
+
+    #[test]
+    fn runs_first_matching_alternative() {
+        let reg = CodecRegistry::new();
+        let matched = Arc::new(Mutex::new(Vec::new()));
+        let m = matched.clone();
+        let c = Chain::new(&reg, "-(hex|base64|url64):hex", 512, true)
+            .on_alternative(move |s| m.lock().unwrap().push(s.to_string()));
+        assert_eq!(c.transform(b"666f6f".to_vec()).unwrap(), b"666f6f");
+        assert_eq!(c.transform(b"Zm9v".to_vec()).unwrap(), b"666f6f");
+        assert_eq!(c.transform(b"-_8".to_vec()).unwrap(), b"fbff");
+        assert_eq!(*matched.lock().unwrap(), vec!["hex", "base64", "url64"]);
+
+        let err = c.transform(b"$$$$".to_vec()).unwrap_err();
+        match tests::inner_error::<Error>(&err) {
+            Some(Error::NoMatchingAlternative(v)) => {
+                let alts: Vec<_> = v.iter().map(|(a, _)| a.as_str()).collect();
+                assert_eq!(alts, vec!["hex", "base64", "url64"]);
+            }
+            _ => panic!("wrong error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn reports_failing_stage() {
+        let reg = CodecRegistry::new();
+        let c = Chain::new(&reg, "hex:-hex:-(hex|base64):-hex", 512, true);
+        let err = c.transform(b"Zm9v".to_vec()).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<StageError>().unwrap();
+        assert_eq!(err.index, 4);
+        assert_eq!(err.name, "hex");
+        assert_eq!(err.dir, Some(Direction::Reverse));
+        assert_eq!(err.chain, "hex:-hex:-(hex|base64):-hex");
+        assert_eq!(err.span, (23, 27));
+        assert!(err.offset.is_some());
+        assert_eq!(err.stage(), "stage 4 ('hex', reverse)");
+
+        let c = Chain::new(&reg, "-hex:hex", 512, true);
+        let err = c.transform(b"666f6fzz".to_vec()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.get_ref().unwrap().downcast_ref::<StageError>().unwrap();
+        assert_eq!(err.index, 1);
+        assert_eq!(err.span, (0, 4));
+        assert_eq!(err.offset, Some(6));
+        match tests::codec_error(&err.error) {
+            Some(codec::Error::InvalidSequence(name, _)) => assert_eq!(name, "hex"),
+            _ => panic!("wrong error: {:?}", err),
+        }
+
+        let c = Chain::new(&reg, "hex:-hex:-(base64|hex)", 512, true);
+        let err = c.transform(b"$$".to_vec()).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<StageError>().unwrap();
+        assert_eq!(err.index, 3);
+        assert_eq!(err.name, "(base64|hex)");
+        assert_eq!(err.dir, None);
+        assert_eq!(err.span, (9, 22));
+        assert_eq!(err.stage(), "stage 3 ('(base64|hex)')");
+    }
+
+    #[test]
+    fn builds_chains_for_other_threads() {
+        let reg = CodecRegistry::new();
+        for chain in &["hex:base64", "-hex:-(base64|hex)", "gzip:-gzip:uri"] {
+            let inp = b"666f6f2062617220626171".to_vec();
+            let c = Chain::new(&reg, *chain, 8, true);
+            let expected = c.transform(inp.clone()).unwrap();
+            let mut r = c.build_send(Box::new(io::Cursor::new(inp))).unwrap();
+            let out = thread::spawn(move || {
+                let mut out = io::Cursor::new(Vec::new());
+                io::copy(&mut r, &mut out).map(|_| out.into_inner())
+            });
+            assert_eq!(out.join().unwrap().unwrap(), expected, "{}", chain);
+        }
+
+        let c = Chain::new(&reg, "-hex", 8, true);
+        let mut r = c
+            .build_send(Box::new(io::Cursor::new(b"666g".to_vec())))
+            .unwrap();
+        let e = thread::spawn(move || io::copy(&mut r, &mut io::sink()))
+            .join()
+            .unwrap()
+            .unwrap_err();
+        let s = e.get_ref().unwrap().downcast_ref::<StageError>().unwrap();
+        assert_eq!(s.index, 1);
+    }
+
+    #[test]
+    fn limits_input_of_alternations() {
+        let reg = CodecRegistry::new();
+        let inp = vec![b'g'; 1000];
+        let c = Chain::new(&reg, "(-hex|hex)", 512, true).with_max_output(2000);
+        assert_eq!(c.transform(inp.clone()).unwrap().len(), 2000);
+
+        let c = Chain::new(&reg, "(-hex|hex)", 512, true).with_max_output(999);
+        let e = c.transform(inp.clone()).unwrap_err();
+        match tests::codec_error(&e) {
+            Some(codec::Error::OutputLimitExceeded(999)) => (),
+            _ => panic!("wrong error: {:?}", e),
+        }
+
+        // Readers which can be sent to other threads buffer the input in the same way.
+        let e = c
+            .build_send(Box::new(io::Cursor::new(inp)))
+            .and_then(|mut r| io::copy(&mut r, &mut io::sink()))
+            .unwrap_err();
+        match tests::codec_error(&e) {
+            Some(codec::Error::OutputLimitExceeded(999)) => (),
+            _ => panic!("wrong error from build_send: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn limits_output_of_each_stage() {
+        let reg = CodecRegistry::new();
+        let inp = vec![b'a'; 1000];
+        for chain in &["hex", "base64:-base64:hex", "(hex)", "hex:xml(html)"] {
+            let c = Chain::new(&reg, *chain, 512, true).with_max_output(2000);
+            assert_eq!(c.transform(inp.clone()).unwrap().len(), 2000, "{}", chain);
+
+            let c = Chain::new(&reg, *chain, 512, true).with_max_output(1999);
+            let e = c.transform(inp.clone()).unwrap_err();
+            match tests::codec_error(&e) {
+                Some(codec::Error::OutputLimitExceeded(1999)) => (),
+                _ => panic!("wrong error for {}: {:?}", chain, e),
+            }
+
+            let mut out = Vec::new();
+            let mut w = c.compile().unwrap().writer(&mut out).unwrap();
+            let e = w.write_all(&inp).and_then(|_| w.try_finish()).unwrap_err();
+            match tests::codec_error(&e) {
+                Some(codec::Error::OutputLimitExceeded(1999)) => (),
+                _ => panic!("wrong error for {} when writing: {:?}", chain, e),
+            }
+        }
+    }
+
+    #[test]
+    fn runs_stages_on_several_threads() {
+        let reg = CodecRegistry::new();
+        // Enough input for several blocks per thread, so that each stage uses more than one.
+        let inp: Vec<u8> = (0..3_500_000u32).map(|x| (x % 251) as u8).collect();
+        for chain in &[
+            "base64",
+            "hex:base32",
+            "url64(nopad):-url64:uri",
+            "crlf:xml",
+        ] {
+            let c = Chain::new(&reg, *chain, 512, true);
+            let expected = c.transform(inp.clone()).unwrap();
+            for &threads in &[1, 2, 5] {
+                let c = c.clone().with_threads(threads);
+                assert_eq!(
+                    c.transform(inp.clone()).unwrap(),
+                    expected,
+                    "{} threads {}",
+                    chain,
+                    threads
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn writes_chains() {
+        let reg = CodecRegistry::new();
+        for chain in &[
+            "hex:base64",
+            "-hex:-(base64|hex)",
+            "gzip:-gzip:uri",
+            "zlib:-zlib",
+            "gzip(threads=2,block=4):-gzip",
+            "zlib(dict=0102):-zlib(dict=0102)",
+        ] {
+            for &i in &[5, 6, 7, 8, 512] {
+                let inp = b"666f6f2062617220626171".to_vec();
+                let expected = Chain::new(&reg, *chain, i, true)
+                    .transform(inp.clone())
+                    .unwrap();
+
+                let mut out = Vec::new();
+                {
+                    let mut w = Chain::new(&reg, *chain, i, true)
+                        .build_writer(&mut out)
+                        .unwrap();
+                    for chunk in inp.chunks(3) {
+                        w.write_all(chunk).unwrap();
+                    }
+                }
+                assert_eq!(out, expected, "{}", chain);
+
+                let c = Chain::new(&reg, *chain, i, true).compile().unwrap();
+                let mut w = c.writer(Vec::new()).unwrap();
+                w.write_all(&inp).unwrap();
+                assert_eq!(w.finish().unwrap(), expected, "{}", chain);
+            }
+        }
+
+        let c = Chain::new(&reg, "-hex", 512, true).compile().unwrap();
+        let mut w = c.writer(Vec::new()).unwrap();
+        w.write_all(b"66").unwrap();
+        w.write_all(b"6g").unwrap_err();
+    }
+
+    #[test]
+    fn compiles_chains() {
+        let reg = CodecRegistry::new();
+        let c = Chain::new(&reg, "-hex:(base64|hex)", 512, true)
+            .compile()
+            .unwrap();
+        assert_eq!(c.transform(b"666f6f").unwrap(), b"Zm9v");
+        assert_eq!(c.transform(b"").unwrap(), b"");
+        assert_eq!(c.transform(b"6f").unwrap(), b"bw==");
+        assert!(c.transform(b"zz").is_err());
+        assert_eq!(c.transform(b"666f6f").unwrap(), b"Zm9v");
+
+        // Codecs kept from one value must not carry any state into the next.
+        let c = Chain::new(&reg, "-hex:base64:wrap(length=3)", 512, true)
+            .compile()
+            .unwrap();
+        for _ in 0..3 {
+            assert_eq!(c.transform(b"666f6f6f").unwrap(), b"Zm9\nvbw\n==");
+            assert!(c.transform(b"6g").is_err());
+            assert_eq!(c.transform(b"66").unwrap(), b"Zg=\n=");
+            assert!(c.cache.iter().all(|s| s.lock().unwrap().is_some()));
+        }
+
+        let c = Chain::new(&reg, "-hex:base64", 512, true).reverse();
+        let c = c.compile().unwrap();
+        let r = c
+            .build(Box::new(io::Cursor::new(b"Zm9v".to_vec())))
+            .unwrap();
+        let v: Result<Vec<u8>, _> = r.bytes().collect();
+        assert_eq!(v.unwrap(), b"666f6f");
+
+        for &(chain, expected) in &[("hex:-bogus", "bogus"), ("hex:(base64|-hash)", "hash")] {
+            let err = Chain::new(&reg, chain, 512, true).compile().err().unwrap();
+            match err.get_ref().and_then(|e| e.downcast_ref()) {
+                Some(codec::Error::UnknownCodec(name)) | Some(codec::Error::ForwardOnly(name)) => {
+                    assert_eq!(name, expected)
+                }
+                _ => panic!("wrong error: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn shares_compiled_chains_between_threads() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let reg: &'static CodecRegistry = Box::leak(Box::new(CodecRegistry::new()));
+        let matched = Arc::new(Mutex::new(0));
+        let m = matched.clone();
+        let c = Chain::new(reg, "-(hex|base64):base64", 512, true)
+            .on_alternative(move |_| *m.lock().unwrap() += 1)
+            .compile()
+            .unwrap();
+        assert_send_sync(&c);
+        let c = Arc::new(c);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        assert_eq!(c.transform(b"666f6f").unwrap(), b"Zm9v");
+                        assert_eq!(c.transform(b"Zm9v").unwrap(), b"Zm9v");
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*matched.lock().unwrap(), 80);
+    }
+
+    #[test]
+    fn builds_chains() {
+        let args = |v: &[(&str, Option<&str>)]| -> BTreeMap<String, Option<String>> {
+            v.iter()
+                .map(|&(k, v)| (k.to_string(), v.map(|s| s.to_string())))
+                .collect()
+        };
+        let b = Chain::builder()
+            .push("hash", args(&[("sha256", None)]))
+            .push_reverse("hex", args(&[]))
+            .push("lf", args(&[]));
+        assert_eq!(b.to_string(), "hash(sha256):-hex:lf");
+
+        let b = Chain::builder().push(
+            "x",
+            args(&[
+                ("a", Some("b:c")),
+                ("d", Some("it's")),
+                ("e", Some("")),
+                ("f=g", None),
+                ("h", Some("'\\\"")),
+            ]),
+        );
+        let s = b.to_string();
+        assert_eq!(s, "x(a='b:c',d=\"it's\",e='','f=g',h=\"'\\\\\\\"\")");
+        assert_eq!(
+            Chain::parse(&s, Direction::Forward).unwrap(),
+            vec![xfrm(
+                "x",
+                vec![
+                    ("a", Some("b:c")),
+                    ("d", Some("it's")),
+                    ("e", Some("")),
+                    ("f=g", None),
+                    ("h", Some("'\\\"")),
+                ],
+                true
+            )]
+        );
+
+        let reg = CodecRegistry::new();
+        let c = Chain::builder()
+            .push_reverse("hex", args(&[]))
+            .push("base64", args(&[]))
+            .into_chain(&reg, 512, true)
+            .unwrap();
+        let d = Chain::new(&reg, "-hex:base64", 512, true);
+        for inp in &[&b"666f6f"[..], b"", b"00ff"] {
+            assert_eq!(
+                c.transform(inp.to_vec()).unwrap(),
+                d.transform(inp.to_vec()).unwrap()
+            );
+        }
+        assert_eq!(
+            c.clone().reverse().transform(b"Zm9v".to_vec()).unwrap(),
+            b"666f6f"
+        );
+
+        for name in &[
+            "hex:base64",
+            "hex(upper)",
+            "hex,upper",
+            "(hex|base64)",
+            "-hex",
+            "",
+        ] {
+            let b = Chain::builder()
+                .push("hex", args(&[]))
+                .push(name, args(&[]));
+            assert_eq!(b.validate(), Err(Error::InvalidName(name.to_string())));
+            let b = Chain::builder().push_reverse(name, args(&[]));
+            assert_eq!(
+                b.into_chain(&reg, 512, true).err(),
+                Some(Error::InvalidName(name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn builds_chains_which_borrow_nothing() {
+        struct Holder {
+            chain: Chain<'static>,
+        }
+
+        let reg = Arc::new(CodecRegistry::new());
+        let h = Holder {
+            chain: Chain::builder()
+                .push_reverse("hex", BTreeMap::new())
+                .push("base64", BTreeMap::new())
+                .into_chain(reg.clone(), 512, true)
+                .unwrap(),
+        };
+        let c = h.chain.compile().unwrap();
+        drop(h);
+        assert_eq!(c.transform(b"666f6f").unwrap(), b"Zm9v");
+        let c = Chain::new(reg, String::from("-base64:hex"), 512, true);
+        assert_eq!(c.transform(b"Zm9v".to_vec()).unwrap(), b"666f6f");
+    }
+
+    #[test]
+    fn validates_chains() {
+        let reg = CodecRegistry::new();
+        for chain in &[
+            "-hex:hash(sha256):base64",
+            "wrap(length=76):-base64,nopad",
+            "xml(html):-(hex|base64)",
+            "-(base64:gzip)",
+        ] {
+            Chain::new(&reg, *chain, 512, true).validate().unwrap();
+        }
+        // Options which apply only to the other direction are ignored, whether a transform or a
+        // whole chain is reversed.
+        for &(chain, rev) in &[
+            ("hex(upper):gzip(level=3)", "-gzip(level=3):-hex(upper)"),
+            ("base64:wrap(length=4)", "-(base64:wrap(length=4))"),
+        ] {
+            let c = Chain::new(&reg, chain, 512, true);
+            let out = c.transform(b"abcdef".to_vec()).unwrap();
+            assert_eq!(
+                c.reverse().transform(out.clone()).unwrap(),
+                b"abcdef",
+                "{}",
+                chain
+            );
+            let c = Chain::new(&reg, rev, 512, true);
+            assert_eq!(c.transform(out).unwrap(), b"abcdef", "{}", rev);
+        }
+
+        for (chain, expected) in &[
+            (
+                "hash(sha265)",
+                codec::Error::UnknownArgument("sha265".to_string()),
+            ),
+            (
+                "xml(bogus)",
+                codec::Error::UnknownArgument("bogus".to_string()),
+            ),
+            (
+                "-hex:(base64|hex,lower,case)",
+                codec::Error::UnknownArgument("case".to_string()),
+            ),
+            (
+                "-hash(sha256)",
+                codec::Error::ForwardOnly("hash".to_string()),
+            ),
+            (
+                "base64(pad,nopad)",
+                codec::Error::IncompatibleParameters("pad".to_string(), "nopad".to_string()),
+            ),
+            (
+                "hash(md5,sha1)",
+                codec::Error::IncompatibleParameters("md5".to_string(), "sha1".to_string()),
+            ),
+            (
+                "wrap(length=x)",
+                codec::Error::InvalidArgument("length".to_string(), "x".to_string()),
+            ),
+            ("base65", codec::Error::UnknownCodec("base65".to_string())),
+            (
+                "-gzip(level=10)",
+                codec::Error::InvalidArgument("level".to_string(), "10".to_string()),
+            ),
+        ] {
+            let err = Chain::new(&reg, *chain, 512, true).validate().unwrap_err();
+            let err = err.get_ref().and_then(|e| e.downcast_ref::<codec::Error>());
+            assert_eq!(
+                format!("{:?}", err),
+                format!("{:?}", Some(expected)),
+                "{}",
+                chain
+            );
+        }
+    }
+
+    #[test]
+    fn explains_chains() {
+        let reg = CodecRegistry::new();
+        let mut args = BTreeMap::new();
+        args.insert("len".to_string(), Some("76".to_string()));
+        let c = Chain::new(&reg, "-hex:wrap(length=$len):(base64|hex,upper)", 512, true)
+            .with_arguments(args)
+            .reverse()
+            .compile()
+            .unwrap();
+        let opt = |k: &str, v: Option<&str>| -> BTreeMap<String, Option<String>> {
+            let mut m = BTreeMap::new();
+            if !k.is_empty() {
+                m.insert(k.to_string(), v.map(|s| s.to_string()));
+            }
+            m
+        };
+        let x = |name: &str, dir, args| ExplainedStage::Transform {
+            name: name.to_string(),
+            dir,
+            args,
+        };
+        assert_eq!(
+            c.explain(),
+            vec![
+                ExplainedStage::Alternation(vec![
+                    (
+                        "base64".to_string(),
+                        vec![x("base64", Direction::Reverse, opt("", None))]
+                    ),
+                    (
+                        "hex,upper".to_string(),
+                        vec![x("hex", Direction::Reverse, opt("", None))]
+                    ),
+                ]),
+                // Options for encoding don't apply once the chain is reversed.
+                x("wrap", Direction::Reverse, opt("", None)),
+                x("hex", Direction::Forward, opt("", None)),
+            ]
+        );
+    }
+
     #[test]
     fn rejects_invalid_data() {
         assert_eq!(
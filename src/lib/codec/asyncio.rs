@@ -0,0 +1,380 @@
+//! Adapters which drive codecs from asynchronous readers and writers.
+//!
+//! These run the codec's `transform` directly over the data as it becomes available, rather than
+//! blocking on the underlying reader or writer, so they can be used from any futures executor.
+
+use super::{Codec, Error, FlushState, InputPosition, Status};
+use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An asynchronous reader which transforms the data from another reader with a codec.
+pub struct AsyncCodecReader<R: AsyncBufRead + Unpin, C: Codec + Unpin> {
+    r: R,
+    codec: C,
+    // Input which has been read but not yet consumed by the codec.
+    buf: Vec<u8>,
+    cap: usize,
+    out: Vec<u8>,
+    // The range of `out` which has not yet been returned to the caller.
+    out_pos: usize,
+    out_len: usize,
+    pos: InputPosition,
+    // Whether the last call to the codec produced no output, so it needs more input.
+    hungry: bool,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: AsyncBufRead + Unpin, C: Codec + Unpin> AsyncCodecReader<R, C> {
+    pub fn new(r: R, c: C, bufsize: usize) -> Self {
+        AsyncCodecReader {
+            r,
+            codec: c,
+            buf: Vec::with_capacity(bufsize),
+            cap: bufsize,
+            out: vec![0u8; bufsize],
+            out_pos: 0,
+            out_len: 0,
+            pos: InputPosition::default(),
+            hungry: true,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+
+    fn locate(&self, e: Error) -> io::Error {
+        self.pos
+            .locate(e, &self.buf, self.codec.line_oriented())
+            .into()
+    }
+
+    /// Read more input into the buffer.
+    fn poll_input(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.buf.len() == self.cap {
+            // The codec can make no progress even with a full buffer.
+            return Poll::Ready(Err(io::Error::from(Error::SmallBuffer)));
+        }
+        let n = match Pin::new(&mut self.r).poll_fill_buf(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Ready(Ok(data)) => {
+                let n = cmp::min(data.len(), self.cap - self.buf.len());
+                self.buf.extend_from_slice(&data[..n]);
+                self.eof = data.is_empty();
+                n
+            }
+        };
+        Pin::new(&mut self.r).consume(n);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Run the codec until it produces some output or reaches the end of the data.
+    fn poll_output(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.out.len() < self.codec.buffer_size() {
+            return Poll::Ready(Err(io::Error::from(Error::SmallBuffer)));
+        }
+        let line_oriented = self.codec.line_oriented();
+        while self.out_pos == self.out_len && !self.done {
+            if self.hungry && !self.eof {
+                match self.poll_input(cx) {
+                    Poll::Ready(Ok(())) => (),
+                    p => return p,
+                }
+            }
+
+            let flush = if self.eof {
+                FlushState::Finish
+            } else {
+                FlushState::None
+            };
+            let ret = self.codec.transform(&self.buf, &mut self.out, flush);
+            let (consumed, written) = match ret {
+                Ok(Status::BufError(0, 0)) => {
+                    return Poll::Ready(Err(io::Error::from(Error::SmallBuffer)))
+                }
+                Ok(Status::SeqError(0, _)) if self.eof => {
+                    return Poll::Ready(Err(self.locate(Error::TruncatedData)))
+                }
+                Ok(st) => st.unpack(),
+                Err(e) => return Poll::Ready(Err(self.locate(e))),
+            };
+            self.pos.advance(&self.buf[..consumed], line_oriented);
+            self.buf.drain(..consumed);
+            self.out_pos = 0;
+            self.out_len = written;
+            self.hungry = written == 0;
+            self.done = self.eof && written == 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin, C: Codec + Unpin> AsyncBufRead for AsyncCodecReader<R, C> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match this.poll_output(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(&this.out[this.out_pos..this.out_len])),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.out_pos = cmp::min(this.out_pos + amt, this.out_len);
+    }
+}
+
+impl<R: AsyncBufRead + Unpin, C: Codec + Unpin> AsyncRead for AsyncCodecReader<R, C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(data)) => {
+                let n = cmp::min(data.len(), dst.len());
+                dst[..n].copy_from_slice(&data[..n]);
+                n
+            }
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// An asynchronous writer which transforms the data written to it with a codec and writes the
+/// result to another writer.
+///
+/// The end of the input is signalled to the codec by closing the writer.
+pub struct AsyncCodecWriter<W: AsyncWrite + Unpin, C: Codec + Unpin> {
+    w: W,
+    codec: C,
+    // Input which has been written but not yet consumed by the codec.
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    // The range of `out` which has not yet been written to the underlying writer.
+    out_pos: usize,
+    out_len: usize,
+    pos: InputPosition,
+    done: bool,
+}
+
+impl<W: AsyncWrite + Unpin, C: Codec + Unpin> AsyncCodecWriter<W, C> {
+    pub fn new(w: W, c: C, bufsize: usize) -> Self {
+        AsyncCodecWriter {
+            w,
+            codec: c,
+            buf: Vec::with_capacity(bufsize),
+            out: vec![0u8; bufsize],
+            out_pos: 0,
+            out_len: 0,
+            pos: InputPosition::default(),
+            done: false,
+        }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    /// Write any pending output to the underlying writer.
+    fn poll_drain(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out_len {
+            match Pin::new(&mut self.w).poll_write(cx, &self.out[self.out_pos..self.out_len]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)))
+                }
+                Poll::Ready(Ok(n)) => self.out_pos += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Run the codec over the pending input until it can make no more progress, writing the
+    /// output.
+    fn poll_process(&mut self, cx: &mut Context, flush: FlushState) -> Poll<io::Result<()>> {
+        if self.out.len() < self.codec.buffer_size() {
+            return Poll::Ready(Err(io::Error::from(Error::SmallBuffer)));
+        }
+        let line_oriented = self.codec.line_oriented();
+        loop {
+            match self.poll_drain(cx) {
+                Poll::Ready(Ok(())) if self.done => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(())) => (),
+                p => return p,
+            }
+            let ret = self.codec.transform(&self.buf, &mut self.out, flush);
+            let (consumed, written) = match ret {
+                Ok(Status::SeqError(0, _)) if flush == FlushState::Finish => {
+                    return Poll::Ready(Err(self
+                        .pos
+                        .locate(Error::TruncatedData, &self.buf, line_oriented)
+                        .into()))
+                }
+                Ok(Status::BufError(0, 0)) => {
+                    return Poll::Ready(Err(io::Error::from(Error::SmallBuffer)))
+                }
+                Ok(st) => st.unpack(),
+                Err(e) => {
+                    return Poll::Ready(Err(self.pos.locate(e, &self.buf, line_oriented).into()))
+                }
+            };
+            self.pos.advance(&self.buf[..consumed], line_oriented);
+            self.buf.drain(..consumed);
+            self.out_pos = 0;
+            self.out_len = written;
+            match ret {
+                Ok(Status::StreamEnd(_, _)) => self.done = true,
+                _ if consumed == 0 && written == 0 => return Poll::Ready(Ok(())),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin, C: Codec + Unpin> AsyncWrite for AsyncCodecWriter<W, C> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(Err(io::Error::from(Error::ExtraData)));
+        }
+        // Don't accept more data until the output from the last write has been written.
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let n = cmp::min(data.len(), this.out.len());
+        this.buf.extend_from_slice(&data[..n]);
+        match this.poll_process(cx, FlushState::None) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Ready(Ok(n)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_process(cx, FlushState::None) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.w).poll_flush(cx),
+            p => p,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_process(cx, FlushState::Finish) {
+            Poll::Ready(Ok(())) => (),
+            p => return p,
+        }
+        this.done = true;
+        Pin::new(&mut this.w).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncCodecReader, AsyncCodecWriter};
+    use codec::registry::CodecRegistry;
+    use codec::tests::{block_on, ChoppyReader, ChoppyWriter};
+    use codec::{Codec, CodecSettings, Direction, Error};
+    use futures_io::{AsyncRead, AsyncWrite};
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::pin::Pin;
+
+    fn codec(name: &str, dir: Direction) -> Box<Codec + Send> {
+        let reg = CodecRegistry::new();
+        let s = CodecSettings {
+            bufsize: 8,
+            strict: true,
+            args: BTreeMap::new(),
+            dir,
+            threads: 1,
+            max_output: None,
+        };
+        reg.get(name).unwrap().codec(s).unwrap().unwrap()
+    }
+
+    fn read(name: &str, dir: Direction, inp: &[u8]) -> io::Result<Vec<u8>> {
+        let mut r = AsyncCodecReader::new(ChoppyReader::new(inp), codec(name, dir), 8);
+        let mut out = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            match block_on(|cx| Pin::new(&mut r).poll_read(cx, &mut buf))? {
+                0 => return Ok(out),
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn write(name: &str, dir: Direction, inp: &[u8]) -> io::Result<Vec<u8>> {
+        let mut w = AsyncCodecWriter::new(ChoppyWriter::default(), codec(name, dir), 8);
+        let mut data = inp;
+        while !data.is_empty() {
+            let n = block_on(|cx| Pin::new(&mut w).poll_write(cx, data))?;
+            data = &data[n..];
+        }
+        block_on(|cx| Pin::new(&mut w).poll_close(cx))?;
+        Ok(w.into_inner().data)
+    }
+
+    #[test]
+    fn transforms_asynchronously() {
+        let inp: &[u8] = b"Hello, world!\n";
+        let enc: &[u8] = b"48656c6c6f2c20776f726c64210a";
+        assert_eq!(read("hex", Direction::Forward, inp).unwrap(), enc);
+        assert_eq!(read("hex", Direction::Reverse, enc).unwrap(), inp);
+        assert_eq!(write("hex", Direction::Forward, inp).unwrap(), enc);
+        assert_eq!(write("hex", Direction::Reverse, enc).unwrap(), inp);
+        assert_eq!(
+            read("base64", Direction::Forward, inp).unwrap(),
+            b"SGVsbG8sIHdvcmxkIQo="
+        );
+        assert_eq!(
+            write("base64", Direction::Forward, inp).unwrap(),
+            b"SGVsbG8sIHdvcmxkIQo="
+        );
+        assert_eq!(
+            write("base64", Direction::Reverse, b"SGVsbG8sIHdvcmxkIQo=").unwrap(),
+            inp
+        );
+    }
+
+    #[test]
+    fn reports_errors_asynchronously() {
+        for f in &[read, write] {
+            let e = f("hex", Direction::Reverse, b"666g").unwrap_err();
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::AtOffset(pos, _)) => assert_eq!(pos.offset, 2),
+                _ => panic!("unexpected error {:?}", e),
+            }
+            let e = f("uri", Direction::Reverse, b"ab%4").unwrap_err();
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::AtOffset(pos, e)) => match **e {
+                    Error::TruncatedData if pos.offset == 2 => (),
+                    _ => panic!("unexpected error {:?}", e),
+                },
+                _ => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+}
@@ -4,6 +4,7 @@
 use codec::helpers::codecs::AffixEncoder;
 use codec::helpers::codecs::FilteredDecoder;
 use codec::helpers::codecs::PaddedEncoder;
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -11,10 +12,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 const DIVISORS: [u64; 5] = [85 * 85 * 85 * 85, 85 * 85 * 85, 85 * 85, 85, 1];
 
@@ -75,7 +73,7 @@ impl Ascii85TransformFactory {
 }
 
 impl CodecTransform for Ascii85TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => {
                 let enc = PaddedEncoder::new_with_pad_function(
@@ -86,20 +84,23 @@ impl CodecTransform for Ascii85TransformFactory {
                     Ascii85Encoder::pad_bytes_needed,
                 );
                 if s.bool_arg("bare")? {
-                    Ok(enc.into_bufread(r, s.bufsize))
+                    Ok(Some(Box::new(enc)))
                 } else {
-                    Ok(AffixEncoder::new(enc, vec![b'<', b'~'], vec![b'~', b'>'])
-                        .into_bufread(r, s.bufsize))
+                    Ok(Some(Box::new(AffixEncoder::new(
+                        enc,
+                        vec![b'<', b'~'],
+                        vec![b'~', b'>'],
+                    ))))
                 }
             }
-            Direction::Reverse => Ok(Ascii85Decoder::new(s.strict).into_bufread(r, s.bufsize)),
+            Direction::Reverse => Ok(Some(Box::new(Ascii85Decoder::new(s.strict)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("bare".to_string(), tr!("do not use delimiters"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("bare", tr!("do not use delimiters"))
+            .direction(Direction::Forward)
     }
 
     fn can_reverse(&self) -> bool {
@@ -189,7 +190,9 @@ impl FilteredDecoder for Ascii85Decoder {
                     } else {
                         2
                     };
-                    let r = self.internal_transform(&src[start..], dst, flush)?;
+                    let r = self
+                        .internal_transform(&src[start..], dst, flush)
+                        .map_err(|e| e.at(start))?;
                     let (a, b) = r.unpack();
                     return Ok(Status::Ok(a + start, b));
                 }
@@ -209,15 +212,18 @@ impl FilteredDecoder for Ascii85Decoder {
                 (_, len, false) if x + 1 == len => x,
                 (_, len, false) if x + 2 == len && src[x + 1] == b'>' => {
                     let r = self.internal_transform(&src[0..x], dst, FlushState::Finish)?;
-                    let dstconsumed = r.unpack().1;
+                    let (srcconsumed, dstconsumed) = r.unpack();
+                    // If the output is full, finish the data before handling the terminator.
+                    if srcconsumed < x {
+                        return Ok(Status::Ok(srcconsumed, dstconsumed));
+                    }
                     self.end = true;
                     return Ok(Status::StreamEnd(src.len(), dstconsumed));
                 }
                 (_, _, false) => {
-                    return Err(Error::InvalidSequence(
-                        "ascii85".to_string(),
-                        src[x..].to_vec(),
-                    ))
+                    return Err(
+                        Error::InvalidSequence("ascii85".to_string(), src[x..].to_vec()).at(x),
+                    )
                 }
             }
         } else {
@@ -226,13 +232,12 @@ impl FilteredDecoder for Ascii85Decoder {
 
         let maxi = match (flush, end) {
             (FlushState::Finish, x) => x,
-            (FlushState::None, x) if x <= is => return Ok(Status::SeqError(0, 0)),
-            (FlushState::None, x) => x - is,
+            // Only start a group if all of it is present, since it may be shorter at the end.
+            (FlushState::None, x) if x < is => return Ok(Status::SeqError(0, 0)),
+            (FlushState::None, x) => x - is + 1,
         };
-        let maxj = dst.len() - os;
-
         let (mut i, mut j) = (0, 0);
-        while i < maxi && j < maxj {
+        while i < maxi && j + os <= dst.len() {
             let srcend = cmp::min(i + is, end);
             let (ri, rj) = self.transform_chunk(&src[i..srcend], &mut dst[j..j + os]);
             i += ri;
@@ -263,14 +268,18 @@ impl Codec for Ascii85Decoder {
 
 #[cfg(test)]
 mod tests {
+    use super::Ascii85Decoder;
     use chain::Chain;
     use codec::registry::CodecRegistry;
     use codec::tests;
+    use codec::Codec;
+    use codec::FlushState;
+    use codec::Status;
 
     fn check(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
         let rev = format!("-{}", name);
-        for i in vec![5, 6, 7, 8, 512] {
+        for &i in &[5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &rev, i, true);
@@ -336,6 +345,37 @@ mod tests {
         check("ascii85", b"Man is distinguished, not only by his reason, but by this singular passion from other animals, which is a lust of the mind, that by a perseverance of delight in the continued and indefatigable generation of knowledge, exceeds the short vehemence of any carnal pleasure.", br#"<~9jqo^BlbD-BleB1DJ+*+F(f,q/0JhKF<GL>Cj@.4Gp$d7F!,L7@<6@)/0JDEF<G%<+EV:2F!,O<DJ+*.@<*K0@<6L(Df-\0Ec5e;DffZ(EZee.Bl.9pF"AGXBPCsi+DGm>@3BB/F*&OCAfu2/AKYi(DIb:@FD,*)+C]U=@3BN#EcYf8ATD3s@q?d$AftVqCh[NqF<G:8+EV:.+Cf>-FD5W8ARlolDIal(DId<j@<?3r@:F%a+D58'ATD4$Bl@l3De:,-DJs`8ARoFb/0JMK@qB4^F!,R<AKZ&-DfTqBG%G>uD.RTpAKYo'+CT/5+Cei#DII?(E,9)oF*2M7/c~>"#);
     }
 
+    #[test]
+    fn decodes_data_before_terminator_with_full_output() {
+        let inp: &[u8] = b"<~87cURD_*#TDfTZ)~>";
+        let mut dec = Ascii85Decoder::new(true);
+        let mut res = Vec::new();
+        let mut pos = 0;
+        loop {
+            let mut out = [0u8; 8];
+            match dec.transform(&inp[pos..], &mut out, FlushState::Finish) {
+                Ok(Status::Ok(a, b)) => {
+                    assert!(a + b > 0);
+                    res.extend_from_slice(&out[..b]);
+                    pos += a;
+                }
+                Ok(Status::StreamEnd(a, b)) => {
+                    res.extend_from_slice(&out[..b]);
+                    pos += a;
+                    break;
+                }
+                _ => panic!("unexpected result"),
+            }
+        }
+        assert_eq!(pos, inp.len());
+        assert_eq!(res, b"Hello, world");
+    }
+
+    #[test]
+    fn decodes_within_buffer_size() {
+        tests::check_buffer_size("-ascii85", b"<~87cURD_*#TDfTZ)~>", b"Hello, world");
+    }
+
     #[test]
     fn default_tests_ascii85() {
         tests::round_trip("ascii85");
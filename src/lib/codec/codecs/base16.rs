@@ -1,11 +1,12 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
 
+use codec::schema::OptionSchema;
+use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
+use codec::Direction;
 use codec::Error;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -17,21 +18,25 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         let settings = CodecSettings {
             bufsize: s.bufsize,
             strict: s.strict,
             args: ["upper"].iter().map(|&x| (String::from(x), None)).collect(),
             dir: s.dir,
+            threads: s.threads,
+            max_output: s.max_output,
         };
-        ::codec::codecs::hex::TransformFactory::new().factory(r, settings)
+        ::codec::codecs::hex::TransformFactory::new().codec(settings)
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("lower".to_string(), tr!("use lowercase letters"));
-        map.insert("upper".to_string(), tr!("use uppercase letters"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("lower", tr!("use lowercase letters"))
+            .direction(Direction::Forward)
+            .flag("upper", tr!("use uppercase letters"))
+            .direction(Direction::Forward)
+            .exclusive(&["lower", "upper"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -71,6 +76,7 @@ mod tests {
     #[test]
     fn default_tests() {
         tests::round_trip("base16");
+        tests::vectorized_matches_portable("base16");
         tests::round_trip_stripped_whitespace("base16");
         tests::basic_configuration("base16");
         tests::invalid_data("base16");
@@ -5,14 +5,14 @@ use codec::helpers::codecs::ChunkedDecoder;
 use codec::helpers::codecs::PaddedDecoder;
 use codec::helpers::codecs::PaddedEncoder;
 use codec::helpers::codecs::StatelessEncoder;
+use codec::helpers::simd;
+use codec::schema::OptionSchema;
+use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
 use codec::Direction;
 use codec::Error;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct Base32TransformFactory {}
@@ -58,6 +58,8 @@ pub const REVHEX: [i8; 256] = [
 ];
 
 fn forward_transform(inp: &[u8], outp: &mut [u8], arr: &[u8; 32]) -> (usize, usize) {
+    let (a, b) = simd::encode(inp, outp, arr);
+    let (inp, outp) = (&inp[a..], &mut outp[b..]);
     let (is, os) = (5, 8);
     let bits = is * 8 / os;
     let mask = (1u64 << bits) - 1;
@@ -73,7 +75,7 @@ fn forward_transform(inp: &[u8], outp: &mut [u8], arr: &[u8; 32]) -> (usize, usi
             *val = arr[(x >> ((os - 1 - k) * bits) & mask) as usize];
         }
     }
-    (n * is, n * os)
+    (a + n * is, b + n * os)
 }
 
 impl Base32TransformFactory {
@@ -81,13 +83,12 @@ impl Base32TransformFactory {
         Base32TransformFactory {}
     }
 
-    fn factory_for(
+    fn codec_for(
         name: &'static str,
         forward: &'static [u8; 32],
         reverse: &'static [i8; 256],
-        r: Box<io::BufRead>,
         s: CodecSettings,
-    ) -> Result<Box<io::BufRead>, Error> {
+    ) -> Result<Option<Box<Codec + Send>>, Error> {
         let pad = match (s.bool_arg("pad")?, s.bool_arg("nopad")?) {
             (true, true) => {
                 return Err(Error::IncompatibleParameters("pad".into(), "nopad".into()))
@@ -95,39 +96,35 @@ impl Base32TransformFactory {
             (_, false) => Some(b'='),
             (false, true) => None,
         };
-        let codec = match s.dir {
-            Direction::Forward => PaddedEncoder::new(
-                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, forward), 8),
+        let codec: Box<Codec + Send> = match s.dir {
+            Direction::Forward => Box::new(PaddedEncoder::new(
+                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, forward), 8)
+                    .with_ratio(5, 8),
                 5,
                 8,
                 pad,
-            )
-            .into_bufread(r, s.bufsize),
-            Direction::Reverse => PaddedDecoder::new(
+            )),
+            Direction::Reverse => Box::new(PaddedDecoder::new(
                 ChunkedDecoder::new(s.strict, name, 8, 5, reverse),
                 8,
                 5,
                 pad,
-            )
-            .into_bufread(r, s.bufsize),
+            )),
         };
-        Ok(codec)
+        Ok(Some(codec))
     }
 }
 
 impl CodecTransform for Base32TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        Base32TransformFactory::factory_for(self.name(), &BASE32, &REV, r, s)
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        Base32TransformFactory::codec_for(self.name(), &BASE32, &REV, s)
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("pad".to_string(), tr!("pad incomplete sequences with ="));
-        map.insert(
-            "nopad".to_string(),
-            tr!("do not pad incomplete sequences with ="),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("pad", tr!("pad incomplete sequences with ="))
+            .flag("nopad", tr!("do not pad incomplete sequences with ="))
+            .exclusive(&["pad", "nopad"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -146,18 +143,15 @@ impl Base32HexTransformFactory {
 }
 
 impl CodecTransform for Base32HexTransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        Base32TransformFactory::factory_for(self.name(), &BASE32HEX, &REVHEX, r, s)
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        Base32TransformFactory::codec_for(self.name(), &BASE32HEX, &REVHEX, s)
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("pad".to_string(), tr!("pad incomplete sequences with ="));
-        map.insert(
-            "nopad".to_string(),
-            tr!("do not pad incomplete sequences with ="),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("pad", tr!("pad incomplete sequences with ="))
+            .flag("nopad", tr!("do not pad incomplete sequences with ="))
+            .exclusive(&["pad", "nopad"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -177,13 +171,13 @@ mod tests {
 
     fn check(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![5, 6, 7, 8, 512] {
+        for &i in &[5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
         }
 
         let rev = format!("-{}", name);
-        for i in vec![8, 9, 10, 11, 512] {
+        for &i in &[8, 9, 10, 11, 512] {
             let c = Chain::new(&reg, &rev, i, true);
             assert_eq!(c.transform(outp.to_vec()).unwrap(), inp);
             let c = Chain::new(&reg, &rev, i, false);
@@ -244,6 +238,7 @@ mod tests {
     #[test]
     fn default_tests_base32() {
         tests::round_trip("base32");
+        tests::vectorized_matches_portable("base32");
         tests::round_trip_stripped_whitespace("base32");
         tests::basic_configuration("base32");
         tests::invalid_data("base32");
@@ -252,6 +247,7 @@ mod tests {
     #[test]
     fn default_tests_base32hex() {
         tests::round_trip("base32hex");
+        tests::vectorized_matches_portable("base32hex");
         tests::round_trip_stripped_whitespace("base32hex");
         tests::basic_configuration("base32hex");
         tests::invalid_data("base32hex");
@@ -6,14 +6,14 @@ use codec::helpers::codecs::ChunkedDecoder;
 use codec::helpers::codecs::PaddedDecoder;
 use codec::helpers::codecs::PaddedEncoder;
 use codec::helpers::codecs::StatelessEncoder;
+use codec::helpers::simd;
+use codec::schema::OptionSchema;
+use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
 use codec::Direction;
 use codec::Error;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 pub const BASE64: [u8; 64] = [
     b'A', b'B', b'C', b'D', b'E', b'F', b'G', b'H', b'I', b'J', b'K', b'L', b'M', b'N', b'O', b'P',
@@ -58,6 +58,8 @@ pub const URLREV: [i8; 256] = [
 ];
 
 fn forward_transform(inp: &[u8], outp: &mut [u8], arr: &[u8; 64]) -> (usize, usize) {
+    let (a, b) = simd::encode(inp, outp, arr);
+    let (inp, outp) = (&inp[a..], &mut outp[b..]);
     let (is, os) = (3, 4);
     let bits = is * 8 / os;
     let mask = (1u64 << bits) - 1;
@@ -73,7 +75,7 @@ fn forward_transform(inp: &[u8], outp: &mut [u8], arr: &[u8; 64]) -> (usize, usi
             *val = arr[(x >> ((os - 1 - k) * bits) & mask) as usize];
         }
     }
-    (n * is, n * os)
+    (a + n * is, b + n * os)
 }
 
 #[derive(Default)]
@@ -86,7 +88,7 @@ impl Base64TransformFactory {
 }
 
 impl CodecTransform for Base64TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         let pad = match (s.bool_arg("pad")?, s.bool_arg("nopad")?) {
             (true, true) => {
                 return Err(Error::IncompatibleParameters("pad".into(), "nopad".into()))
@@ -95,31 +97,27 @@ impl CodecTransform for Base64TransformFactory {
             (false, true) => None,
         };
         match s.dir {
-            Direction::Forward => Ok(PaddedEncoder::new(
-                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, &BASE64), 4),
+            Direction::Forward => Ok(Some(Box::new(PaddedEncoder::new(
+                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, &BASE64), 4)
+                    .with_ratio(3, 4),
                 3,
                 4,
                 pad,
-            )
-            .into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(PaddedDecoder::new(
+            )))),
+            Direction::Reverse => Ok(Some(Box::new(PaddedDecoder::new(
                 ChunkedDecoder::new(s.strict, "base64", 4, 3, &REV),
                 4,
                 3,
                 pad,
-            )
-            .into_bufread(r, s.bufsize)),
+            )))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("pad".to_string(), tr!("pad incomplete sequences with ="));
-        map.insert(
-            "nopad".to_string(),
-            tr!("do not pad incomplete sequences with ="),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("pad", tr!("pad incomplete sequences with ="))
+            .flag("nopad", tr!("do not pad incomplete sequences with ="))
+            .exclusive(&["pad", "nopad"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -141,7 +139,7 @@ impl URL64TransformFactory {
 }
 
 impl CodecTransform for URL64TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         let pad = match (s.bool_arg("pad")?, s.bool_arg("nopad")?) {
             (true, true) => {
                 return Err(Error::IncompatibleParameters("pad".into(), "nopad".into()))
@@ -150,31 +148,27 @@ impl CodecTransform for URL64TransformFactory {
             (true, false) => Some(b'='),
         };
         match s.dir {
-            Direction::Forward => Ok(PaddedEncoder::new(
-                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, &URL64), 4),
+            Direction::Forward => Ok(Some(Box::new(PaddedEncoder::new(
+                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, &URL64), 4)
+                    .with_ratio(3, 4),
                 3,
                 4,
                 pad,
-            )
-            .into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(PaddedDecoder::new(
+            )))),
+            Direction::Reverse => Ok(Some(Box::new(PaddedDecoder::new(
                 ChunkedDecoder::new(s.strict, "url64", 4, 3, &URLREV),
                 4,
                 3,
                 pad,
-            )
-            .into_bufread(r, s.bufsize)),
+            )))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("pad".to_string(), tr!("pad incomplete sequences with ="));
-        map.insert(
-            "nopad".to_string(),
-            tr!("do not pad incomplete sequences with ="),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("pad", tr!("pad incomplete sequences with ="))
+            .flag("nopad", tr!("do not pad incomplete sequences with ="))
+            .exclusive(&["pad", "nopad"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -195,7 +189,7 @@ mod tests {
     fn check(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
         let rev = format!("-{}", name);
-        for i in vec![5, 6, 7, 8, 512] {
+        for &i in &[5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &rev, i, true);
@@ -258,6 +252,7 @@ mod tests {
     #[test]
     fn default_tests_base64() {
         tests::round_trip("base64");
+        tests::vectorized_matches_portable("base64");
         tests::round_trip_stripped_whitespace("base64");
         tests::basic_configuration("base64");
         tests::invalid_data("base64");
@@ -266,6 +261,7 @@ mod tests {
     #[test]
     fn default_tests_url64() {
         tests::round_trip("url64");
+        tests::vectorized_matches_portable("url64");
         tests::round_trip_stripped_whitespace("url64");
         tests::basic_configuration("url64");
         tests::invalid_data("url64");
@@ -2,6 +2,7 @@
 #![allow(bare_trait_objects)]
 
 use codec::helpers::codecs::FilteredDecoder;
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -9,10 +10,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 pub const V: [u8; 6] = *b"aeiouy";
 pub const C: [u8; 17] = *b"bcdfghklmnprstvzx";
@@ -54,15 +52,15 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
-            Direction::Forward => Ok(Encoder::new().into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(Decoder::new(s.strict).into_bufread(r, s.bufsize)),
+            Direction::Forward => Ok(Some(Box::new(Encoder::new()))),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(s.strict)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        BTreeMap::new()
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
     }
 
     fn can_reverse(&self) -> bool {
@@ -295,7 +293,8 @@ impl FilteredDecoder for Decoder {
         if chunks == 0 {
             return match (f, self.finished, inp.len(), outp.len()) {
                 (FlushState::Finish, false, 4, len) if len >= 2 => {
-                    let count = Self::transform_final_chunk(inp, outp, self.c)?;
+                    let count =
+                        Self::transform_final_chunk(inp, outp, self.c).map_err(|e| e.at(extra))?;
                     self.finished = true;
                     Ok(Status::StreamEnd(4 + extra, count))
                 }
@@ -307,6 +306,7 @@ impl FilteredDecoder for Decoder {
         let (il, ol) = (inp.len(), outp.len());
         self.c = (0..chunks).try_fold(self.c, |res, i| {
             Self::transform_chunk(&inp[i * is..il], &mut outp[i * os..ol], res)
+                .map_err(|e| e.at(i * is + extra))
         })?;
         Ok(Status::Ok(chunks * is + extra, chunks * os))
     }
@@ -334,12 +334,12 @@ mod tests {
 
     fn check(inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![2, 3, 4, 5, 6, 7, 512] {
+        for &i in &[2, 3, 4, 5, 6, 7, 512] {
             let c = Chain::new(&reg, "bubblebabble", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
         }
 
-        for i in vec![6, 7, 8, 9, 10, 11, 512] {
+        for &i in &[6, 7, 8, 9, 10, 11, 512] {
             let c = Chain::new(&reg, "-bubblebabble", i, true);
             assert_eq!(c.transform(outp.to_vec()).unwrap(), inp);
             let c = Chain::new(&reg, "-bubblebabble", i, false);
@@ -1,6 +1,8 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
+#![allow(clippy::manual_is_multiple_of)]
 
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -8,12 +10,9 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
-use std::collections::BTreeMap;
 use std::convert::TryInto;
-use std::io;
 
-trait Hash {
+trait Hash: Send {
     fn input(&mut self, data: &[u8]);
     fn result_reset(&mut self) -> Box<[u8]>;
     fn input_size(&self) -> usize;
@@ -43,19 +42,17 @@ impl Endianness {
     }
 }
 
-struct Adler32 {
+pub struct Adler32 {
     a: u16,
     b: u16,
 }
 
 impl Adler32 {
-    fn new() -> Adler32 {
+    pub fn new() -> Adler32 {
         Adler32 { a: 1, b: 0 }
     }
-}
 
-impl Hash for Adler32 {
-    fn input(&mut self, data: &[u8]) {
+    pub fn update(&mut self, data: &[u8]) {
         let (a, b) = data.chunks(1024).fold((self.a, self.b), |(a, b), chunk| {
             let (x, y) = chunk
                 .iter()
@@ -70,9 +67,24 @@ impl Hash for Adler32 {
         self.b = b;
     }
 
+    pub fn sum(&self) -> u32 {
+        (self.b as u32) << 16 | self.a as u32
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hash for Adler32 {
+    fn input(&mut self, data: &[u8]) {
+        self.update(data)
+    }
+
     fn result_reset(&mut self) -> Box<[u8]> {
-        let x: u32 = (self.b as u32) << 16 | self.a as u32;
-        x.to_be_bytes().to_vec().into_boxed_slice()
+        self.sum().to_be_bytes().to_vec().into_boxed_slice()
     }
 
     fn input_size(&self) -> usize {
@@ -195,73 +207,48 @@ impl TransformFactory {
 }
 
 impl TransformFactory {
-    fn digest(
-        name: &str,
-        length: Option<usize>,
-        endianness: Endianness,
-    ) -> Result<Box<Hash>, Error> {
-        match (name, length) {
-            ("adler32", _) => Ok(Box::new(Adler32::new())),
-            ("fletcher16", _) => Ok(Box::new(Fletcher16::new())),
-            ("fletcher32", _) => Ok(Box::new(Fletcher32::new(endianness))),
+    fn digest(name: &str, endianness: Endianness) -> Result<Box<Hash>, Error> {
+        match name {
+            "adler32" => Ok(Box::new(Adler32::new())),
+            "fletcher16" => Ok(Box::new(Fletcher16::new())),
+            "fletcher32" => Ok(Box::new(Fletcher32::new(endianness))),
             _ => Err(Error::UnknownArgument(name.to_string())),
         }
     }
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => (),
             Direction::Reverse => return Err(Error::ForwardOnly("checksum".to_string())),
         }
 
-        let length = s.int_arg("length")?;
-        let endianness: Vec<_> = s
+        let endianness = s
             .args
-            .iter()
-            .filter_map(|(s, _)| Endianness::from_str(s))
-            .collect();
-        let args: Vec<_> = s
+            .keys()
+            .filter_map(|s| Endianness::from_str(s))
+            .next()
+            .unwrap_or(Endianness::Big);
+        let name = s
             .args
             .keys()
-            .filter(|&s| s != "length" && Endianness::from_str(s).is_none())
-            .collect();
-        match args.len() {
-            0 => return Err(Error::MissingArgument("checksum".to_string())),
-            1 => (),
-            _ => {
-                return Err(Error::IncompatibleParameters(
-                    args[0].to_string(),
-                    args[1].to_string(),
-                ));
-            }
-        };
-        let endianness = match endianness.len() {
-            0 => Endianness::Big,
-            1 => endianness[0],
-            _ => {
-                return Err(Error::IncompatibleParameters(
-                    "be".to_string(),
-                    "le".to_string(),
-                ));
-            }
-        };
-        Ok(Encoder::new(Self::digest(args[0], length, endianness)?).into_bufread(r, s.bufsize))
+            .find(|&s| Endianness::from_str(s).is_none())
+            .ok_or_else(|| Error::MissingArgument("checksum".to_string()))?;
+        Ok(Some(Box::new(Encoder::new(Self::digest(
+            name, endianness,
+        )?))))
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("adler32".to_string(), tr!("use Adler32 as the checksum"));
-        map.insert(
-            "fletcher16".to_string(),
-            tr!("use Fletcher16 as the checksum"),
-        );
-        map.insert(
-            "fletcher32".to_string(),
-            tr!("use Fletcher32 as the checksum"),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("adler32", tr!("use Adler32 as the checksum"))
+            .flag("fletcher16", tr!("use Fletcher16 as the checksum"))
+            .flag("fletcher32", tr!("use Fletcher32 as the checksum"))
+            .one_of("checksum", &["adler32", "fletcher16", "fletcher32"])
+            .flag("be", tr!("produce a big-endian checksum (default)"))
+            .flag("le", tr!("produce a little-endian checksum"))
+            .exclusive(&["be", "le"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -337,7 +324,7 @@ mod tests {
         let reg = CodecRegistry::new();
         let codec = format!("checksum({}):hex", algo);
         let dlen = outp.len() / 2;
-        for i in vec![dlen, dlen + 1, dlen + 2, dlen + 3, 512] {
+        for &i in &[dlen, dlen + 1, dlen + 2, dlen + 3, 512] {
             let c = Chain::new(&reg, &codec, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &codec, i, false);
@@ -1,53 +1,1444 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
 #![allow(ellipsis_inclusive_range_patterns)]
+#![allow(clippy::manual_is_multiple_of)]
 
+use codec::codecs::checksum::Adler32;
+use codec::codecs::lzw;
+use codec::parallel::ParallelReader;
+use codec::schema::{OptionKind, OptionSchema};
+use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
 use codec::Direction;
 use codec::Error;
+use codec::FlushState;
+use codec::Position;
+use codec::Status;
+use codec::Transform;
 use flate2::bufread::{
     DeflateDecoder, DeflateEncoder, GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder,
 };
-use flate2::Compression;
-use std::collections::BTreeMap;
+use flate2::{
+    Compress, CompressError, Compression, CrcReader, Decompress, DecompressError, FlushCompress,
+    FlushDecompress, GzBuilder,
+};
+use std::cmp;
 use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum CompressionType {
     Deflate,
     Gzip,
     Zlib,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
+    #[cfg(feature = "xz")]
+    Xz,
+    #[cfg(feature = "xz")]
+    Lzma,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// The compression levels a format accepts.
+struct Levels {
+    min: u32,
+    max: u32,
+    fast: u32,
+    best: u32,
+    default: u32,
+}
+
+impl CompressionType {
+    fn levels(self) -> Levels {
+        let (min, max, fast, best, default) = match self {
+            CompressionType::Deflate | CompressionType::Gzip | CompressionType::Zlib => {
+                (0, 9, 1, 9, 6)
+            }
+            #[cfg(feature = "bzip2")]
+            CompressionType::Bzip2 => (1, 9, 1, 9, 9),
+            #[cfg(feature = "xz")]
+            CompressionType::Xz | CompressionType::Lzma => (0, 9, 0, 9, 6),
+            // Levels above 19 use far more memory; the zstd command needs --ultra for them.
+            #[cfg(feature = "zstd")]
+            CompressionType::Zstd => (1, 22, 1, 19, 3),
+            #[cfg(feature = "brotli")]
+            CompressionType::Brotli => (0, 11, 0, 11, 11),
+            // Levels from 3 up use the slower high-compression mode.
+            #[cfg(feature = "lz4")]
+            CompressionType::Lz4 => (1, 12, 1, 12, 1),
+        };
+        Levels {
+            min,
+            max,
+            fast,
+            best,
+            default,
+        }
+    }
+
+    /// The name of the option for the compression level.
+    fn level_name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            CompressionType::Brotli => "quality",
+            _ => "level",
+        }
+    }
+}
+
+/// The settings given by a codec's arguments.
+struct Options {
+    level: u32,
+    dict: Option<Vec<u8>>,
+    name: Option<String>,
+    comment: Option<String>,
+    mtime: u32,
+    threads: usize,
+    block: usize,
+    #[cfg(feature = "brotli")]
+    window: u32,
+    #[cfg(feature = "lz4")]
+    block_size: usize,
+}
+
+impl Options {
+    fn new(s: &CodecSettings, ctype: CompressionType) -> Result<Self, Error> {
+        let levels = ctype.levels();
+        let level = if s.bool_arg("fast")? {
+            levels.fast
+        } else if s.bool_arg("best")? {
+            levels.best
+        } else {
+            s.int_arg(ctype.level_name())?.unwrap_or(levels.default)
+        };
+        // The gzip header stores these as NUL-terminated strings.
+        let text = |name: &str| match s.str_arg(name)? {
+            Some(val) if val.contains('\0') => {
+                Err(Error::InvalidArgument(name.to_string(), val.to_string()))
+            }
+            val => Ok(val.map(String::from)),
+        };
+        Ok(Options {
+            level,
+            dict: s.hex_arg("dict")?,
+            name: text("name")?,
+            comment: text("comment")?,
+            mtime: s.int_arg("mtime")?.unwrap_or(0),
+            threads: s.int_arg("threads")?.unwrap_or(s.threads),
+            block: s.int_arg("block")?.unwrap_or(GZIP_BLOCK_SIZE),
+            #[cfg(feature = "brotli")]
+            window: s.int_arg("window")?.unwrap_or(BROTLI_WINDOW),
+            #[cfg(feature = "lz4")]
+            block_size: s.int_arg("blocksize")?.unwrap_or(LZ4_BLOCK_SIZE),
+        })
+    }
+
+    fn compression(&self) -> Compression {
+        Compression::new(self.level)
+    }
+
+    /// Returns the gzip header for these options, as `GzBuilder` writes it.
+    fn gzip_header(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.name.is_some() {
+            flags |= 0x08;
+        }
+        if self.comment.is_some() {
+            flags |= 0x10;
+        }
+        let xfl = if self.level >= Compression::best().level() {
+            2
+        } else if self.level <= Compression::fast().level() {
+            4
+        } else {
+            0
+        };
+        let mut header = vec![0x1f, 0x8b, 8, flags];
+        header.extend_from_slice(&self.mtime.to_le_bytes());
+        // The operating system is unknown, so that the output is the same everywhere.
+        header.extend_from_slice(&[xfl, 0xff]);
+        for s in self.name.iter().chain(self.comment.iter()) {
+            header.extend_from_slice(s.as_bytes());
+            header.push(0);
+        }
+        header
+    }
+
+    fn gzip_builder(&self) -> GzBuilder {
+        let mut b = GzBuilder::new().mtime(self.mtime);
+        if let Some(ref name) = self.name {
+            b = b.filename(name.as_bytes());
+        }
+        if let Some(ref comment) = self.comment {
+            b = b.comment(comment.as_bytes());
+        }
+        b
+    }
+}
+
+// io::Error::other is newer than our minimum supported version.
+#[allow(clippy::io_other_error)]
+fn compress_error(e: CompressError) -> Error {
+    Error::IO(io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn decompress_error(e: DecompressError) -> Error {
+    Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// A reader which reads the gzip header from a `GzEncoder` separately from the compressed data.
+///
+/// `GzEncoder` discards the part of the header it has already copied if reading the compressed
+/// data in the same call fails, which happens whenever the input isn't available yet, such as
+/// when the chain is driven by a writer.
+struct GzipHeaderReader<R: io::Read> {
+    inner: R,
+    header: usize,
+}
+
+impl<R: io::Read> io::Read for GzipHeaderReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.header == 0 {
+            return self.inner.read(buf);
+        }
+        let len = buf.len().min(self.header);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.header -= n;
+        Ok(n)
+    }
+}
+
+/// The size of each block compressed independently by the parallel gzip encoder if none is given.
+const GZIP_BLOCK_SIZE: usize = 128 * 1024;
+
+/// A codec which compresses each input it is given as raw deflate data, ending it with a sync flush
+/// instead of a final block, so that the output for consecutive inputs can be concatenated.
+///
+/// Nothing is carried over from one input to the next, so no data in one block refers to another.
+struct DeflateBlockEncoder {
+    compress: Compress,
+}
+
+impl DeflateBlockEncoder {
+    fn new(level: Compression) -> Self {
+        DeflateBlockEncoder {
+            compress: Compress::new(level, false),
+        }
+    }
+}
+
+impl Codec for DeflateBlockEncoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        let (before_in, before_out) = (self.compress.total_in(), self.compress.total_out());
+        let flush = match f {
+            FlushState::Finish => FlushCompress::Sync,
+            FlushState::None => FlushCompress::None,
+        };
+        self.compress
+            .compress(inp, out, flush)
+            .map_err(compress_error)?;
+        let consumed = (self.compress.total_in() - before_in) as usize;
+        let written = (self.compress.total_out() - before_out) as usize;
+        // The flush is complete once it no longer fills the output.
+        if f == FlushState::Finish && consumed == inp.len() && written < out.len() {
+            self.compress.reset();
+            return Ok(Status::StreamEnd(consumed, written));
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+}
+
+/// A reader which produces a gzip stream by compressing blocks of its input on several threads.
+///
+/// As with `pigz --independent`, the output is a single gzip member, but each block is compressed
+/// without reference to the preceding one.  This costs a little in compression, but the output
+/// can be decoded by any gzip decoder.
+///
+/// This format is intentionally neither multi-member output nor pigz's default of priming each
+/// block with the end of the previous one as a preset dictionary.  A single member keeps the
+/// header's name, comment, and mtime meaningful and decodes fully with tools which read only the
+/// first member, and independent blocks keep the workers free of any shared state.
+struct ParallelGzipEncoder<R: io::BufRead> {
+    inner: ParallelReader<DeflateBlockEncoder, CrcReader<R>>,
+    // The header, or once the compressed data is exhausted, the trailer.
+    pending: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: io::BufRead> ParallelGzipEncoder<R> {
+    fn new(r: R, o: &Options) -> Self {
+        let codecs = (0..o.threads)
+            .map(|_| DeflateBlockEncoder::new(o.compression()))
+            .collect();
+        ParallelGzipEncoder {
+            inner: ParallelReader::new(CrcReader::new(r), codecs, o.block),
+            pending: o.gzip_header(),
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    fn trailer(&self) -> Vec<u8> {
+        let crc = self.inner.get_ref().crc();
+        // An empty final block ends the deflate stream.
+        let mut trailer = vec![0x03, 0x00];
+        trailer.extend_from_slice(&crc.sum().to_le_bytes());
+        trailer.extend_from_slice(&crc.amount().to_le_bytes());
+        trailer
+    }
+}
+
+impl<R: io::BufRead> io::Read for ParallelGzipEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.pending.len() {
+                let n = cmp::min(buf.len(), self.pending.len() - self.pos);
+                buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            let n = self.inner.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.pending = self.trailer();
+            self.pos = 0;
+            self.finished = true;
+        }
+    }
+}
+
+/// A codec which compresses raw deflate or zlib data using a preset dictionary.
+///
+/// The window is primed by compressing the dictionary and discarding the output, which ends with
+/// a sync flush so that the compressed data proper starts with a new block.
+struct DictEncoder {
+    compress: Compress,
+    adler: Adler32,
+    zlib: bool,
+    // Output not yet written: the zlib header, and later the trailer.
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl DictEncoder {
+    fn new(level: Compression, dict: &[u8], zlib: bool) -> Result<Self, Error> {
+        let mut compress = Compress::new(level, false);
+        let mut scratch = vec![0u8; 65536];
+        loop {
+            let (before_in, before_out) = (compress.total_in() as usize, compress.total_out());
+            compress
+                .compress(&dict[before_in..], &mut scratch, FlushCompress::Sync)
+                .map_err(compress_error)?;
+            let written = (compress.total_out() - before_out) as usize;
+            if compress.total_in() as usize == dict.len() && written < scratch.len() {
+                break;
+            }
+        }
+        let mut pending = Vec::new();
+        if zlib {
+            // The compression level as zlib records it, with the flag for a preset dictionary.
+            let flevel = match level.level() {
+                0 | 1 => 0,
+                2..=5 => 1,
+                6 => 2,
+                _ => 3,
+            };
+            let (cmf, flg) = (0x78u8, flevel << 6 | 0x20);
+            let check = (31 - (u16::from(cmf) << 8 | u16::from(flg)) % 31) % 31;
+            pending.extend_from_slice(&[cmf, flg | check as u8]);
+            let mut adler = Adler32::new();
+            adler.update(dict);
+            pending.extend_from_slice(&adler.sum().to_be_bytes());
+        }
+        Ok(DictEncoder {
+            compress,
+            adler: Adler32::new(),
+            zlib,
+            pending,
+            finished: false,
+        })
+    }
+
+    /// Write as much pending output as fits into `out`, returning the amount written.
+    fn drain(&mut self, out: &mut [u8]) -> usize {
+        let n = cmp::min(out.len(), self.pending.len());
+        out[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+}
+
+impl Codec for DictEncoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        let mut written = self.drain(out);
+        if !self.pending.is_empty() || self.finished {
+            return Ok(Status::Ok(0, written));
+        }
+        let (before_in, before_out) = (self.compress.total_in(), self.compress.total_out());
+        let flush = match f {
+            FlushState::Finish => FlushCompress::Finish,
+            FlushState::None => FlushCompress::None,
+        };
+        let status = self
+            .compress
+            .compress(inp, &mut out[written..], flush)
+            .map_err(compress_error)?;
+        let consumed = (self.compress.total_in() - before_in) as usize;
+        written += (self.compress.total_out() - before_out) as usize;
+        self.adler.update(&inp[..consumed]);
+        if status == flate2::Status::StreamEnd {
+            self.finished = true;
+            if self.zlib {
+                self.pending = self.adler.sum().to_be_bytes().to_vec();
+                written += self.drain(&mut out[written..]);
+            }
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DictState {
+    Header,
+    Body,
+    Trailer,
+    Done,
+}
+
+/// A codec which decompresses raw deflate or zlib data compressed with a preset dictionary.
+///
+/// The window is primed by decompressing the dictionary stored in uncompressed blocks.
+struct DictDecoder {
+    decompress: Decompress,
+    adler: Adler32,
+    dictid: u32,
+    zlib: bool,
+    state: DictState,
+    // The part of the zlib header or trailer read so far.
+    buf: Vec<u8>,
+}
+
+impl DictDecoder {
+    fn new(dict: &[u8], zlib: bool) -> Result<Self, Error> {
+        let mut stored = Vec::with_capacity(dict.len() + 5 * (dict.len() / 65535 + 1));
+        for chunk in dict.chunks(65535) {
+            let len = chunk.len() as u16;
+            stored.push(0);
+            stored.extend_from_slice(&len.to_le_bytes());
+            stored.extend_from_slice(&(!len).to_le_bytes());
+            stored.extend_from_slice(chunk);
+        }
+        let mut decompress = Decompress::new(false);
+        let mut scratch = Vec::with_capacity(dict.len());
+        decompress
+            .decompress_vec(&stored, &mut scratch, FlushDecompress::None)
+            .map_err(decompress_error)?;
+        let mut adler = Adler32::new();
+        adler.update(dict);
+        Ok(DictDecoder {
+            decompress,
+            adler: Adler32::new(),
+            dictid: adler.sum(),
+            zlib,
+            state: if zlib {
+                DictState::Header
+            } else {
+                DictState::Body
+            },
+            buf: Vec::new(),
+        })
+    }
+
+    /// The length of the zlib header or trailer, as far as is known from what has been read.
+    fn wanted(&self) -> usize {
+        match self.state {
+            DictState::Header if self.buf.len() >= 2 && self.buf[1] & 0x20 != 0 => 6,
+            DictState::Header => 2,
+            _ => 4,
+        }
+    }
+
+    /// Check a complete zlib header or trailer and move on to the next state.
+    fn check(&mut self) -> Result<(), Error> {
+        let bytes = |b: &[u8]| {
+            u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3])
+        };
+        let invalid = |b: &[u8]| Error::InvalidSequence("zlib".to_string(), b.to_vec());
+        match self.state {
+            DictState::Header => {
+                let (cmf, flg) = (self.buf[0], self.buf[1]);
+                if cmf & 0x0f != 8 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+                    return Err(invalid(&self.buf));
+                }
+                if self.buf.len() == 6 && bytes(&self.buf[2..]) != self.dictid {
+                    return Err(invalid(&self.buf));
+                }
+                self.state = DictState::Body;
+            }
+            _ => {
+                if bytes(&self.buf) != self.adler.sum() {
+                    return Err(invalid(&self.buf));
+                }
+                self.state = DictState::Done;
+            }
+        }
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Codec for DictDecoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        let (mut consumed, mut written): (usize, usize) = (0, 0);
+        loop {
+            match self.state {
+                DictState::Header | DictState::Trailer => {
+                    let want = self.wanted();
+                    if self.buf.len() == want {
+                        // Report a bad header or trailer at its start, if it is in this input.
+                        let start = consumed.saturating_sub(self.buf.len());
+                        self.check().map_err(|e| e.at(start))?;
+                        continue;
+                    }
+                    if consumed == inp.len() {
+                        break;
+                    }
+                    let n = cmp::min(want - self.buf.len(), inp.len() - consumed);
+                    self.buf.extend_from_slice(&inp[consumed..consumed + n]);
+                    consumed += n;
+                }
+                DictState::Body => {
+                    let (before_in, before_out) =
+                        (self.decompress.total_in(), self.decompress.total_out());
+                    let status = self
+                        .decompress
+                        .decompress(&inp[consumed..], &mut out[written..], FlushDecompress::None)
+                        .map_err(decompress_error)?;
+                    consumed += (self.decompress.total_in() - before_in) as usize;
+                    let start = written;
+                    written += (self.decompress.total_out() - before_out) as usize;
+                    self.adler.update(&out[start..written]);
+                    if status != flate2::Status::StreamEnd {
+                        break;
+                    }
+                    self.state = if self.zlib {
+                        DictState::Trailer
+                    } else {
+                        DictState::Done
+                    };
+                }
+                DictState::Done => {
+                    // Like the other decoders, ignore anything after the end of the data.
+                    consumed = inp.len();
+                    break;
+                }
+            }
+        }
+        if f == FlushState::Finish && self.state != DictState::Done && written == 0 {
+            return Err(Error::TruncatedData);
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+/// A reader which counts the bytes consumed from another reader.
+struct CountingReader<R: io::BufRead> {
+    inner: R,
+    count: u64,
+    // Whether the other reader has returned an error since this was last cleared.
+    failed: bool,
+}
+
+impl<R: io::BufRead> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            count: 0,
+            failed: false,
+        }
+    }
+}
+
+impl<R: io::BufRead> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.count += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<R: io::BufRead> io::BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self.inner.fill_buf() {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        self.inner.consume(amt)
+    }
 }
 
-fn generic_factory(
-    r: Box<io::BufRead>,
+/// A reader which decompresses every gzip member in its input, one after another.
+///
+/// Anything after the last member which isn't the start of another is an error in strict mode and
+/// is ignored otherwise.  This includes data which starts with the gzip magic number but doesn't
+/// have a valid header.
+struct MultiGzipDecoder<R: io::BufRead> {
+    member: Option<GzDecoder<CountingReader<R>>>,
+    // The offset of the current member, or `None` if it is the first.
+    start: Option<u64>,
+    // The reader between members, or `None` once the input has ended.
+    between: Option<CountingReader<R>>,
+    strict: bool,
+}
+
+impl<R: io::BufRead> MultiGzipDecoder<R> {
+    fn new(r: R, strict: bool) -> Self {
+        MultiGzipDecoder {
+            member: Some(GzDecoder::new(CountingReader::new(r))),
+            start: None,
+            between: None,
+            strict,
+        }
+    }
+
+    /// Handle data at `offset` after the last member, which is an error in strict mode and is
+    /// otherwise skipped.
+    fn trailing(&mut self, mut r: CountingReader<R>, offset: u64) -> io::Result<usize> {
+        if self.strict {
+            self.between = Some(r);
+            let pos = Position { offset, line: None };
+            return Err(Error::AtOffset(pos, Box::new(Error::ExtraData)).into());
+        }
+        loop {
+            let n = r.fill_buf()?.len();
+            if n == 0 {
+                return Ok(0);
+            }
+            r.consume(n);
+        }
+    }
+}
+
+impl<R: io::BufRead> io::Read for MultiGzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref mut d) = self.member {
+                d.get_mut().failed = false;
+                let res = d.read(buf);
+                // A member after the first whose header couldn't be read isn't a member at all,
+                // unless the error came from the input.
+                let header = d.header().is_some() || self.start.is_none() || d.get_ref().failed;
+                match res {
+                    Ok(n) if n > 0 || buf.is_empty() => return Ok(n),
+                    Ok(_) if header => (),
+                    Err(e) if header => return Err(e),
+                    _ => {
+                        let r = self.member.take().unwrap().into_inner();
+                        let start = self.start.unwrap_or(0);
+                        return self.trailing(r, start);
+                    }
+                }
+            }
+            if let Some(d) = self.member.take() {
+                self.between = Some(d.into_inner());
+            }
+            // Whether the data starts with the magic number of another member, if there is any.
+            let next = match self.between {
+                Some(ref mut r) => {
+                    let data = r.fill_buf()?;
+                    match data.first() {
+                        Some(&0x1f) => Some(data.len() == 1 || data[1] == 0x8b),
+                        Some(_) => Some(false),
+                        None => None,
+                    }
+                }
+                None => return Ok(0),
+            };
+            match (next, self.between.take()) {
+                (Some(true), Some(r)) => {
+                    self.start = Some(r.count);
+                    self.member = Some(GzDecoder::new(r));
+                }
+                (Some(_), Some(r)) => {
+                    let offset = r.count;
+                    return self.trailing(r, offset);
+                }
+                _ => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A compressing or decompressing reader.
+enum Compressor<R: io::BufRead> {
+    DeflateEncoder(DeflateEncoder<R>),
+    GzipEncoder(GzipHeaderReader<GzEncoder<R>>),
+    ParallelGzipEncoder(ParallelGzipEncoder<R>),
+    ZlibEncoder(ZlibEncoder<R>),
+    DeflateDecoder(DeflateDecoder<R>),
+    GzipDecoder(MultiGzipDecoder<R>),
+    ZlibDecoder(ZlibDecoder<R>),
+    // A format implemented as a codec, such as deflate with a preset dictionary.
+    Codec(Transform<Box<Codec + Send>, R>),
+    #[cfg(feature = "bzip2")]
+    Bzip2Encoder(bzip2::bufread::BzEncoder<R>),
+    #[cfg(feature = "bzip2")]
+    Bzip2Decoder(bzip2::bufread::MultiBzDecoder<R>),
+    #[cfg(feature = "xz")]
+    XzEncoder(xz2::bufread::XzEncoder<R>),
+    #[cfg(feature = "xz")]
+    XzDecoder(xz2::bufread::XzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    ZstdEncoder(zstd::stream::read::Encoder<'static, R>),
+    #[cfg(feature = "zstd")]
+    ZstdDecoder(zstd::stream::read::Decoder<'static, R>),
+}
+
+impl<R: io::BufRead> io::Read for Compressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Compressor::DeflateEncoder(r) => r.read(buf),
+            Compressor::GzipEncoder(r) => r.read(buf),
+            Compressor::ParallelGzipEncoder(r) => r.read(buf),
+            Compressor::ZlibEncoder(r) => r.read(buf),
+            Compressor::DeflateDecoder(r) => r.read(buf),
+            Compressor::GzipDecoder(r) => r.read(buf),
+            Compressor::ZlibDecoder(r) => r.read(buf),
+            Compressor::Codec(r) => r.read(buf),
+            #[cfg(feature = "bzip2")]
+            Compressor::Bzip2Encoder(r) => r.read(buf),
+            #[cfg(feature = "bzip2")]
+            Compressor::Bzip2Decoder(r) => r.read(buf),
+            #[cfg(feature = "xz")]
+            Compressor::XzEncoder(r) => r.read(buf),
+            #[cfg(feature = "xz")]
+            Compressor::XzDecoder(r) => r.read(buf),
+            #[cfg(feature = "zstd")]
+            Compressor::ZstdEncoder(r) => r.read(buf),
+            #[cfg(feature = "zstd")]
+            Compressor::ZstdDecoder(r) => r.read(buf),
+        }
+    }
+}
+
+fn generic_factory<R: io::BufRead>(
+    r: R,
     s: CodecSettings,
     ctype: CompressionType,
-) -> Box<io::BufRead> {
-    let compr = Compression::new(6);
-    match (s.dir, ctype) {
+) -> Result<BufReader<Compressor<R>>, Error> {
+    let o = Options::new(&s, ctype)?;
+    Ok(BufReader::new(match ctype {
+        CompressionType::Deflate | CompressionType::Gzip | CompressionType::Zlib => {
+            flate_compressor(r, &s, &o, ctype)?
+        }
+        #[cfg(feature = "bzip2")]
+        CompressionType::Bzip2 => bzip2_compressor(r, &s, &o),
+        #[cfg(feature = "xz")]
+        CompressionType::Xz | CompressionType::Lzma => xz_compressor(r, &s, &o, ctype)?,
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => zstd_compressor(r, &s, &o)?,
+        #[cfg(feature = "brotli")]
+        CompressionType::Brotli => brotli_compressor(r, &s, &o),
+        #[cfg(feature = "lz4")]
+        CompressionType::Lz4 => lz4_compressor(r, &s, &o)?,
+    }))
+}
+
+/// Returns the reader for one of the formats flate2 implements.
+fn flate_compressor<R: io::BufRead>(
+    r: R,
+    s: &CodecSettings,
+    o: &Options,
+    ctype: CompressionType,
+) -> Result<Compressor<R>, Error> {
+    let compr = o.compression();
+    if let Some(ref dict) = o.dict {
+        let zlib = ctype == CompressionType::Zlib;
+        let codec: Box<Codec + Send> = match s.dir {
+            Direction::Forward => Box::new(DictEncoder::new(compr, dict, zlib)?),
+            Direction::Reverse => Box::new(DictDecoder::new(dict, zlib)?),
+        };
+        return Ok(Compressor::Codec(Transform::new(r, codec, s.bufsize)));
+    }
+    Ok(match (s.dir, ctype) {
         (Direction::Forward, CompressionType::Deflate) => {
-            Box::new(BufReader::new(DeflateEncoder::new(r, compr)))
+            Compressor::DeflateEncoder(DeflateEncoder::new(r, compr))
         }
-        (Direction::Forward, CompressionType::Gzip) => {
-            Box::new(BufReader::new(GzEncoder::new(r, compr)))
+        (Direction::Forward, CompressionType::Gzip) if o.threads > 1 => {
+            Compressor::ParallelGzipEncoder(ParallelGzipEncoder::new(r, o))
         }
+        (Direction::Forward, CompressionType::Gzip) => Compressor::GzipEncoder(GzipHeaderReader {
+            inner: o.gzip_builder().buf_read(r, compr),
+            header: o.gzip_header().len(),
+        }),
         (Direction::Forward, CompressionType::Zlib) => {
-            Box::new(BufReader::new(ZlibEncoder::new(r, compr)))
+            Compressor::ZlibEncoder(ZlibEncoder::new(r, compr))
         }
         (Direction::Reverse, CompressionType::Deflate) => {
-            Box::new(BufReader::new(DeflateDecoder::new(r)))
+            Compressor::DeflateDecoder(DeflateDecoder::new(r))
+        }
+        (Direction::Reverse, CompressionType::Gzip) => {
+            Compressor::GzipDecoder(MultiGzipDecoder::new(r, s.strict))
+        }
+        (Direction::Reverse, CompressionType::Zlib) => Compressor::ZlibDecoder(ZlibDecoder::new(r)),
+        // The other formats are handled elsewhere, and may not be built at all.
+        #[allow(unreachable_patterns)]
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(feature = "bzip2")]
+fn bzip2_compressor<R: io::BufRead>(r: R, s: &CodecSettings, o: &Options) -> Compressor<R> {
+    match s.dir {
+        Direction::Forward => Compressor::Bzip2Encoder(bzip2::bufread::BzEncoder::new(
+            r,
+            bzip2::Compression::new(o.level),
+        )),
+        Direction::Reverse => Compressor::Bzip2Decoder(bzip2::bufread::MultiBzDecoder::new(r)),
+    }
+}
+
+#[cfg(feature = "xz")]
+// u64::MAX is newer than our minimum supported version.
+#[allow(clippy::legacy_numeric_constants)]
+fn xz_compressor<R: io::BufRead>(
+    r: R,
+    s: &CodecSettings,
+    o: &Options,
+    ctype: CompressionType,
+) -> Result<Compressor<R>, Error> {
+    use xz2::bufread::{XzDecoder, XzEncoder};
+    use xz2::stream::{Check, LzmaOptions, MtStreamBuilder, Stream, CONCATENATED};
+
+    let stream = match (s.dir, ctype) {
+        (Direction::Forward, CompressionType::Xz) if o.threads > 1 => MtStreamBuilder::new()
+            .threads(o.threads as u32)
+            .preset(o.level)
+            .check(Check::Crc64)
+            .encoder(),
+        (Direction::Forward, CompressionType::Xz) => {
+            Stream::new_easy_encoder(o.level, Check::Crc64)
+        }
+        (Direction::Forward, _) => {
+            LzmaOptions::new_preset(o.level).and_then(|opts| Stream::new_lzma_encoder(&opts))
+        }
+        (Direction::Reverse, CompressionType::Xz) => {
+            Stream::new_stream_decoder(u64::max_value(), CONCATENATED)
+        }
+        (Direction::Reverse, _) => Stream::new_lzma_decoder(u64::max_value()),
+    };
+    let stream = stream.map_err(|e| Error::IO(e.into()))?;
+    Ok(match s.dir {
+        Direction::Forward => Compressor::XzEncoder(XzEncoder::new_stream(r, stream)),
+        Direction::Reverse => Compressor::XzDecoder(XzDecoder::new_stream(r, stream)),
+    })
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compressor<R: io::BufRead>(
+    r: R,
+    s: &CodecSettings,
+    o: &Options,
+) -> Result<Compressor<R>, Error> {
+    use zstd::stream::read::{Decoder, Encoder};
+
+    let dict = o.dict.as_ref().map_or(&[][..], |d| &d[..]);
+    Ok(match s.dir {
+        Direction::Forward => {
+            let mut enc = Encoder::with_dictionary(r, o.level as i32, dict).map_err(Error::IO)?;
+            if o.threads > 1 {
+                enc.multithread(o.threads as u32).map_err(Error::IO)?;
+            }
+            Compressor::ZstdEncoder(enc)
+        }
+        Direction::Reverse => {
+            Compressor::ZstdDecoder(Decoder::with_dictionary(r, dict).map_err(Error::IO)?)
+        }
+    })
+}
+
+/// The default base-2 logarithm of the brotli window size, as brotli(1) uses.
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW: u32 = 22;
+
+/// The default size of each lz4 block, as lz4(1) uses.
+#[cfg(feature = "lz4")]
+const LZ4_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+#[cfg(feature = "brotli")]
+fn brotli_compressor<R: io::BufRead>(r: R, s: &CodecSettings, o: &Options) -> Compressor<R> {
+    let codec: Box<Codec + Send> = match s.dir {
+        Direction::Forward => Box::new(BrotliEncoder::new(o.level, o.window)),
+        Direction::Reverse => Box::new(BrotliDecoder::new(s.strict)),
+    };
+    Compressor::Codec(Transform::new(r, codec, s.bufsize))
+}
+
+/// A brotli encoder.
+#[cfg(feature = "brotli")]
+struct BrotliEncoder {
+    state: brotli::enc::encode::BrotliEncoderStateStruct<brotli::enc::StandardAlloc>,
+}
+
+#[cfg(feature = "brotli")]
+impl BrotliEncoder {
+    fn new(quality: u32, window: u32) -> Self {
+        use brotli::enc::encode::{
+            BrotliEncoderCreateInstance, BrotliEncoderParameter, BrotliEncoderSetParameter,
+        };
+
+        let mut state = BrotliEncoderCreateInstance(brotli::enc::StandardAlloc::default());
+        BrotliEncoderSetParameter(
+            &mut state,
+            BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
+            quality,
+        );
+        BrotliEncoderSetParameter(
+            &mut state,
+            BrotliEncoderParameter::BROTLI_PARAM_LGWIN,
+            window,
+        );
+        BrotliEncoder { state }
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl Drop for BrotliEncoder {
+    fn drop(&mut self) {
+        brotli::enc::encode::BrotliEncoderDestroyInstance(&mut self.state);
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl Codec for BrotliEncoder {
+    // io::Error::other is newer than our minimum supported version.
+    #[allow(clippy::io_other_error)]
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        use brotli::enc::encode::{
+            BrotliEncoderCompressStream, BrotliEncoderIsFinished, BrotliEncoderOperation,
+        };
+
+        let op = match f {
+            FlushState::Finish => BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+            FlushState::None => BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+        };
+        let (mut avail_in, mut consumed) = (inp.len(), 0);
+        let (mut avail_out, mut written) = (out.len(), 0);
+        // Keep going until the output is full or no more progress can be made, since writing
+        // nothing at the end of the input ends the stream.
+        while avail_out > 0 && BrotliEncoderIsFinished(&self.state) == 0 {
+            let prev = (consumed, written);
+            let ok = BrotliEncoderCompressStream(
+                &mut self.state,
+                op,
+                &mut avail_in,
+                inp,
+                &mut consumed,
+                &mut avail_out,
+                out,
+                &mut written,
+                &mut None,
+                &mut |_, _, _, _| (),
+            );
+            if ok == 0 {
+                return Err(Error::IO(io::Error::new(
+                    io::ErrorKind::Other,
+                    tr!("brotli compression failed"),
+                )));
+            }
+            if (consumed, written) == prev {
+                break;
+            }
         }
-        (Direction::Reverse, CompressionType::Gzip) => Box::new(BufReader::new(GzDecoder::new(r))),
-        (Direction::Reverse, CompressionType::Zlib) => {
-            Box::new(BufReader::new(ZlibDecoder::new(r)))
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+#[cfg(feature = "brotli")]
+type BrotliDecoderState = brotli::BrotliState<
+    brotli::enc::StandardAlloc,
+    brotli::enc::StandardAlloc,
+    brotli::enc::StandardAlloc,
+>;
+
+/// A brotli decoder.
+///
+/// A brotli stream has no magic number, so unlike the other formats, data after the end of the
+/// stream can't be another stream.
+#[cfg(feature = "brotli")]
+struct BrotliDecoder {
+    state: BrotliDecoderState,
+    done: bool,
+    strict: bool,
+}
+
+#[cfg(feature = "brotli")]
+impl BrotliDecoder {
+    fn new(strict: bool) -> Self {
+        let alloc = brotli::enc::StandardAlloc::default;
+        BrotliDecoder {
+            state: BrotliDecoderState::new_strict(alloc(), alloc(), alloc()),
+            done: false,
+            strict,
+        }
+    }
+
+    /// Handle the data in `inp` from `start` on, which follows the end of the stream, returning
+    /// the amount of input consumed.
+    fn extra_data(&self, inp: &[u8], start: usize) -> Result<usize, Error> {
+        if start < inp.len() && self.strict {
+            return Err(Error::ExtraData.at(start));
         }
+        Ok(inp.len())
     }
 }
 
+#[cfg(feature = "brotli")]
+impl Codec for BrotliDecoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        use brotli::{BrotliDecompressStream, BrotliResult};
+
+        if self.done {
+            return Ok(Status::Ok(self.extra_data(inp, 0)?, 0));
+        }
+        let (mut avail_in, mut consumed) = (inp.len(), 0);
+        let (mut avail_out, mut written) = (out.len(), 0);
+        let mut total = 0;
+        let res = BrotliDecompressStream(
+            &mut avail_in,
+            &mut consumed,
+            inp,
+            &mut avail_out,
+            &mut written,
+            out,
+            &mut total,
+            &mut self.state,
+        );
+        match res {
+            BrotliResult::ResultFailure => Err(Error::InvalidSequence(
+                "brotli".to_string(),
+                inp[consumed..].to_vec(),
+            )
+            .at(consumed)),
+            BrotliResult::ResultSuccess => {
+                self.done = true;
+                // Return any output first, so that it isn't lost if the rest is an error.
+                if written == 0 {
+                    consumed = self.extra_data(inp, consumed)?;
+                }
+                Ok(Status::Ok(consumed, written))
+            }
+            BrotliResult::NeedsMoreInput if f == FlushState::Finish && written == 0 => {
+                Err(Error::TruncatedData.at(consumed))
+            }
+            BrotliResult::NeedsMoreInput | BrotliResult::NeedsMoreOutput => {
+                Ok(Status::Ok(consumed, written))
+            }
+        }
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+/// The magic number at the start of an lz4 frame.
+#[cfg(feature = "lz4")]
+const LZ4_MAGIC: &[u8] = b"\x04\x22\x4d\x18";
+
+/// Returns whether `data`, which follows a frame, starts with another frame, or `None` if there
+/// isn't enough data to tell.
+///
+/// Like lz4(1), this accepts skippable frames, whose magic numbers end in `2a 4d 18`.
+#[cfg(feature = "lz4")]
+fn lz4_frame_follows(data: &[u8], f: FlushState) -> Option<bool> {
+    let n = cmp::min(data.len(), LZ4_MAGIC.len());
+    let frame =
+        data[..n] == LZ4_MAGIC[..n] || (data[0] & 0xf0 == 0x50 && data[1..n] == LZ4_MAGIC[1..n]);
+    match (frame, n == LZ4_MAGIC.len(), f) {
+        (false, _, _) => Some(false),
+        (true, true, _) => Some(true),
+        (true, false, FlushState::Finish) => Some(false),
+        (true, false, FlushState::None) => None,
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_error(e: io::Error) -> Error {
+    Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_compressor<R: io::BufRead>(
+    r: R,
+    s: &CodecSettings,
+    o: &Options,
+) -> Result<Compressor<R>, Error> {
+    let codec: Box<Codec + Send> = match s.dir {
+        Direction::Forward => Box::new(Lz4Encoder::new(o)?),
+        Direction::Reverse => Box::new(Lz4Decoder::new(s.strict)?),
+    };
+    Ok(Compressor::Codec(Transform::new(r, codec, s.bufsize)))
+}
+
+/// The most input compressed at once by `Lz4Encoder`, which bounds the size of its buffer.
+#[cfg(feature = "lz4")]
+const LZ4_CHUNK_SIZE: usize = 64 * 1024;
+
+/// An lz4 frame encoder, writing frames as lz4(1) does, with independent blocks and a checksum of
+/// the content.
+#[cfg(feature = "lz4")]
+struct Lz4Encoder {
+    ctx: lz4::liblz4::LZ4FCompressionContext,
+    // The output of the last call into the library, of which `buf[pos..len]` is not yet written.
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    finished: bool,
+}
+
+#[cfg(feature = "lz4")]
+impl Lz4Encoder {
+    fn new(o: &Options) -> Result<Self, Error> {
+        use lz4::liblz4::*;
+        use std::ptr;
+
+        let block_size_id = match o.block_size {
+            0x1_0000 => BlockSize::Max64KB,
+            0x4_0000 => BlockSize::Max256KB,
+            0x10_0000 => BlockSize::Max1MB,
+            0x40_0000 => BlockSize::Max4MB,
+            n => return Err(Error::InvalidArgument("blocksize".into(), n.to_string())),
+        };
+        let prefs = LZ4FPreferences {
+            frame_info: LZ4FFrameInfo {
+                block_size_id,
+                block_mode: BlockMode::Independent,
+                content_checksum_flag: ContentChecksum::ChecksumEnabled,
+                frame_type: FrameType::Frame,
+                content_size: 0,
+                dict_id: 0,
+                block_checksum_flag: BlockChecksum::NoBlockChecksum,
+            },
+            compression_level: o.level,
+            auto_flush: 0,
+            favor_dec_speed: 0,
+            reserved: [0; 3],
+        };
+        let mut ctx = LZ4FCompressionContext(ptr::null_mut());
+        check_error(unsafe { LZ4F_createCompressionContext(&mut ctx, LZ4F_VERSION) })
+            .map_err(Error::IO)?;
+        // This is large enough for the header, the end of the frame, and any one chunk along with
+        // whatever the library has buffered.
+        let size = unsafe { LZ4F_compressBound(LZ4_CHUNK_SIZE, &prefs) };
+        let mut enc = Lz4Encoder {
+            ctx,
+            buf: vec![0; size],
+            pos: 0,
+            len: 0,
+            finished: false,
+        };
+        enc.len = check_error(unsafe {
+            LZ4F_compressBegin(enc.ctx, enc.buf.as_mut_ptr(), enc.buf.len(), &prefs)
+        })
+        .map_err(Error::IO)?;
+        Ok(enc)
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Drop for Lz4Encoder {
+    fn drop(&mut self) {
+        unsafe { lz4::liblz4::LZ4F_freeCompressionContext(self.ctx) };
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4Encoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        use lz4::liblz4::*;
+        use std::ptr;
+
+        let (mut consumed, mut written) = (0, 0);
+        loop {
+            let n = cmp::min(out.len() - written, self.len - self.pos);
+            out[written..written + n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            written += n;
+            self.pos += n;
+            if self.pos < self.len {
+                break;
+            }
+            let code = if consumed < inp.len() {
+                let n = cmp::min(inp.len() - consumed, LZ4_CHUNK_SIZE);
+                let src = inp[consumed..].as_ptr();
+                consumed += n;
+                unsafe {
+                    LZ4F_compressUpdate(
+                        self.ctx,
+                        self.buf.as_mut_ptr(),
+                        self.buf.len(),
+                        src,
+                        n,
+                        ptr::null(),
+                    )
+                }
+            } else if f == FlushState::Finish && !self.finished {
+                self.finished = true;
+                unsafe {
+                    LZ4F_compressEnd(self.ctx, self.buf.as_mut_ptr(), self.buf.len(), ptr::null())
+                }
+            } else {
+                break;
+            };
+            self.len = check_error(code).map_err(Error::IO)?;
+            self.pos = 0;
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+/// An lz4 frame decoder, which decodes each of several concatenated frames in turn.
+#[cfg(feature = "lz4")]
+struct Lz4Decoder {
+    ctx: lz4::liblz4::LZ4FDecompressionContext,
+    // Whether the input so far ends within a frame, or has no frames at all.
+    in_frame: bool,
+    strict: bool,
+}
+
+#[cfg(feature = "lz4")]
+impl Lz4Decoder {
+    fn new(strict: bool) -> Result<Self, Error> {
+        use lz4::liblz4::*;
+        use std::ptr;
+
+        let mut ctx = LZ4FDecompressionContext(ptr::null_mut());
+        check_error(unsafe { LZ4F_createDecompressionContext(&mut ctx, LZ4F_VERSION) })
+            .map_err(Error::IO)?;
+        Ok(Lz4Decoder {
+            ctx,
+            in_frame: true,
+            strict,
+        })
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Drop for Lz4Decoder {
+    fn drop(&mut self) {
+        unsafe { lz4::liblz4::LZ4F_freeDecompressionContext(self.ctx) };
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4Decoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        use lz4::liblz4::*;
+        use std::ptr;
+
+        let (mut consumed, mut written) = (0, 0);
+        while consumed < inp.len() || self.in_frame {
+            if !self.in_frame {
+                match lz4_frame_follows(&inp[consumed..], f) {
+                    Some(true) => self.in_frame = true,
+                    // Return any output first, so that it isn't lost if the rest is an error.
+                    Some(false) if written > 0 => break,
+                    Some(false) if self.strict => return Err(Error::ExtraData.at(consumed)),
+                    Some(false) => {
+                        consumed = inp.len();
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            let mut src = inp.len() - consumed;
+            let mut dst = out.len() - written;
+            let hint = check_error(unsafe {
+                LZ4F_decompress(
+                    self.ctx,
+                    out[written..].as_mut_ptr(),
+                    &mut dst,
+                    inp[consumed..].as_ptr(),
+                    &mut src,
+                    ptr::null(),
+                )
+            })
+            .map_err(lz4_error)?;
+            consumed += src;
+            written += dst;
+            if hint == 0 {
+                self.in_frame = false;
+            } else if src == 0 && dst == 0 {
+                break;
+            }
+        }
+        if f == FlushState::Finish && self.in_frame && written == 0 {
+            return Err(Error::TruncatedData.at(consumed));
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+/// The options for the compression level, which every format takes.
+fn level_schema(ctype: CompressionType) -> OptionSchema {
+    let levels = ctype.levels();
+    OptionSchema::new()
+        .option(
+            ctype.level_name(),
+            OptionKind::Integer {
+                min: levels.min as u64,
+                max: Some(levels.max as u64),
+            },
+            tr!(
+                "compression level from {} to {} (default {})",
+                levels.min,
+                levels.max,
+                levels.default
+            ),
+        )
+        .direction(Direction::Forward)
+        .flag("fast", tr!("use the fastest compression level"))
+        .direction(Direction::Forward)
+        .flag("best", tr!("use the best compression level"))
+        .direction(Direction::Forward)
+        .exclusive(&[ctype.level_name(), "fast", "best"])
+}
+
+fn dict_schema(ctype: CompressionType) -> OptionSchema {
+    level_schema(ctype).option(
+        "dict",
+        OptionKind::Hex,
+        tr!("preset dictionary, in hexadecimal"),
+    )
+}
+
+/// The option for the number of threads, for formats whose libraries compress on several threads
+/// themselves.
+#[cfg(any(feature = "xz", feature = "zstd"))]
+fn threads_schema(schema: OptionSchema) -> OptionSchema {
+    schema
+        .option(
+            "threads",
+            OptionKind::Integer { min: 1, max: None },
+            tr!("compress on this many threads"),
+        )
+        .direction(Direction::Forward)
+}
+
+fn gzip_schema() -> OptionSchema {
+    level_schema(CompressionType::Gzip)
+        .option("name", OptionKind::Text, tr!("file name for the header"))
+        .direction(Direction::Forward)
+        .option(
+            "mtime",
+            OptionKind::Integer {
+                min: 0,
+                max: Some(0xffff_ffff),
+            },
+            tr!("modification time for the header, in seconds since the epoch"),
+        )
+        .direction(Direction::Forward)
+        .option("comment", OptionKind::Text, tr!("comment for the header"))
+        .direction(Direction::Forward)
+        .option(
+            "threads",
+            OptionKind::Integer { min: 1, max: None },
+            tr!("compress independent blocks on this many threads"),
+        )
+        .direction(Direction::Forward)
+        .option(
+            "block",
+            OptionKind::Integer { min: 1, max: None },
+            tr!("size of each block compressed on its own thread (default 131072)"),
+        )
+        .direction(Direction::Forward)
+}
+
 macro_rules! compress_defn {
-    ($ty: ident, $name: expr, $algo: expr) => {
+    ($ty: ident, $name: expr, $algo: expr, $schema: expr) => {
         #[derive(Default)]
         pub struct $ty {}
 
@@ -63,11 +1454,19 @@ macro_rules! compress_defn {
                 r: Box<io::BufRead>,
                 s: CodecSettings,
             ) -> Result<Box<io::BufRead>, Error> {
-                Ok(generic_factory(r, s, $algo))
+                Ok(Box::new(generic_factory(r, s, $algo)?))
             }
 
-            fn options(&self) -> BTreeMap<String, String> {
-                BTreeMap::new()
+            fn factory_send(
+                &self,
+                r: Box<io::BufRead + Send>,
+                s: CodecSettings,
+            ) -> Result<Box<io::BufRead + Send>, Error> {
+                Ok(Box::new(generic_factory(r, s, $algo)?))
+            }
+
+            fn schema(&self) -> OptionSchema {
+                $schema
             }
 
             fn can_reverse(&self) -> bool {
@@ -81,14 +1480,314 @@ macro_rules! compress_defn {
     };
 }
 
-compress_defn!(DeflateTransformFactory, "deflate", CompressionType::Deflate);
-compress_defn!(ZlibTransformFactory, "zlib", CompressionType::Zlib);
-compress_defn!(GzipTransformFactory, "gzip", CompressionType::Gzip);
+compress_defn!(
+    DeflateTransformFactory,
+    "deflate",
+    CompressionType::Deflate,
+    dict_schema(CompressionType::Deflate)
+);
+compress_defn!(
+    ZlibTransformFactory,
+    "zlib",
+    CompressionType::Zlib,
+    dict_schema(CompressionType::Zlib)
+);
+compress_defn!(
+    GzipTransformFactory,
+    "gzip",
+    CompressionType::Gzip,
+    gzip_schema()
+);
+#[cfg(feature = "bzip2")]
+compress_defn!(
+    Bzip2TransformFactory,
+    "bzip2",
+    CompressionType::Bzip2,
+    level_schema(CompressionType::Bzip2)
+);
+#[cfg(feature = "xz")]
+compress_defn!(
+    XzTransformFactory,
+    "xz",
+    CompressionType::Xz,
+    threads_schema(level_schema(CompressionType::Xz))
+);
+#[cfg(feature = "xz")]
+compress_defn!(
+    LzmaTransformFactory,
+    "lzma",
+    CompressionType::Lzma,
+    level_schema(CompressionType::Lzma)
+);
+#[cfg(feature = "zstd")]
+compress_defn!(
+    ZstdTransformFactory,
+    "zstd",
+    CompressionType::Zstd,
+    threads_schema(dict_schema(CompressionType::Zstd))
+);
+#[cfg(feature = "brotli")]
+compress_defn!(
+    BrotliTransformFactory,
+    "brotli",
+    CompressionType::Brotli,
+    level_schema(CompressionType::Brotli)
+        .option(
+            "window",
+            OptionKind::Integer {
+                min: 10,
+                max: Some(24),
+            },
+            tr!("base-2 logarithm of the window size from 10 to 24 (default 22)"),
+        )
+        .direction(Direction::Forward)
+);
+#[cfg(feature = "lz4")]
+compress_defn!(
+    Lz4TransformFactory,
+    "lz4",
+    CompressionType::Lz4,
+    level_schema(CompressionType::Lz4)
+        .option(
+            "blocksize",
+            OptionKind::Integer {
+                min: 0x1_0000,
+                max: Some(0x40_0000),
+            },
+            tr!("size of each block: 65536, 262144, 1048576, or 4194304 (default 4194304)"),
+        )
+        .direction(Direction::Forward)
+);
+
+/// A format `decompress` recognizes.
+#[derive(Clone, Copy)]
+enum Detected {
+    Compressed(CompressionType),
+    // The .Z format of compress(1), which the lzw codec implements.
+    Lzw,
+}
+
+/// The formats `decompress` recognizes, in the order it tries them.
+const DETECTED: &[(&str, Detected)] = &[
+    ("gzip", Detected::Compressed(CompressionType::Gzip)),
+    ("zlib", Detected::Compressed(CompressionType::Zlib)),
+    #[cfg(feature = "bzip2")]
+    ("bzip2", Detected::Compressed(CompressionType::Bzip2)),
+    #[cfg(feature = "xz")]
+    ("xz", Detected::Compressed(CompressionType::Xz)),
+    #[cfg(feature = "zstd")]
+    ("zstd", Detected::Compressed(CompressionType::Zstd)),
+    #[cfg(feature = "lz4")]
+    ("lz4", Detected::Compressed(CompressionType::Lz4)),
+    ("lzw", Detected::Lzw),
+];
+
+/// The number of bytes needed to recognize any of the formats in `DETECTED`.
+const MAGIC_LEN: usize = 6;
+
+/// Returns whether data starting with `header` looks like the given format.
+fn has_magic(format: Detected, header: &[u8]) -> bool {
+    let ctype = match format {
+        Detected::Compressed(ctype) => ctype,
+        Detected::Lzw => return header.starts_with(b"\x1f\x9d"),
+    };
+    match ctype {
+        CompressionType::Gzip => header.starts_with(b"\x1f\x8b"),
+        // Deflate with a window of at most 32 KiB, in a header which is a multiple of 31.
+        CompressionType::Zlib => {
+            header.len() >= 2
+                && header[0] & 0x8f == 0x08
+                && u16::from_be_bytes([header[0], header[1]]) % 31 == 0
+        }
+        #[cfg(feature = "bzip2")]
+        CompressionType::Bzip2 => {
+            header.len() >= 4 && header.starts_with(b"BZh") && (b'1'..=b'9').contains(&header[3])
+        }
+        #[cfg(feature = "xz")]
+        CompressionType::Xz => header.starts_with(b"\xfd7zXZ\0"),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => header.starts_with(b"\x28\xb5\x2f\xfd"),
+        #[cfg(feature = "lz4")]
+        CompressionType::Lz4 => header.starts_with(LZ4_MAGIC),
+        _ => false,
+    }
+}
+
+/// The input to the decoder `AutoDecoder` chooses: the bytes it read to choose it, then the rest.
+type Sniffed<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// A reader which decompresses data in whichever format its first few bytes indicate.
+///
+/// Nothing is read until the first read, so that the reader can be created before any input is
+/// available.
+struct AutoDecoder<R: io::BufRead> {
+    r: Option<R>,
+    header: Vec<u8>,
+    s: CodecSettings,
+    inner: Option<BufReader<Compressor<Sniffed<R>>>>,
+}
+
+impl<R: io::BufRead> AutoDecoder<R> {
+    fn new(r: R, s: CodecSettings) -> Self {
+        AutoDecoder {
+            r: Some(r),
+            header: Vec::with_capacity(MAGIC_LEN),
+            s,
+            inner: None,
+        }
+    }
+
+    /// Read enough of the input to recognize its format and set up the decoder for it.
+    fn start(&mut self) -> io::Result<()> {
+        let mut r = self.r.take().unwrap();
+        while self.header.len() < MAGIC_LEN {
+            let n = match r.fill_buf() {
+                Ok(data) => {
+                    let n = cmp::min(data.len(), MAGIC_LEN - self.header.len());
+                    self.header.extend_from_slice(&data[..n]);
+                    n
+                }
+                Err(e) => {
+                    self.r = Some(r);
+                    return Err(e);
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            r.consume(n);
+        }
+        let format = match DETECTED.iter().find(|&&(_, t)| has_magic(t, &self.header)) {
+            Some(&(_, t)) => t,
+            None => {
+                let names: Vec<_> = DETECTED.iter().map(|&(name, _)| name).collect();
+                let e = Error::InvalidSequence(
+                    tr!("decompress (tried {})", names.join(", ")),
+                    self.header.clone(),
+                );
+                self.r = Some(r);
+                let pos = Position {
+                    offset: 0,
+                    line: None,
+                };
+                return Err(Error::AtOffset(pos, Box::new(e)).into());
+            }
+        };
+        let header = std::mem::take(&mut self.header);
+        let r = io::Read::chain(io::Cursor::new(header), r);
+        self.inner = Some(match format {
+            Detected::Compressed(ctype) => generic_factory(r, self.s.clone(), ctype)?,
+            Detected::Lzw => {
+                let codec = lzw::TransformFactory::new().codec(self.s.clone())?.unwrap();
+                BufReader::new(Compressor::Codec(Transform::new(r, codec, self.s.bufsize)))
+            }
+        });
+        Ok(())
+    }
+}
+
+impl<R: io::BufRead> io::Read for AutoDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.inner.is_none() {
+            self.start()?;
+        }
+        match self.inner {
+            Some(ref mut d) => d.read(buf),
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<R: io::BufRead> io::BufRead for AutoDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.inner.is_none() {
+            self.start()?;
+        }
+        match self.inner {
+            Some(ref mut d) => d.fill_buf(),
+            None => unreachable!(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(ref mut d) = self.inner {
+            d.consume(amt)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct DecompressTransformFactory {}
+
+impl DecompressTransformFactory {
+    pub fn new() -> Self {
+        DecompressTransformFactory {}
+    }
+}
+
+impl CodecTransform for DecompressTransformFactory {
+    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+        match s.dir {
+            Direction::Forward => Err(Error::ReverseOnly("decompress".to_string())),
+            Direction::Reverse => Ok(Box::new(AutoDecoder::new(r, s))),
+        }
+    }
+
+    fn factory_send(
+        &self,
+        r: Box<io::BufRead + Send>,
+        s: CodecSettings,
+    ) -> Result<Box<io::BufRead + Send>, Error> {
+        match s.dir {
+            Direction::Forward => Err(Error::ReverseOnly("decompress".to_string())),
+            Direction::Reverse => Ok(Box::new(AutoDecoder::new(r, s))),
+        }
+    }
+
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+    }
+
+    fn can_reverse(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "decompress"
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use chain::Chain;
+    use codec::registry::CodecRegistry;
     use codec::tests;
+    use codec::Error;
+    use std::collections::BTreeMap;
     use std::convert::TryInto;
+    use std::io::Write;
+
+    fn args(v: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        v.iter()
+            .map(|&(k, v)| (k.to_string(), v.map(|s| s.to_string())))
+            .collect()
+    }
+
+    fn encode(
+        name: &str,
+        a: BTreeMap<String, Option<String>>,
+        inp: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        CodecRegistry::new().encode(name, a, inp, true)
+    }
+
+    fn decode(
+        name: &str,
+        a: BTreeMap<String, Option<String>>,
+        inp: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        CodecRegistry::new().decode(name, a, inp, true)
+    }
 
     fn matches_zlib_pattern(encoded: &[u8]) -> bool {
         if encoded.len() < 2 {
@@ -106,7 +1805,7 @@ mod tests {
         if (u16::from_be_bytes(encoded[0..2].try_into().unwrap()) % 31) != 0 {
             return false;
         }
-        return true;
+        true
     }
 
     fn matches_gzip_pattern(encoded: &[u8]) -> bool {
@@ -133,7 +1832,7 @@ mod tests {
         if u32::from_be_bytes(encoded[4..8].try_into().unwrap()) != 0 {
             return false;
         }
-        return true;
+        true
     }
 
     #[test]
@@ -151,6 +1850,332 @@ mod tests {
         tests::matches_pattern("gzip", matches_gzip_pattern);
     }
 
+    #[test]
+    fn round_trip_parallel_gzip() {
+        tests::round_trip("gzip(threads=3,block=100)");
+        tests::round_trip("gzip(threads=2,block=4096)");
+        tests::matches_pattern("gzip(threads=3,block=100)", matches_gzip_pattern);
+    }
+
+    #[test]
+    fn round_trip_with_options() {
+        tests::round_trip("deflate(level=0)");
+        tests::round_trip("deflate(best)");
+        tests::round_trip("zlib(fast)");
+        tests::round_trip("gzip(level=1,name=data.bin,comment=test,mtime=1600000000)");
+        tests::round_trip("deflate(dict=68656c6c6f2c20776f726c64)");
+        tests::round_trip("zlib(dict=00ff00ff,level=9)");
+        tests::matches_pattern("zlib(dict=00)", |b| b.len() > 6 && &b[..2] == b"\x78\xbb");
+    }
+
+    #[test]
+    fn writes_gzip_header() {
+        let a = args(&[
+            ("name", Some("a.txt")),
+            ("comment", Some("hi")),
+            ("mtime", Some("1234567890")),
+            ("best", None),
+        ]);
+        let header = b"\x1f\x8b\x08\x18\xd2\x02\x96\x49\x02\xffa.txt\x00hi\x00";
+        for threads in &["1", "2"] {
+            let mut a = a.clone();
+            a.insert("threads".to_string(), Some(threads.to_string()));
+            let out = encode("gzip", a, b"abc").unwrap();
+            assert_eq!(&out[..header.len()], &header[..]);
+            assert_eq!(decode("gzip", BTreeMap::new(), &out).unwrap(), b"abc");
+        }
+    }
+
+    #[test]
+    fn decodes_concatenated_gzip_members() {
+        let mut inp = encode("gzip", BTreeMap::new(), b"abc").unwrap();
+        inp.extend(encode("gzip", args(&[("name", Some("x"))]), b"defg").unwrap());
+        inp.extend(encode("gzip", args(&[("threads", Some("2"))]), b"").unwrap());
+        let reg = CodecRegistry::new();
+        let c = Chain::new(&reg, "-gzip", 512, true);
+        assert_eq!(c.transform(inp.clone()).unwrap(), b"abcdefg");
+
+        let mut out = Vec::new();
+        {
+            let mut w = c.build_writer(&mut out).unwrap();
+            for b in inp.chunks(1) {
+                w.write_all(b).unwrap();
+            }
+        }
+        assert_eq!(out, b"abcdefg");
+
+        let mut extra = inp.clone();
+        extra.extend_from_slice(b"\0\0");
+        let e = c.transform(extra.clone()).unwrap_err();
+        match tests::codec_error(&e) {
+            Some(Error::ExtraData) => (),
+            _ => panic!("wrong error: {:?}", e),
+        }
+        assert_eq!(tests::error_position(&e).unwrap().offset, inp.len() as u64);
+        let c = Chain::new(&reg, "-gzip", 512, false);
+        assert_eq!(c.transform(extra).unwrap(), b"abcdefg");
+
+        // Trailing data which starts like a member but has no valid header is still extra data.
+        for trailer in &[&b"\x1f\x8bjunkjunkjunk"[..], b"\x1f\x8b", b"\x1f"] {
+            let mut extra = inp.clone();
+            extra.extend_from_slice(trailer);
+            for &bufsize in &[8, 512] {
+                let c = Chain::new(&reg, "-gzip", bufsize, true);
+                let e = c.transform(extra.clone()).unwrap_err();
+                match tests::codec_error(&e) {
+                    Some(Error::ExtraData) => (),
+                    _ => panic!("wrong error for {:?}: {:?}", trailer, e),
+                }
+                assert_eq!(tests::error_position(&e).unwrap().offset, inp.len() as u64);
+                let c = Chain::new(&reg, "-gzip", bufsize, false);
+                assert_eq!(c.transform(extra.clone()).unwrap(), b"abcdefg");
+            }
+            let mut out = Vec::new();
+            {
+                let c = Chain::new(&reg, "-gzip", 512, false);
+                let mut w = c.build_writer(&mut out).unwrap();
+                for b in extra.chunks(1) {
+                    w.write_all(b).unwrap();
+                }
+            }
+            assert_eq!(out, b"abcdefg");
+        }
+    }
+
+    #[test]
+    fn limits_decompressed_output() {
+        let inp = vec![0u8; 1 << 20];
+        let reg = CodecRegistry::new();
+        for name in &["deflate", "gzip", "zlib"] {
+            let enc = encode(name, BTreeMap::new(), &inp).unwrap();
+            let chain = format!("-{}", name);
+            let c = Chain::new(&reg, &chain, 512, true).with_max_output(inp.len() as u64);
+            assert_eq!(c.transform(enc.clone()).unwrap(), inp);
+            let c = Chain::new(&reg, &chain, 512, true).with_max_output(1000);
+            let e = c.transform(enc).unwrap_err();
+            match tests::codec_error(&e) {
+                Some(Error::OutputLimitExceeded(1000)) => (),
+                _ => panic!("wrong error for {}: {:?}", name, e),
+            }
+        }
+    }
+
+    #[test]
+    fn decompress_detects_format() {
+        let reg = CodecRegistry::new();
+        let inp: Vec<u8> = (0..5000u32).map(|x| (x % 13) as u8).collect();
+        for &(name, _) in super::DETECTED {
+            let enc = encode(name, BTreeMap::new(), &inp).unwrap();
+            let c = Chain::new(&reg, "-decompress", 512, true);
+            assert_eq!(c.transform(enc.clone()).unwrap(), inp, "{}", name);
+
+            let mut out = Vec::new();
+            {
+                let mut w = c.build_writer(&mut out).unwrap();
+                for b in enc.chunks(1) {
+                    w.write_all(b).unwrap();
+                }
+            }
+            assert_eq!(out, inp, "{}", name);
+        }
+        let c = Chain::new(&reg, "lzw:-decompress", 512, true);
+        assert_eq!(c.transform(inp.clone()).unwrap(), inp);
+        tests::basic_configuration("decompress");
+        tests::invalid_data("decompress");
+
+        let enc = encode("deflate", BTreeMap::new(), &inp).unwrap();
+        for data in &[enc, b"\x1f".to_vec(), vec![]] {
+            let c = Chain::new(&reg, "-decompress", 512, true);
+            let e = c.transform(data.clone()).unwrap_err();
+            match tests::codec_error(&e) {
+                Some(Error::InvalidSequence(name, _)) => assert!(name.contains("gzip, zlib")),
+                _ => panic!("wrong error: {:?}", e),
+            }
+        }
+        let e = Chain::new(&reg, "decompress", 512, true)
+            .transform(inp)
+            .unwrap_err();
+        match tests::codec_error(&e) {
+            Some(Error::ReverseOnly(_)) => (),
+            _ => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn decodes_preset_dictionary() {
+        // Produced by zlib with the dictionary "hello, world".
+        let dict = args(&[("dict", Some("68656c6c6f2c20776f726c64"))]);
+        let zlib = b"\x78\xbb\x1d\x54\x04\x89\xcb\x40\x62\x2b\x2a\xa4\xe7\xe7\xa7\x24\x55\xa6\xc2\xf8\x00\x9c\xb3\x0a\x48";
+        let deflate = &zlib[6..zlib.len() - 4];
+        let expected = b"hello, world! goodbye, world!";
+        assert_eq!(decode("zlib", dict.clone(), zlib).unwrap(), &expected[..]);
+        assert_eq!(
+            decode("deflate", dict.clone(), deflate).unwrap(),
+            &expected[..]
+        );
+        // The encoder is primed the same way, so it finds the same matches.
+        let out = encode("zlib", dict.clone(), expected).unwrap();
+        assert_eq!(&out[..6], &zlib[..6]);
+
+        let other = args(&[("dict", Some("68656c6c6f"))]);
+        match decode("zlib", other, zlib) {
+            Err(Error::IO(ref e)) => match tests::codec_error(e) {
+                Some(Error::InvalidSequence(_, _)) => (),
+                _ => panic!("wrong error: {:?}", e),
+            },
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut corrupt = zlib.to_vec();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(decode("zlib", dict, &corrupt).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        for &(name, ref a) in &[
+            ("gzip", args(&[("level", Some("10"))])),
+            ("gzip", args(&[("mtime", Some("4294967296"))])),
+            ("zlib", args(&[("dict", Some("abc"))])),
+            ("deflate", args(&[("dict", Some("zz"))])),
+        ] {
+            match encode(name, a.clone(), b"abc") {
+                Err(Error::InvalidArgument(_, _)) => (),
+                r => panic!("unexpected result for {} {:?}: {:?}", name, a, r),
+            }
+        }
+        match encode("gzip", args(&[("fast", None), ("best", None)]), b"abc") {
+            Err(Error::IncompatibleParameters(_, _)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn round_trip_bzip2() {
+        tests::round_trip("bzip2");
+        tests::round_trip("bzip2(fast)");
+        tests::basic_configuration("bzip2");
+        tests::invalid_data("bzip2");
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn round_trip_xz() {
+        tests::round_trip("xz");
+        tests::round_trip("xz(level=0)");
+        tests::round_trip("xz(threads=2)");
+        tests::basic_configuration("xz");
+        tests::invalid_data("xz");
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn round_trip_lzma() {
+        tests::round_trip("lzma");
+        tests::basic_configuration("lzma");
+        tests::invalid_data("lzma");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn round_trip_zstd() {
+        tests::round_trip("zstd");
+        tests::round_trip("zstd(fast)");
+        tests::round_trip("zstd(threads=2)");
+        tests::round_trip("zstd(dict=00ff00ff)");
+        tests::basic_configuration("zstd");
+        tests::invalid_data("zstd");
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn round_trip_brotli() {
+        // The default quality of 11 is too slow for this many inputs in a debug build, but the
+        // tests below use it.
+        tests::round_trip("brotli(quality=9)");
+        tests::round_trip("brotli(quality=1,window=10)");
+        tests::basic_configuration("brotli");
+        tests::invalid_data("brotli");
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn rejects_invalid_brotli_data() {
+        let reg = CodecRegistry::new();
+        let c = Chain::new(&reg, "-brotli", 512, true);
+        let e = c.transform(b"\xff\xff\xff\xff".to_vec()).unwrap_err();
+        match tests::codec_error(&e) {
+            Some(Error::InvalidSequence(name, _)) => assert_eq!(name, "brotli"),
+            _ => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn round_trip_lz4() {
+        tests::round_trip("lz4");
+        tests::round_trip("lz4(best,blocksize=65536)");
+        tests::basic_configuration("lz4");
+        tests::invalid_data("lz4");
+    }
+
+    #[cfg(any(feature = "brotli", feature = "lz4"))]
+    #[test]
+    fn rejects_truncated_and_extra_data() {
+        let reg = CodecRegistry::new();
+        let inp: Vec<u8> = (0..5000u32).map(|x| (x % 13) as u8).collect();
+        for name in &["brotli", "lz4"] {
+            if reg.iter().all(|(k, _)| k != name) {
+                continue;
+            }
+            let enc = encode(name, BTreeMap::new(), &inp).unwrap();
+            let chain = format!("-{}", name);
+            let mut extra = enc.clone();
+            extra.extend_from_slice(b"abc");
+            let truncated = enc[..enc.len() - 1].to_vec();
+            for &(ref data, offset) in &[
+                (truncated, enc.len() - 1),
+                (vec![], 0),
+                (extra.clone(), enc.len()),
+            ] {
+                // The offset is the same however the input is split between calls.
+                for &bufsize in &[16, 512] {
+                    let c = Chain::new(&reg, &chain, bufsize, true);
+                    let e = c.transform(data.clone()).unwrap_err();
+                    match tests::codec_error(&e) {
+                        Some(Error::TruncatedData) if offset < enc.len() => (),
+                        Some(Error::ExtraData) if offset == enc.len() => (),
+                        _ => panic!("wrong error for {}: {:?}", name, e),
+                    }
+                    assert_eq!(
+                        tests::error_position(&e).unwrap().offset,
+                        offset as u64,
+                        "{} with buffer size {}",
+                        name,
+                        bufsize
+                    );
+                }
+            }
+            let c = Chain::new(&reg, &chain, 512, false);
+            assert_eq!(c.transform(extra).unwrap(), inp, "{}", name);
+        }
+    }
+
+    #[cfg(any(feature = "bzip2", feature = "xz", feature = "zstd", feature = "lz4"))]
+    #[test]
+    fn decodes_concatenated_streams() {
+        let reg = CodecRegistry::new();
+        for name in &["bzip2", "xz", "zstd", "lz4"] {
+            if reg.iter().all(|(k, _)| k != name) {
+                continue;
+            }
+            let mut inp = encode(name, BTreeMap::new(), b"abc").unwrap();
+            inp.extend(encode(name, BTreeMap::new(), b"defg").unwrap());
+            let c = Chain::new(&reg, format!("-{}", name), 512, true);
+            assert_eq!(c.transform(inp).unwrap(), b"abcdefg", "{}", name);
+        }
+    }
+
     #[test]
     fn round_trip_zlib() {
         tests::round_trip("zlib");
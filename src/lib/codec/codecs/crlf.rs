@@ -2,6 +2,7 @@
 #![allow(bare_trait_objects)]
 
 use codec::helpers::codecs::StatelessEncoder;
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -9,10 +10,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -24,17 +22,18 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
-            Direction::Forward => {
-                Ok(StatelessEncoder::new(Self::forward_transform, 3).into_bufread(r, s.bufsize))
-            }
-            Direction::Reverse => Ok(Decoder::new().into_bufread(r, s.bufsize)),
+            Direction::Forward => Ok(Some(Box::new(StatelessEncoder::new(
+                Self::forward_transform,
+                3,
+            )))),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new()))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        BTreeMap::new()
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
     }
 
     fn can_reverse(&self) -> bool {
@@ -50,7 +49,7 @@ impl TransformFactory {
     fn forward_transform(inp: &[u8], outp: &mut [u8]) -> (usize, usize) {
         let n = cmp::min(inp.len(), outp.len() / 2);
         let mut last = 0;
-        for (i, x) in inp
+        for (i, x) in inp[..n]
             .iter()
             .flat_map(|&x| {
                 if x == b'\n' {
@@ -79,34 +78,31 @@ impl Decoder {
 
 impl Codec for Decoder {
     fn transform(&mut self, src: &[u8], dst: &mut [u8], f: FlushState) -> Result<Status, Error> {
-        let max = cmp::min(src.len(), dst.len() - 2);
         let mut srcpos = 0;
         let mut dstpos = 0;
-        let mut iter = src.iter().take(max).peekable();
 
-        while let Some(incr) = iter.position(|&x| x == b'\r') {
-            let src = &src[srcpos..];
-            let dst = &mut dst[dstpos..];
-            dst[0..incr].copy_from_slice(&src[0..incr]);
+        while srcpos < src.len() && dstpos < dst.len() {
+            let max = cmp::min(src.len() - srcpos, dst.len() - dstpos);
+            let incr = src[srcpos..srcpos + max]
+                .iter()
+                .position(|&x| x == b'\r')
+                .unwrap_or(max);
+            dst[dstpos..dstpos + incr].copy_from_slice(&src[srcpos..srcpos + incr]);
             srcpos += incr;
             dstpos += incr;
+            if incr == max {
+                break;
+            }
 
-            let dst = &mut dst[incr..];
-            let (srcoff, dstoff) = match (iter.peek(), f) {
-                (Some(&&b'\n'), _) => {
-                    iter.next();
-                    dst[0] = b'\n';
-                    (2, 1)
-                }
-                (Some(&&b'\r'), _) => {
-                    dst[0] = b'\r';
-                    (1, 1)
+            // We're at a carriage return and there's space for at least one byte of output.
+            let srcoff = match (src.get(srcpos + 1), f) {
+                (Some(&b'\n'), _) => {
+                    dst[dstpos] = b'\n';
+                    2
                 }
-                (Some(&&x), _) => {
-                    iter.next();
-                    dst[0] = b'\r';
-                    dst[1] = x;
-                    (2, 2)
+                (Some(_), _) => {
+                    dst[dstpos] = b'\r';
+                    1
                 }
                 (None, FlushState::None) => {
                     return Ok(Status::SeqError(srcpos, dstpos));
@@ -116,17 +112,9 @@ impl Codec for Decoder {
                     return Ok(Status::StreamEnd(srcpos + 1, dstpos + 1));
                 }
             };
-            dstpos += dstoff;
             srcpos += srcoff;
+            dstpos += 1;
         }
-
-        let pos = max;
-        let incr = pos - srcpos;
-        let src = &src[srcpos..];
-        let dst = &mut dst[dstpos..];
-        dst[0..incr].copy_from_slice(&src[0..incr]);
-        srcpos += incr;
-        dstpos += incr;
         Ok(Status::Ok(srcpos, dstpos))
     }
 
@@ -141,13 +129,14 @@ impl Codec for Decoder {
 
 #[cfg(test)]
 mod tests {
+    use super::TransformFactory;
     use chain::Chain;
     use codec::registry::CodecRegistry;
     use codec::tests;
 
     fn check(inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![3, 4, 5, 6, 7, 8, 512] {
+        for &i in &[3, 4, 5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, "crlf", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, "-crlf", i, true);
@@ -192,6 +181,21 @@ mod tests {
         check(b"abcd\r\ndef", b"abcd\r\r\ndef");
     }
 
+    #[test]
+    fn encodes_only_what_fits() {
+        let mut out = [0u8; 5];
+        assert_eq!(
+            TransformFactory::forward_transform(b"a\nbcdef", &mut out),
+            (2, 3)
+        );
+        assert_eq!(&out[..3], b"a\r\n");
+    }
+
+    #[test]
+    fn decodes_within_buffer_size() {
+        tests::check_buffer_size("-crlf", b"a\r\nb\rc\r\r\n", b"a\nb\rc\r\n");
+    }
+
     #[test]
     fn default_tests() {
         tests::round_trip("crlf");
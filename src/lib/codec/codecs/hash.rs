@@ -2,6 +2,7 @@
 #![allow(bare_trait_objects)]
 
 use blake2::{VarBlake2b, VarBlake2s};
+use codec::schema::{OptionKind, OptionSchema};
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -9,17 +10,14 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use digest::{Digest, DynDigest, Input, InvalidOutputSize, Reset, VariableOutput};
 use md5::Md5;
 use sha1::Sha1;
 use sha2::{Sha224, Sha256, Sha384, Sha512};
 use sha3::{Sha3_224, Sha3_256, Sha3_384, Sha3_512};
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
-trait Hash {
+trait Hash: Send {
     fn input(&mut self, data: &[u8]);
     fn result_reset(&mut self) -> Box<[u8]>;
     fn output_size(&self) -> usize;
@@ -205,48 +203,50 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => (),
             Direction::Reverse => return Err(Error::ForwardOnly("hash".to_string())),
         }
 
         let length = s.int_arg("length")?;
-        let args: Vec<_> = s.args.keys().filter(|&s| s != "length").collect();
-        match args.len() {
-            0 => return Err(Error::MissingArgument("hash".to_string())),
-            1 => (),
-            _ => {
-                return Err(Error::IncompatibleParameters(
-                    args[0].to_string(),
-                    args[1].to_string(),
-                ));
-            }
-        };
-        Ok(Encoder::new(Self::digest(args[0], length)?).into_bufread(r, s.bufsize))
+        let name = s
+            .args
+            .keys()
+            .find(|&s| s != "length")
+            .ok_or_else(|| Error::MissingArgument("hash".to_string()))?;
+        Ok(Some(Box::new(Encoder::new(Self::digest(name, length)?))))
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("md5".to_string(), tr!("use MD5 as the hash"));
-        map.insert("sha1".to_string(), tr!("use SHA-1 as the hash"));
-        map.insert("sha224".to_string(), tr!("use SHA-224 as the hash"));
-        map.insert("sha256".to_string(), tr!("use SHA-256 as the hash"));
-        map.insert("sha384".to_string(), tr!("use SHA-384 as the hash"));
-        map.insert("sha512".to_string(), tr!("use SHA-512 as the hash"));
-        map.insert("sha3-224".to_string(), tr!("use SHA3-224 as the hash"));
-        map.insert("sha3-256".to_string(), tr!("use SHA3-256 as the hash"));
-        map.insert("sha3-384".to_string(), tr!("use SHA3-384 as the hash"));
-        map.insert("sha3-512".to_string(), tr!("use SHA3-512 as the hash"));
-        map.insert("blake2b".to_string(), tr!("use BLAKE2b as the hash"));
-        map.insert("blake2s".to_string(), tr!("use BLAKE2s as the hash"));
-        #[cfg(feature = "modern")]
-        map.insert("blake3".to_string(), tr!("use BLAKE3 as the hash"));
-        map.insert(
-            "length".to_string(),
-            tr!("specify the digest length in bytes for BLAKE2b, BLAKE2s, and BLAKE3"),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        let algorithms = [
+            ("md5", tr!("use MD5 as the hash")),
+            ("sha1", tr!("use SHA-1 as the hash")),
+            ("sha224", tr!("use SHA-224 as the hash")),
+            ("sha256", tr!("use SHA-256 as the hash")),
+            ("sha384", tr!("use SHA-384 as the hash")),
+            ("sha512", tr!("use SHA-512 as the hash")),
+            ("sha3-224", tr!("use SHA3-224 as the hash")),
+            ("sha3-256", tr!("use SHA3-256 as the hash")),
+            ("sha3-384", tr!("use SHA3-384 as the hash")),
+            ("sha3-512", tr!("use SHA3-512 as the hash")),
+            ("blake2b", tr!("use BLAKE2b as the hash")),
+            ("blake2s", tr!("use BLAKE2s as the hash")),
+            #[cfg(feature = "modern")]
+            ("blake3", tr!("use BLAKE3 as the hash")),
+        ];
+        let names: Vec<_> = algorithms.iter().map(|&(name, _)| name).collect();
+        algorithms
+            .iter()
+            .fold(OptionSchema::new(), |schema, &(name, ref desc)| {
+                schema.flag(name, desc.clone())
+            })
+            .one_of("hash", &names)
+            .option(
+                "length",
+                OptionKind::Integer { min: 1, max: None },
+                tr!("specify the digest length in bytes for BLAKE2b, BLAKE2s, and BLAKE3"),
+            )
     }
 
     fn can_reverse(&self) -> bool {
@@ -322,7 +322,7 @@ mod tests {
         let codec = format!("hash({}):hex", algo);
         let dlen = outp.len() / 2;
         eprintln!("algo = {}", algo);
-        for i in vec![dlen, dlen + 1, dlen + 2, dlen + 3, 512] {
+        for &i in &[dlen, dlen + 1, dlen + 2, dlen + 3, 512] {
             let c = Chain::new(&reg, &codec, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &codec, i, false);
@@ -330,10 +330,13 @@ mod tests {
         }
     }
 
+    // The algorithm and the digests of "", "abc", "message digest", and a million and three "a"s.
+    type Digests<'a> = (&'a str, &'a [u8], &'a [u8], &'a [u8], &'a [u8]);
+
     #[test]
     fn expected_values() {
         let buf = [b'a'; 1000003];
-        let items: &[(&str, &[u8], &[u8], &[u8], &[u8])] = &[
+        let items: &[Digests] = &[
             (
                 "md5",
                 b"d41d8cd98f00b204e9800998ecf8427e",
@@ -3,6 +3,8 @@
 
 use codec::helpers::codecs::FilteredDecoder;
 use codec::helpers::codecs::StatelessEncoder;
+use codec::helpers::simd::{self, Ranges};
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -10,10 +12,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -58,8 +57,9 @@ pub const MODHEXREV: [i8; 256] = [
 ];
 
 fn forward_transform(inp: &[u8], outp: &mut [u8], arr: &[u8; 16]) -> (usize, usize) {
+    let (a, _) = simd::encode(inp, outp, arr);
     let n = cmp::min(inp.len(), outp.len() / 2);
-    for (i, j) in (0..n).map(|x| (x, x * 2)) {
+    for (i, j) in (a..n).map(|x| (x, x * 2)) {
         outp[j..j + 2]
             .copy_from_slice(&[arr[(inp[i] >> 4) as usize], arr[(inp[i] & 0xf) as usize]]);
     }
@@ -73,24 +73,26 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => {
                 let arr = if s.bool_arg("upper")? { &UPPER } else { &LOWER };
-                Ok(
+                Ok(Some(Box::new(
                     StatelessEncoder::new(move |inp, out| forward_transform(inp, out, arr), 2)
-                        .into_bufread(r, s.bufsize),
-                )
+                        .with_ratio(1, 2),
+                )))
             }
-            Direction::Reverse => Ok(Decoder::new(s.strict, &REV).into_bufread(r, s.bufsize)),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(s.strict, &REV)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("lower".to_string(), tr!("use lowercase letters"));
-        map.insert("upper".to_string(), tr!("use uppercase letters"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("lower", tr!("use lowercase letters"))
+            .direction(Direction::Forward)
+            .flag("upper", tr!("use uppercase letters"))
+            .direction(Direction::Forward)
+            .exclusive(&["lower", "upper"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -109,19 +111,18 @@ impl ModHexTransformFactory {
 }
 
 impl CodecTransform for ModHexTransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
-            Direction::Forward => Ok(StatelessEncoder::new(
-                move |inp, out| forward_transform(inp, out, &MODHEX),
-                2,
-            )
-            .into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(Decoder::new(s.strict, &MODHEXREV).into_bufread(r, s.bufsize)),
+            Direction::Forward => Ok(Some(Box::new(
+                StatelessEncoder::new(move |inp, out| forward_transform(inp, out, &MODHEX), 2)
+                    .with_ratio(1, 2),
+            ))),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(s.strict, &MODHEXREV)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        BTreeMap::new()
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
     }
 
     fn can_reverse(&self) -> bool {
@@ -136,11 +137,16 @@ impl CodecTransform for ModHexTransformFactory {
 pub struct Decoder {
     strict: bool,
     rev: &'static [i8; 256],
+    ranges: Option<Ranges>,
 }
 
 impl Decoder {
     fn new(strict: bool, rev: &'static [i8; 256]) -> Self {
-        Decoder { strict, rev }
+        Decoder {
+            strict,
+            rev,
+            ranges: Ranges::new(rev),
+        }
     }
 }
 
@@ -170,12 +176,15 @@ impl FilteredDecoder for Decoder {
         }
 
         let bytes = cmp::min(src.len() / 2, dst.len());
-        let mut consumed = 0;
-        for (i, j) in (0..bytes).map(|x| (x * 2, x)) {
+        let (mut consumed, start) = match self.ranges {
+            Some(ref r) => simd::decode(&src[..bytes * 2], &mut dst[..bytes], 4, r),
+            None => (0, 0),
+        };
+        for (i, j) in (start..bytes).map(|x| (x * 2, x)) {
             let (x, y) = (src[i], src[i + 1]);
             let v: i16 = (i16::from(self.rev[x as usize]) << 4) | i16::from(self.rev[y as usize]);
             if v < 0 {
-                return Err(Error::InvalidSequence("hex".to_string(), vec![x, y]));
+                return Err(Error::InvalidSequence("hex".to_string(), vec![x, y]).at(i));
             }
             dst[j] = (v & 0xff) as u8;
             consumed = i + 2;
@@ -200,6 +209,10 @@ impl Codec for Decoder {
     fn buffer_size(&self) -> usize {
         1
     }
+
+    fn reset(&mut self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -210,7 +223,7 @@ mod tests {
 
     fn check(inp: &[u8], lower: &[u8], upper: &[u8], modhex: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![5, 6, 7, 8, 512] {
+        for &i in &[5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, "hex", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), lower);
             let c = Chain::new(&reg, "hex,lower", i, true);
@@ -232,6 +245,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reports_position() {
+        let reg = CodecRegistry::new();
+        let mut inp = b"0123456789abcdef".repeat(20);
+        inp.extend(b"0g12");
+        for &i in &[5, 6, 7, 8, 512] {
+            let c = Chain::new(&reg, "-hex", i, true);
+            let err = c.transform(inp.clone()).unwrap_err();
+            let pos = tests::error_position(&err).unwrap();
+            assert_eq!(pos.offset, 320);
+            assert_eq!(pos.line, None);
+        }
+    }
+
     #[test]
     fn encodes_bytes() {
         check(b"abc", b"616263", b"616263", b"hbhdhe");
@@ -249,8 +276,11 @@ mod tests {
     #[test]
     fn default_tests() {
         tests::round_trip("hex");
+        tests::vectorized_matches_portable("hex");
         tests::round_trip("hex,upper");
+        tests::vectorized_matches_portable("hex,upper");
         tests::round_trip("hex,lower");
+        tests::vectorized_matches_portable("hex,lower");
         tests::round_trip_stripped_whitespace("hex");
         tests::round_trip_stripped_whitespace("hex,upper");
         tests::round_trip_stripped_whitespace("hex,lower");
@@ -258,6 +288,7 @@ mod tests {
         tests::invalid_data("hex");
 
         tests::round_trip("modhex");
+        tests::vectorized_matches_portable("modhex");
         tests::basic_configuration("modhex");
         tests::round_trip_stripped_whitespace("modhex");
         tests::invalid_data("modhex");
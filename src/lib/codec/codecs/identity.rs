@@ -2,13 +2,12 @@
 #![allow(bare_trait_objects)]
 
 use codec::helpers::codecs::StatelessEncoder;
+use codec::schema::OptionSchema;
+use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
 use codec::Error;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -26,12 +25,14 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        Ok(StatelessEncoder::new(transform, 1).into_bufread(r, s.bufsize))
+    fn codec(&self, _s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        Ok(Some(Box::new(
+            StatelessEncoder::new(transform, 1).with_ratio(1, 1),
+        )))
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        BTreeMap::new()
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
     }
 
     fn can_reverse(&self) -> bool {
@@ -51,7 +52,7 @@ mod tests {
 
     fn check(inp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![4, 5, 6, 7, 8, 512] {
+        for &i in &[4, 5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, "identity", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), inp);
             let c = Chain::new(&reg, "identity", i, false);
@@ -1,6 +1,7 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
 
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -8,10 +9,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -23,21 +21,16 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => (),
             Direction::Reverse => return Err(Error::ForwardOnly("lf".to_string())),
         }
-        Ok(Encoder::new(s.args.contains_key("empty")).into_bufread(r, s.bufsize))
+        Ok(Some(Box::new(Encoder::new(s.args.contains_key("empty")))))
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert(
-            "empty".to_string(),
-            tr!("print nothing if the input is empty"),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new().flag("empty", tr!("print nothing if the input is empty"))
     }
 
     fn can_reverse(&self) -> bool {
@@ -135,7 +128,7 @@ mod tests {
 
     fn check(inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![4, 5, 6, 512] {
+        for &i in &[4, 5, 6, 512] {
             let c = Chain::new(&reg, "lf", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, "lf", i, false);
@@ -149,7 +142,7 @@ mod tests {
 
     fn check_full(inp: &[u8], def: &[u8], empty: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![4, 5, 6, 512] {
+        for &i in &[4, 5, 6, 512] {
             let c = Chain::new(&reg, "lf", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), def);
             let c = Chain::new(&reg, "lf", i, false);
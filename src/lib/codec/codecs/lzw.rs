@@ -0,0 +1,631 @@
+#![allow(unknown_lints)]
+#![allow(bare_trait_objects)]
+
+use codec::schema::{OptionKind, OptionSchema};
+use codec::Codec;
+use codec::CodecSettings;
+use codec::CodecTransform;
+use codec::Direction;
+use codec::Error;
+use codec::FlushState;
+use codec::Status;
+use std::cmp;
+
+/// The magic number at the start of compressed data.
+const MAGIC: [u8; 2] = [0x1f, 0x9d];
+/// The flag in the third byte of the header for block mode, in which the table can be cleared.
+const BLOCK_MODE: u8 = 0x80;
+/// The mask for the maximum code size in the third byte of the header.
+const BITS_MASK: u8 = 0x1f;
+const HEADER_LEN: usize = 3;
+
+const INIT_BITS: u32 = 9;
+const MAX_BITS: u32 = 16;
+/// The code which clears the table in block mode.
+const CLEAR: u32 = 256;
+/// The first code for a string of more than one byte in block mode.
+const FIRST: u32 = 257;
+/// How often, in bytes of input, the encoder checks whether a full table should be cleared.
+const CHECK_GAP: u64 = 10000;
+
+/// The size of codes, which grows as the table fills, as both the encoder and decoder see it.
+///
+/// Codes are written in groups of eight, which take a whole number of bytes.  When the size
+/// changes, the rest of the current group is padding, as compress(1) writes it.
+struct CodeSize {
+    bits: u32,
+    max_bits: u32,
+    // The largest number of entries in the table before the codes grow.
+    limit: u32,
+    // The number of codes of this size so far.
+    count: u32,
+}
+
+impl CodeSize {
+    fn new(max_bits: u32) -> Self {
+        CodeSize {
+            bits: INIT_BITS,
+            max_bits,
+            limit: (1 << INIT_BITS) - 1,
+            count: 0,
+        }
+    }
+
+    /// The number of bits of padding to the end of the current group.
+    fn padding(&self) -> u32 {
+        (8 - self.count % 8) % 8 * self.bits
+    }
+
+    /// Prepare to read or write a code when the table's next entry is `next`, returning the bits
+    /// of padding which come first.
+    fn before_code(&mut self, next: u32) -> u32 {
+        if next <= self.limit {
+            return 0;
+        }
+        let pad = self.padding();
+        self.bits += 1;
+        self.limit = if self.bits == self.max_bits {
+            1 << self.max_bits
+        } else {
+            (1 << self.bits) - 1
+        };
+        self.count = 0;
+        pad
+    }
+
+    /// Return to the initial size after the table is cleared, returning the bits of padding.
+    fn reset(&mut self) -> u32 {
+        let pad = self.padding();
+        *self = CodeSize::new(self.max_bits);
+        pad
+    }
+}
+
+#[derive(Default)]
+pub struct TransformFactory {}
+
+impl TransformFactory {
+    pub fn new() -> Self {
+        TransformFactory {}
+    }
+}
+
+impl CodecTransform for TransformFactory {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        Ok(Some(match s.dir {
+            Direction::Forward => Box::new(Encoder::new(s.int_arg("bits")?.unwrap_or(MAX_BITS))),
+            Direction::Reverse => Box::new(Decoder::new()),
+        }))
+    }
+
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .option(
+                "bits",
+                OptionKind::Integer {
+                    min: u64::from(INIT_BITS),
+                    max: Some(u64::from(MAX_BITS)),
+                },
+                tr!("maximum size of a code in bits (default 16)"),
+            )
+            .direction(Direction::Forward)
+    }
+
+    fn can_reverse(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "lzw"
+    }
+}
+
+/// The encoder's table, mapping the code for a string and a following byte to the code for the
+/// longer string.
+///
+/// Keys are stored plus one, so that an empty slot is zero and a new table needs no filling.
+struct Table {
+    keys: Vec<u32>,
+    codes: Vec<u16>,
+}
+
+impl Table {
+    // Twice the largest number of entries, so that probe sequences stay short.
+    const SIZE: usize = 1 << (MAX_BITS + 1);
+
+    fn new() -> Self {
+        Table {
+            keys: vec![0; Self::SIZE],
+            codes: vec![0; Self::SIZE],
+        }
+    }
+
+    /// Returns the slot where the entry for `key` is or would go.
+    fn slot(&self, key: u32) -> usize {
+        let mut i = (key.wrapping_mul(0x9e37_79b1) >> (32 - MAX_BITS - 1)) as usize;
+        while self.keys[i] != key && self.keys[i] != 0 {
+            i = (i + 1) & (Self::SIZE - 1);
+        }
+        i
+    }
+
+    fn get(&self, code: u32, b: u8) -> Option<u32> {
+        let key = (code << 8 | u32::from(b)) + 1;
+        let i = self.slot(key);
+        if self.keys[i] == key {
+            Some(u32::from(self.codes[i]))
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, code: u32, b: u8, value: u32) {
+        let key = (code << 8 | u32::from(b)) + 1;
+        let i = self.slot(key);
+        self.keys[i] = key;
+        self.codes[i] = value as u16;
+    }
+
+    fn clear(&mut self) {
+        for k in self.keys.iter_mut() {
+            *k = 0;
+        }
+    }
+}
+
+pub struct Encoder {
+    table: Table,
+    size: CodeSize,
+    // One more than the largest code.
+    max_code: u32,
+    // The code for the input matched so far.
+    ent: Option<u32>,
+    // The code for the next entry in the table.
+    next: u32,
+    // The code for the next entry as the decoder sees it, since it adds each entry one code later.
+    decoder_next: u32,
+    started: bool,
+    acc: u64,
+    nbits: u32,
+    // Output not yet written.
+    pending: Vec<u8>,
+    bytes_in: u64,
+    bits_out: u64,
+    checkpoint: u64,
+    ratio: u64,
+    finished: bool,
+}
+
+impl Encoder {
+    fn new(max_bits: u32) -> Self {
+        let mut pending = MAGIC.to_vec();
+        pending.push(BLOCK_MODE | max_bits as u8);
+        Encoder {
+            table: Table::new(),
+            size: CodeSize::new(max_bits),
+            max_code: 1 << max_bits,
+            ent: None,
+            next: FIRST,
+            decoder_next: FIRST,
+            started: false,
+            acc: 0,
+            nbits: 0,
+            pending,
+            bytes_in: 0,
+            bits_out: HEADER_LEN as u64 * 8,
+            checkpoint: CHECK_GAP,
+            ratio: 0,
+            finished: false,
+        }
+    }
+
+    fn put_bits(&mut self, value: u32, bits: u32) {
+        self.acc |= u64::from(value) << self.nbits;
+        self.nbits += bits;
+        self.bits_out += u64::from(bits);
+        while self.nbits >= 8 {
+            self.pending.push(self.acc as u8);
+            self.acc >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn put_padding(&mut self, bits: u32) {
+        for _ in 0..bits / 8 {
+            self.put_bits(0, 8);
+        }
+        self.put_bits(0, bits % 8);
+    }
+
+    fn emit(&mut self, code: u32) {
+        let pad = self.size.before_code(self.decoder_next);
+        self.put_padding(pad);
+        let bits = self.size.bits;
+        self.put_bits(code, bits);
+        self.size.count += 1;
+        if !self.started {
+            self.started = true;
+        } else if code == CLEAR {
+            self.decoder_next = CLEAR;
+            let pad = self.size.reset();
+            self.put_padding(pad);
+        } else if self.decoder_next < self.max_code {
+            self.decoder_next += 1;
+        }
+    }
+
+    /// Clear the table if it is full and compression has gotten worse since the last check, as
+    /// compress(1) does.
+    fn check_ratio(&mut self) {
+        if self.bytes_in < self.checkpoint {
+            return;
+        }
+        self.checkpoint = self.bytes_in + CHECK_GAP;
+        let bytes_out = self.bits_out / 8;
+        let ratio = if self.bytes_in > 0x007f_ffff {
+            match bytes_out >> 8 {
+                0 => 0x7fff_ffff,
+                out => self.bytes_in / out,
+            }
+        } else {
+            (self.bytes_in << 8) / cmp::max(bytes_out, 1)
+        };
+        if ratio >= self.ratio {
+            self.ratio = ratio;
+            return;
+        }
+        self.ratio = 0;
+        self.emit(CLEAR);
+        self.table.clear();
+        self.next = FIRST;
+    }
+
+    fn push(&mut self, b: u8) {
+        self.bytes_in += 1;
+        let ent = match self.ent {
+            Some(ent) => ent,
+            None => {
+                self.ent = Some(u32::from(b));
+                return;
+            }
+        };
+        if let Some(code) = self.table.get(ent, b) {
+            self.ent = Some(code);
+            return;
+        }
+        self.emit(ent);
+        if self.next < self.max_code {
+            self.table.insert(ent, b, self.next);
+            self.next += 1;
+        } else {
+            self.check_ratio();
+        }
+        self.ent = Some(u32::from(b));
+    }
+
+    fn finish(&mut self) {
+        if let Some(ent) = self.ent.take() {
+            self.emit(ent);
+        }
+        if self.nbits > 0 {
+            self.pending.push(self.acc as u8);
+            self.acc = 0;
+            self.nbits = 0;
+        }
+        self.finished = true;
+    }
+
+    /// Write as much pending output as fits into `out`, returning the amount written.
+    fn drain(&mut self, out: &mut [u8]) -> usize {
+        let n = cmp::min(out.len(), self.pending.len());
+        out[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+}
+
+impl Codec for Encoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        let (mut consumed, mut written) = (0, 0);
+        loop {
+            written += self.drain(&mut out[written..]);
+            if !self.pending.is_empty() {
+                break;
+            }
+            if consumed < inp.len() {
+                // Keep the pending output small by encoding a little at a time.
+                let end = cmp::min(inp.len(), consumed + 64);
+                for &b in &inp[consumed..end] {
+                    self.push(b);
+                }
+                consumed = end;
+            } else if f == FlushState::Finish && !self.finished {
+                self.finish();
+            } else {
+                break;
+            }
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+pub struct Decoder {
+    header: Vec<u8>,
+    block_mode: bool,
+    size: CodeSize,
+    max_code: u32,
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    // The code for the next entry in the table.
+    next: u32,
+    // The previous code and the first byte of its string.
+    old: Option<u32>,
+    first: u8,
+    acc: u64,
+    nbits: u32,
+    // Bits of padding still to be skipped.
+    skip: u32,
+    // The string for the last code, not yet written.
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Decoder {
+            header: Vec::with_capacity(HEADER_LEN),
+            block_mode: false,
+            size: CodeSize::new(MAX_BITS),
+            max_code: 0,
+            prefix: vec![0; 1 << MAX_BITS],
+            suffix: vec![0; 1 << MAX_BITS],
+            next: 0,
+            old: None,
+            first: 0,
+            acc: 0,
+            nbits: 0,
+            skip: 0,
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn invalid(&self, seq: &[u8]) -> Error {
+        Error::InvalidSequence("lzw".to_string(), seq.to_vec())
+    }
+
+    fn check_header(&mut self) -> Result<(), Error> {
+        let max_bits = u32::from(self.header[2] & BITS_MASK);
+        if self.header[..2] != MAGIC || !(INIT_BITS..=MAX_BITS).contains(&max_bits) {
+            return Err(self.invalid(&self.header));
+        }
+        self.block_mode = self.header[2] & BLOCK_MODE != 0;
+        self.size = CodeSize::new(max_bits);
+        self.max_code = 1 << max_bits;
+        self.next = if self.block_mode { FIRST } else { CLEAR };
+        Ok(())
+    }
+
+    /// Decode a code into `pending`.
+    fn decode(&mut self, code: u32) -> Result<(), Error> {
+        self.pending.clear();
+        self.pos = 0;
+        let old = match self.old {
+            Some(old) => old,
+            None if code < CLEAR => {
+                self.old = Some(code);
+                self.first = code as u8;
+                self.pending.push(self.first);
+                return Ok(());
+            }
+            None => return Err(self.invalid(&[])),
+        };
+        if code == CLEAR && self.block_mode {
+            self.next = CLEAR;
+            self.skip += self.size.reset();
+            return Ok(());
+        }
+        let mut cur = code;
+        if cur >= self.next {
+            if cur > self.next {
+                return Err(self.invalid(&[]));
+            }
+            // The string for the code being defined: the previous string and its first byte.
+            self.pending.push(self.first);
+            cur = old;
+        }
+        while cur >= CLEAR {
+            if self.pending.len() > self.max_code as usize {
+                return Err(self.invalid(&[]));
+            }
+            self.pending.push(self.suffix[cur as usize]);
+            cur = u32::from(self.prefix[cur as usize]);
+        }
+        self.first = cur as u8;
+        self.pending.push(self.first);
+        self.pending.reverse();
+        if self.next < self.max_code {
+            self.prefix[self.next as usize] = old as u16;
+            self.suffix[self.next as usize] = self.first;
+            self.next += 1;
+        }
+        self.old = Some(code);
+        Ok(())
+    }
+}
+
+impl Codec for Decoder {
+    fn transform(&mut self, inp: &[u8], out: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        let (mut consumed, mut written) = (0, 0);
+        loop {
+            let n = cmp::min(out.len() - written, self.pending.len() - self.pos);
+            out[written..written + n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+            written += n;
+            self.pos += n;
+            if self.pos < self.pending.len() {
+                break;
+            }
+            if self.header.len() < HEADER_LEN {
+                let n = cmp::min(HEADER_LEN - self.header.len(), inp.len() - consumed);
+                self.header.extend_from_slice(&inp[consumed..consumed + n]);
+                consumed += n;
+                if self.header.len() < HEADER_LEN {
+                    break;
+                }
+                self.check_header()?;
+            }
+            self.skip += self.size.before_code(self.next);
+            while self.skip > 0 || self.nbits < self.size.bits {
+                if self.nbits == 0 || self.skip == 0 {
+                    if consumed == inp.len() {
+                        break;
+                    }
+                    self.acc |= u64::from(inp[consumed]) << self.nbits;
+                    self.nbits += 8;
+                    consumed += 1;
+                }
+                let n = cmp::min(self.skip, self.nbits);
+                self.acc >>= n;
+                self.nbits -= n;
+                self.skip -= n;
+            }
+            if self.skip > 0 || self.nbits < self.size.bits {
+                break;
+            }
+            let code = (self.acc & ((1 << self.size.bits) - 1)) as u32;
+            self.acc >>= self.size.bits;
+            self.nbits -= self.size.bits;
+            self.size.count += 1;
+            // Report an invalid code at the byte holding its last bits.
+            self.decode(code)
+                .map_err(|e| e.at(consumed.saturating_sub(1)))?;
+        }
+        if f == FlushState::Finish && self.header.len() < HEADER_LEN && written == 0 {
+            return Err(Error::TruncatedData);
+        }
+        Ok(Status::Ok(consumed, written))
+    }
+
+    fn chunk_size(&self) -> usize {
+        1
+    }
+
+    fn buffer_size(&self) -> usize {
+        64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chain::Chain;
+    use codec::registry::CodecRegistry;
+    use codec::tests;
+
+    // The output of compress -b10 for the input in decodes_cleared_table, in base64.  It was
+    // generated with a port of compress(1) from ncompress 4.2.4 and checked with gzip -d.  The
+    // codes grow to their maximum width, the table fills, and it is cleared shortly before the end.
+    const CLEARED: &str = "\
+        H52KAAIKHEiwoMGDCBMqXMiwocOHECNKnEixosWLGDNq3Mixo8ePIEOKHEmypMmTKFOqXMmypcuXMGPKnEmzps2b\
+        OHPq3Mmzp8+fQIMKHUq0qNGjSJMqXcq0qdOnUKNKnUq1qtWrWLNq3cq1q9evYMOKHUu2rNmzaNOqXcu2rdu3cOPK\
+        nUu3rt27ePPq3cu3r9+/gAMLHky4sOHDiBMrXsy4sePHkCNLnky5suXLmDNr3sy5s+fPoEOLHk26tOnTqFOrXs26\
+        tevXsGPLnk27tu3buHPr3s27t+/fwIMLH068uPHjyJMrX868ufPn0KNLn069uvXr2LNr3869u/fv4MOLH0++vPnz\
+        6NOrX8++vfv38OPLn0+/vv37+PPr38+/v///AAYo4IAEFmjggQgmqOCCDDbo4IMQRijhhBRWaOGFGGao4YYcdujh\
+        hyCGKOKIJJZo4okopqjiiiy26OKLMMYo44w01mjjjTjmqOOOPPbo449ABinkkEQWaeSRSCap5JJMNunkk1BGKeWU\
+        VFZp5ZVYZqnlllx26eWXYIYp5phklmnmmWimqeaabLbp5ptwxinnnHTWaeedeOap55589unnn4AGKuighBZq6KGI\
+        Jqrooow26uijkEYq6aSUVmrppZhmqummnHbq6aeghirqqKSWauqpqKaq6qqsturqq7DGKuustNZq66245qrrrrz2\
+        6uuvwAYr7LDEFmvsscgmq+yyzDbr7LPQRivttNRWa+212Gar7bbcduvtt+CGK+645JZr7rnopqvuuuy26+678MYr\
+        77z01mvvvfjmq+++/Pbr778AByzwwAQXbPDBCCes8MIMN+zwwxBHLPHEFFds8cUYZ6zxxhx37PHHIIcs8sgkl2zy\
+        ySinrPLKLLfs8sswxyzzzDTXbPPNOOes88489+zzz0AHLfTQRBdt9NFIJ6300kw37fTTUEct9dRUV2311VhnrfXW\
+        XHft9ddghy322GSXbfbZaKet9tpst+3223DHLffcdNdt991456333nz37fffgAcu+OCEF2744YgnrvjijDfu+OOQ\
+        Ry755JRXbvnlmGeu+eacd+7556CHLvropJdu+umop6766qy37vrrsMcu++y012777bjnrvvuvPfu++/ABy/88MQX\
+        b/zxyCev/PLMN+/889BHL/301Fdv/fXYZ6/99tx37/334Icv/vjkl2/++einr/767Lfv/vvwxy///PTXb//9+Oev\
+        //789+//////////////bgCPBvBoAI8G8GgAjwbwaACPBvBoAI8G8GgAjwIEAAABwogZI5DgQA==";
+
+    #[test]
+    fn round_trip() {
+        tests::round_trip("lzw");
+        tests::round_trip("lzw(bits=9)");
+        tests::basic_configuration("lzw");
+        tests::invalid_data("lzw");
+    }
+
+    #[test]
+    fn decodes_known_data() {
+        // Produced by compress(1).
+        let reg = CodecRegistry::new();
+        let c = Chain::new(&reg, "-lzw", 512, true);
+        assert_eq!(c.transform(b"\x1f\x9d\x90".to_vec()).unwrap(), b"");
+        assert_eq!(
+            c.transform(b"\x1f\x9d\x90\x61\xc4\x8c\x09\x38\x50\x20".to_vec())
+                .unwrap(),
+            b"abcabcabc"
+        );
+    }
+
+    #[test]
+    fn decodes_cleared_table() {
+        let mut inp = vec![0u8; 300_000];
+        for _ in 0..12 {
+            inp.extend_from_slice(&[0u8; 400]);
+            inp.push(1);
+        }
+        inp.extend_from_slice(b"abcabcabc");
+        let reg = CodecRegistry::new();
+        for &i in &[7, 512] {
+            let c = Chain::new(&reg, "-base64:-lzw", i, true);
+            assert_eq!(c.transform(CLEARED.as_bytes().to_vec()).unwrap(), inp);
+        }
+    }
+
+    #[test]
+    fn encodes_wide_codes_as_compress_does() {
+        // The codes grow to 16 bits.  The digest is of the output of compress(1), generated and
+        // checked as for CLEARED.
+        let inp: Vec<u8> = (0..40000)
+            .flat_map(|i| format!("{}\n", i).into_bytes())
+            .collect();
+        let reg = CodecRegistry::new();
+        let enc = Chain::new(&reg, "lzw", 512, true)
+            .transform(inp.clone())
+            .unwrap();
+        let c = Chain::new(&reg, "hash(sha256):hex", 512, true);
+        assert_eq!(
+            c.transform(enc.clone()).unwrap(),
+            b"1857cbd4bb859f0e17a9968912c8a867e0ae206490728842b4354acb100d68d2".to_vec()
+        );
+        let c = Chain::new(&reg, "-lzw", 512, true);
+        assert_eq!(c.transform(enc).unwrap(), inp);
+    }
+
+    #[test]
+    fn round_trips_large_input() {
+        // Enough input to widen the codes to 16 bits, and with smaller codes, to fill the table and
+        // clear it when the data changes.
+        let mut x = 1u32;
+        let mut inp: Vec<u8> = (0..150_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                (x % 16) as u8
+            })
+            .collect();
+        inp.extend((0..150_000u32).map(|x| (x % 7) as u8 ^ (x >> 12) as u8));
+        for chain in &["lzw", "lzw(bits=9)", "lzw(bits=13)"] {
+            let reg = CodecRegistry::new();
+            let enc = Chain::new(&reg, *chain, 512, true)
+                .transform(inp.clone())
+                .unwrap();
+            let c = Chain::new(&reg, "-lzw", 512, true);
+            assert_eq!(c.transform(enc).unwrap(), inp, "{}", chain);
+        }
+    }
+}
@@ -10,6 +10,7 @@ pub mod hash;
 pub mod hex;
 pub mod identity;
 pub mod lf;
+pub mod lzw;
 pub mod quotedprintable;
 pub mod swab;
 #[cfg(test)]
@@ -8,11 +8,9 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
-use std::collections::BTreeMap;
-use std::io;
 
 use codec::codecs::hex::UPPER;
+use codec::schema::{OptionKind, OptionSchema};
 
 #[derive(Copy, Clone)]
 enum Characters {
@@ -305,21 +303,23 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        let linelen = s.length_arg("length", 4, Some(76))?;
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        let linelen = s.length_arg("length")?;
         match s.dir {
-            Direction::Forward => Ok(Encoder::new(linelen).into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(Decoder::new(s.strict).into_bufread(r, s.bufsize)),
+            Direction::Forward => Ok(Some(Box::new(Encoder::new(linelen)))),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(s.strict)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert(
-            "length".to_string(),
-            tr!("wrap at specified line length (default 76; 0 disables)"),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .option(
+                "length",
+                OptionKind::Length(4),
+                tr!("wrap at specified line length (default 76; 0 disables)"),
+            )
+            .default_value("76")
+            .direction(Direction::Forward)
     }
 
     fn can_reverse(&self) -> bool {
@@ -363,7 +363,7 @@ impl Codec for Encoder {
             if let Some(linelen) = self.linelen {
                 // +1 for b'='.  Note that we don't count the LF, since the RFC says not to.
                 if enclen + curline + 1 > linelen {
-                    outp[j..j + 2].copy_from_slice(&[b'=', b'\n']);
+                    outp[j..j + 2].copy_from_slice(b"=\n");
                     j += 2;
                     curline = 0;
                 }
@@ -430,8 +430,9 @@ impl Codec for Decoder {
                             if val < 0 {
                                 return Err(Error::InvalidSequence(
                                     "quotedprintable".to_string(),
-                                    vec![*c1, *c2],
-                                ));
+                                    vec![b'=', *c1, *c2],
+                                )
+                                .at(i));
                             }
                             dst[j] = val as u8;
                         }
@@ -452,6 +453,10 @@ impl Codec for Decoder {
     fn buffer_size(&self) -> usize {
         1
     }
+
+    fn line_oriented(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -464,7 +469,7 @@ mod tests {
     fn check(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
         let reverse = format!("-{}", name);
-        for i in vec![76, 77, 78, 79, 512] {
+        for &i in &[76, 77, 78, 79, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &reverse, i, true);
@@ -476,7 +481,7 @@ mod tests {
 
     fn check_decode(inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![76, 77, 78, 79, 512] {
+        for &i in &[76, 77, 78, 79, 512] {
             let c = Chain::new(&reg, "-quotedprintable", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, "-quotedprintable", i, false);
@@ -492,7 +497,7 @@ mod tests {
                     let c = Chain::new(&reg, "-quotedprintable", i, b);
                     match c.transform($inp.to_vec()) {
                         Ok(_) => panic!("got success for invalid sequence"),
-                        Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                        Err(e) => match tests::codec_error(&e) {
                             Some(&$x) => (),
                             Some(e) => panic!("got wrong error: {:?}", e),
                             None => panic!("No internal error?"),
@@ -509,7 +514,7 @@ mod tests {
             let c = Chain::new(&reg, $inp, 512, false);
             match c.transform((0..32).collect()) {
                 Ok(_) => panic!("got success for invalid sequence"),
-                Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                Err(e) => match tests::codec_error(&e) {
                     Some(&$x) => (),
                     Some(e) => panic!("got wrong error: {:?}", e),
                     None => panic!("No internal error?"),
@@ -540,6 +545,23 @@ mod tests {
         check_failure!(b"abc=ff", Error::InvalidSequence(_, _));
     }
 
+    #[test]
+    fn reports_position() {
+        let reg = CodecRegistry::new();
+        for &i in &[76, 77, 78, 79, 512] {
+            let c = Chain::new(&reg, "-quotedprintable", i, true);
+            let err = c.transform(b"abc=3D\nde=vvf=".to_vec()).unwrap_err();
+            let pos = tests::error_position(&err).unwrap();
+            assert_eq!(pos.offset, 9);
+            assert_eq!(pos.line, Some((2, 3)));
+
+            let err = c.transform(b"abc\n\nd=4".to_vec()).unwrap_err();
+            let pos = tests::error_position(&err).unwrap();
+            assert_eq!(pos.offset, 6);
+            assert_eq!(pos.line, Some((3, 2)));
+        }
+    }
+
     #[test]
     fn rejects_invalid_length() {
         check_length!("quotedprintable,length", Error::MissingArgument(_));
@@ -1,6 +1,7 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
 
+use codec::schema::{OptionKind, OptionSchema};
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -8,10 +9,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -23,22 +21,26 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => {
                 let chunklen = s
-                    .length_arg("length", 1, None)?
+                    .int_arg("length")?
                     .ok_or_else(|| Error::MissingArgument("swab".to_string()))?;
-                Ok(Encoder::new(chunklen).into_bufread(r, s.bufsize))
+                Ok(Some(Box::new(Encoder::new(chunklen))))
             }
             Direction::Reverse => Err(Error::ForwardOnly("swab".to_string())),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("length".to_string(), tr!("handle chunks of this size"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .option(
+                "length",
+                OptionKind::Integer { min: 1, max: None },
+                tr!("handle chunks of this size"),
+            )
+            .one_of("swab", &["length"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -113,7 +115,7 @@ mod tests {
 
     fn check(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![8, 9, 10, 11, 512] {
+        for &i in &[8, 9, 10, 11, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
         }
@@ -125,7 +127,7 @@ mod tests {
             let c = Chain::new(&reg, $inp, 512, false);
             match c.transform((0..32).collect()) {
                 Ok(_) => panic!("got success for invalid sequence"),
-                Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                Err(e) => match tests::codec_error(&e) {
                     Some(&$x) => (),
                     Some(e) => panic!("got wrong error: {:?}", e),
                     None => panic!("No internal error?"),
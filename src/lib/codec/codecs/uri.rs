@@ -11,11 +11,9 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
-use std::collections::BTreeMap;
-use std::io;
 
 use codec::codecs::hex::{LOWER, REV, UPPER};
+use codec::schema::OptionSchema;
 
 enum Characters {
     Identity,
@@ -325,15 +323,17 @@ impl URITransformFactory {
 }
 
 impl CodecTransform for URITransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        Self::generic_factory(r, s, false)
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        Self::generic_codec(s, false)
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("lower".to_string(), tr!("use lowercase letters"));
-        map.insert("upper".to_string(), tr!("use uppercase letters"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("lower", tr!("use lowercase letters"))
+            .direction(Direction::Forward)
+            .flag("upper", tr!("use uppercase letters"))
+            .direction(Direction::Forward)
+            .exclusive(&["lower", "upper"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -346,23 +346,16 @@ impl CodecTransform for URITransformFactory {
 }
 
 impl URITransformFactory {
-    fn generic_factory(
-        r: Box<io::BufRead>,
-        s: CodecSettings,
-        form: bool,
-    ) -> Result<Box<io::BufRead>, Error> {
+    fn generic_codec(s: CodecSettings, form: bool) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => {
                 let arr = if s.bool_arg("lower")? { &LOWER } else { &UPPER };
-                Ok(
-                    StatelessEncoder::new(
-                        move |inp, out| forward_transform(inp, out, arr, form),
-                        3,
-                    )
-                    .into_bufread(r, s.bufsize),
-                )
+                Ok(Some(Box::new(StatelessEncoder::new(
+                    move |inp, out| forward_transform(inp, out, arr, form),
+                    3,
+                ))))
             }
-            Direction::Reverse => Ok(Decoder::new(form).into_bufread(r, s.bufsize)),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(form)))),
         }
     }
 }
@@ -377,15 +370,17 @@ impl FormTransformFactory {
 }
 
 impl CodecTransform for FormTransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        URITransformFactory::generic_factory(r, s, true)
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        URITransformFactory::generic_codec(s, true)
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("lower".to_string(), tr!("use lowercase letters"));
-        map.insert("upper".to_string(), tr!("use uppercase letters"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("lower", tr!("use lowercase letters"))
+            .direction(Direction::Forward)
+            .flag("upper", tr!("use uppercase letters"))
+            .direction(Direction::Forward)
+            .exclusive(&["lower", "upper"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -428,8 +423,9 @@ impl Codec for Decoder {
                             if val < 0 {
                                 return Err(Error::InvalidSequence(
                                     "uri".to_string(),
-                                    vec![*c1, *c2],
-                                ));
+                                    vec![b'%', *c1, *c2],
+                                )
+                                .at(i));
                             }
                             dst[j] = val as u8;
                         }
@@ -478,7 +474,7 @@ mod tests {
         let lname = format!("{},lower", name);
         let uname = format!("{},upper", name);
         let reverse = format!("-{}", name);
-        for i in vec![4, 5, 6, 7, 8, 512] {
+        for &i in &[4, 5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), upper);
             let c = Chain::new(&reg, &lname, i, true);
@@ -497,7 +493,7 @@ mod tests {
     fn check_decode(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
         let reverse = format!("-{}", name);
-        for i in vec![4, 5, 6, 7, 8, 512] {
+        for &i in &[4, 5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, &reverse, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &reverse, i, false);
@@ -513,7 +509,7 @@ mod tests {
                     let c = Chain::new(&reg, $rev, i, b);
                     match c.transform($inp.to_vec()) {
                         Ok(_) => panic!("got success for invalid sequence"),
-                        Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                        Err(e) => match tests::codec_error(&e) {
                             Some(&$x) => (),
                             Some(e) => panic!("got wrong error: {:?}", e),
                             None => panic!("No internal error?"),
@@ -1,8 +1,10 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
-#![cfg_attr(feature = "cargo-clippy", allow(clippy::manual_range_contains))]
+#![allow(clippy::manual_range_contains)]
+#![allow(clippy::manual_div_ceil)]
 
 use codec::helpers::codecs::FilteredDecoder;
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -10,10 +12,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct UuencodeTransformFactory {}
@@ -25,15 +24,15 @@ impl UuencodeTransformFactory {
 }
 
 impl CodecTransform for UuencodeTransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
-            Direction::Forward => Ok(Encoder::new().into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(Decoder::new(s.strict).into_bufread(r, s.bufsize)),
+            Direction::Forward => Ok(Some(Box::new(Encoder::new()))),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(s.strict)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        BTreeMap::new()
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
     }
 
     fn can_reverse(&self) -> bool {
@@ -125,7 +124,8 @@ impl Codec for Encoder {
     }
 
     fn buffer_size(&self) -> usize {
-        62
+        // The final line and the terminating line.
+        64
     }
 }
 
@@ -165,13 +165,18 @@ impl Decoder {
         if self.strict {
             let m = inp[0..inp.len() - 1]
                 .iter()
-                .find(|&&v| !Self::valid_char(v));
+                .enumerate()
+                .find(|&(_, &v)| !Self::valid_char(v));
             match (m, inp[inp.len() - 1]) {
-                (Some(&x), _) => {
-                    return Err(Error::InvalidSequence("uuencode".to_string(), vec![x]))
+                (Some((k, &x)), _) => {
+                    return Err(Error::InvalidSequence("uuencode".to_string(), vec![x]).at(k))
                 }
                 (None, b'\n') => (),
-                (None, c) => return Err(Error::InvalidSequence("uuencode".to_string(), vec![c])),
+                (None, c) => {
+                    return Err(
+                        Error::InvalidSequence("uuencode".to_string(), vec![c]).at(inp.len() - 1)
+                    )
+                }
             };
         };
 
@@ -207,6 +212,10 @@ impl Codec for Decoder {
     fn buffer_size(&self) -> usize {
         45
     }
+
+    fn line_oriented(&self) -> bool {
+        true
+    }
 }
 
 impl FilteredDecoder for Decoder {
@@ -236,7 +245,9 @@ impl FilteredDecoder for Decoder {
 
         let ret = (0..chunks).try_fold((0, 0), |_, i| {
             let max = cmp::min((i + 1) * is, inp.len());
-            let r = self.transform_chunk(&inp[i * is..max], &mut outp[i * os..(i + 1) * os])?;
+            let r = self
+                .transform_chunk(&inp[i * is..max], &mut outp[i * os..(i + 1) * os])
+                .map_err(|e| e.at(i * is))?;
             Ok((i * is + r.0, i * os + r.1))
         })?;
         Ok(Status::Ok(ret.0, ret.1))
@@ -251,12 +262,12 @@ mod tests {
 
     fn check(inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![45, 46, 47, 48, 512] {
+        for &i in &[45, 46, 47, 48, 512] {
             let c = Chain::new(&reg, "uuencode", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
         }
 
-        for i in vec![62, 63, 64, 65, 512] {
+        for &i in &[62, 63, 64, 65, 512] {
             let c = Chain::new(&reg, "-uuencode", i, true);
             assert_eq!(c.transform(outp.to_vec()).unwrap(), inp);
             let c = Chain::new(&reg, "-uuencode", i, false);
@@ -282,6 +293,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn encodes_within_buffer_size() {
+        tests::check_buffer_size(
+            "uuencode",
+            b"abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqr",
+            b"L86)C9&5F9VAI:FML;6YO<'%R<W1U=G=X>7IA8F-D969G:&EJ:VQM;F]P<7(`\n`\n",
+        );
+    }
+
     #[test]
     fn default_tests() {
         tests::round_trip("uuencode");
@@ -1,7 +1,8 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
-#![cfg_attr(feature = "cargo-clippy", allow(clippy::manual_range_contains))]
+#![allow(clippy::manual_range_contains)]
 
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -9,9 +10,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::collections::BTreeMap;
-use std::io;
 use std::iter::Peekable;
 
 #[derive(PartialEq, Eq, Debug)]
@@ -304,33 +303,34 @@ impl VisTransformFactory {
 }
 
 impl CodecTransform for VisTransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
-            Direction::Forward => Ok(Encoder::new(&s.args).into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(Decoder::new().into_bufread(r, s.bufsize)),
+            Direction::Forward => Ok(Some(Box::new(Encoder::new(&s.args)))),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new()))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert(
-            "cstyle".to_string(),
-            tr!("encode using C-like escape sequences"),
-        );
-        map.insert(
-            "glob".to_string(),
-            tr!("encode characters recognized by glob(3) and hash mark"),
-        );
-        map.insert("nl".to_string(), tr!("encode newline"));
-        map.insert(
-            "octal".to_string(),
-            tr!("encode using octal escape sequences"),
-        );
-        map.insert("sp".to_string(), tr!("encode space"));
-        map.insert("space".to_string(), tr!("encode space"));
-        map.insert("tab".to_string(), tr!("encode tab"));
-        map.insert("white".to_string(), tr!("encode space, tab, and newline"));
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("cstyle", tr!("encode using C-like escape sequences"))
+            .direction(Direction::Forward)
+            .flag(
+                "glob",
+                tr!("encode characters recognized by glob(3) and hash mark"),
+            )
+            .direction(Direction::Forward)
+            .flag("nl", tr!("encode newline"))
+            .direction(Direction::Forward)
+            .flag("octal", tr!("encode using octal escape sequences"))
+            .direction(Direction::Forward)
+            .flag("sp", tr!("encode space"))
+            .direction(Direction::Forward)
+            .flag("space", tr!("encode space"))
+            .direction(Direction::Forward)
+            .flag("tab", tr!("encode tab"))
+            .direction(Direction::Forward)
+            .flag("white", tr!("encode space, tab, and newline"))
+            .direction(Direction::Forward)
     }
 
     fn can_reverse(&self) -> bool {
@@ -351,10 +351,10 @@ impl Codec for Encoder {
     fn transform(&mut self, inp: &[u8], outp: &mut [u8], f: FlushState) -> Result<Status, Error> {
         assert!(self.table.len() == 256);
 
-        let max = outp.len() - 4;
         let maxin = inp.len();
         let (mut i, mut j) = (0, 0);
-        while i < maxin && j < max {
+        // Each byte is written as at most four bytes.
+        while i < maxin && j + 4 <= outp.len() {
             let x = inp[i];
             // If this is a NUL byte and we're in cstyle-mode,…
             let sl: &[u8] = if x == b'\0' && self.cstyle {
@@ -613,7 +613,10 @@ impl Codec for Decoder {
             }
 
             match *val {
-                b'\\' => match self.handle_escape(i, &mut iter, &mut dst[j..], flush)? {
+                b'\\' => match self
+                    .handle_escape(i, &mut iter, &mut dst[j..], flush)
+                    .map_err(|e| e.at(i))?
+                {
                     Status::Ok(_, b) => j += b,
                     Status::SeqError(a, b) => return Ok(Status::SeqError(a, j + b)),
                     Status::StreamEnd(a, b) => return Ok(Status::StreamEnd(a, j + b)),
@@ -635,6 +638,10 @@ impl Codec for Decoder {
     fn buffer_size(&self) -> usize {
         1
     }
+
+    fn line_oriented(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -647,8 +654,12 @@ mod tests {
     fn check(options: &[&'static str], inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
         let optstring = options.join(",");
-        let name = format!("vis{}{}", if optstring == "" { "" } else { "," }, optstring);
-        for i in vec![4, 5, 6, 7, 8, 512] {
+        let name = format!(
+            "vis{}{}",
+            if optstring.is_empty() { "" } else { "," },
+            optstring
+        );
+        for &i in &[4, 5, 6, 7, 8, 512] {
             let c = Chain::new(&reg, &name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, "-vis", i, true);
@@ -666,7 +677,7 @@ mod tests {
                     let c = Chain::new(&reg, "-vis", i, b);
                     match c.transform($inp.to_vec()) {
                         Ok(_) => panic!("got success for invalid sequence"),
-                        Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                        Err(e) => match tests::codec_error(&e) {
                             Some(&$x) => (),
                             Some(e) => panic!("got wrong error: {:?}", e),
                             None => panic!("No internal error?"),
@@ -686,9 +697,9 @@ mod tests {
         check(&[], b"\x00\xff", b"\\^@\\M^?");
         check(&["cstyle"], b"^^\0", b"^^\\0");
         check(&["cstyle"], b"\0/", b"\\0/");
-        check(&["cstyle"], b"\00", b"\\0000");
-        check(&["cstyle"], b"\01", b"\\0001");
-        check(&["cstyle"], b"\07", b"\\0007");
+        check(&["cstyle"], b"\x000", b"\\0000");
+        check(&["cstyle"], b"\x001", b"\\0001");
+        check(&["cstyle"], b"\x007", b"\\0007");
         check(&["cstyle"], b"\08", b"\\08");
         check(
             &["cstyle"],
@@ -711,6 +722,11 @@ mod tests {
         check_failure!(b"abc\\00a", Error::InvalidSequence(_, _));
     }
 
+    #[test]
+    fn encodes_within_buffer_size() {
+        tests::check_buffer_size("vis", b"a\x00\xff", b"a\\^@\\M^?");
+    }
+
     #[test]
     fn default_tests() {
         tests::round_trip("vis");
@@ -1,6 +1,8 @@
 #![allow(unknown_lints)]
 #![allow(bare_trait_objects)]
+#![allow(clippy::manual_div_ceil)]
 
+use codec::schema::{OptionKind, OptionSchema};
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -8,10 +10,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::cmp;
-use std::collections::BTreeMap;
-use std::io;
 
 #[derive(Default)]
 pub struct TransformFactory {}
@@ -23,23 +22,27 @@ impl TransformFactory {
 }
 
 impl CodecTransform for TransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
-        let linelen = s
-            .length_arg("length", 1, Some(80))?
-            .ok_or_else(|| Error::InvalidArgument("wrap".to_string(), "length".to_string()))?;
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
-            Direction::Forward => Ok(Encoder::new(linelen).into_bufread(r, s.bufsize)),
-            Direction::Reverse => Ok(Decoder::new(s.strict).into_bufread(r, s.bufsize)),
+            Direction::Forward => {
+                let linelen = s
+                    .int_arg("length")?
+                    .ok_or_else(|| Error::MissingArgument("length".to_string()))?;
+                Ok(Some(Box::new(Encoder::new(linelen))))
+            }
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new(s.strict)))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert(
-            "length".to_string(),
-            tr!("wrap at specified line length (default 80)"),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .option(
+                "length",
+                OptionKind::Integer { min: 1, max: None },
+                tr!("wrap at specified line length (default 80)"),
+            )
+            .default_value("80")
+            .direction(Direction::Forward)
     }
 
     fn can_reverse(&self) -> bool {
@@ -133,6 +136,11 @@ impl Codec for Encoder {
     fn buffer_size(&self) -> usize {
         2
     }
+
+    fn reset(&mut self) -> bool {
+        self.curline = 0;
+        true
+    }
 }
 
 pub struct Decoder {}
@@ -161,6 +169,14 @@ impl Codec for Decoder {
     fn buffer_size(&self) -> usize {
         1
     }
+
+    fn line_oriented(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -173,7 +189,7 @@ mod tests {
     fn check(name: &str, inp: &[u8], outp: &[u8], dec: &[u8]) {
         let reg = CodecRegistry::new();
         let reverse = format!("-{}", name);
-        for i in vec![6, 7, 8, 9, 10, 11, 512] {
+        for &i in &[6, 7, 8, 9, 10, 11, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, &reverse, i, true);
@@ -189,7 +205,7 @@ mod tests {
             let c = Chain::new(&reg, $inp, 512, false);
             match c.transform((0..32).collect()) {
                 Ok(_) => panic!("got success for invalid sequence"),
-                Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                Err(e) => match tests::codec_error(&e) {
                     Some(&$x) => (),
                     Some(e) => panic!("got wrong error: {:?}", e),
                     None => panic!("No internal error?"),
@@ -4,6 +4,7 @@
 #![allow(clippy::upper_case_acronyms)]
 
 use codec::helpers::codecs::StatelessEncoder;
+use codec::schema::OptionSchema;
 use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
@@ -11,10 +12,7 @@ use codec::Direction;
 use codec::Error;
 use codec::FlushState;
 use codec::Status;
-use codec::TransformableCodec;
 use std::char;
-use std::collections::BTreeMap;
-use std::io;
 use std::str;
 
 enum Characters {
@@ -72,7 +70,7 @@ impl XMLTransformFactory {
 }
 
 impl CodecTransform for XMLTransformFactory {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error> {
+    fn codec(&self, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
         match s.dir {
             Direction::Forward => {
                 let arr = if s.args.contains_key("hex") {
@@ -82,28 +80,27 @@ impl CodecTransform for XMLTransformFactory {
                 } else {
                     DEFAULT
                 };
-                Ok(StatelessEncoder::new(
+                Ok(Some(Box::new(StatelessEncoder::new(
                     move |inp, out| forward_transform(inp, out, &arr),
                     MAX_LEN,
-                )
-                .into_bufread(r, s.bufsize))
+                ))))
             }
-            Direction::Reverse => Ok(Decoder::new().into_bufread(r, s.bufsize)),
+            Direction::Reverse => Ok(Some(Box::new(Decoder::new()))),
         }
     }
 
-    fn options(&self) -> BTreeMap<String, String> {
-        let mut map = BTreeMap::new();
-        map.insert("default".to_string(), tr!("use XML entity names"));
-        map.insert(
-            "hex".to_string(),
-            tr!("use hexadecimal entity names for XML entities"),
-        );
-        map.insert(
-            "html".to_string(),
-            tr!("use HTML-friendly entity names for XML entities"),
-        );
-        map
+    fn schema(&self) -> OptionSchema {
+        OptionSchema::new()
+            .flag("default", tr!("use XML entity names"))
+            .direction(Direction::Forward)
+            .flag("hex", tr!("use hexadecimal entity names for XML entities"))
+            .direction(Direction::Forward)
+            .flag(
+                "html",
+                tr!("use HTML-friendly entity names for XML entities"),
+            )
+            .direction(Direction::Forward)
+            .exclusive(&["default", "hex", "html"])
     }
 
     fn can_reverse(&self) -> bool {
@@ -167,20 +164,23 @@ impl Codec for Decoder {
                     if src.len() == name.len() + i + 1 {
                         match f {
                             FlushState::None => return Ok(Status::SeqError(i, j)),
-                            FlushState::Finish => return Err(Error::TruncatedData),
+                            FlushState::Finish => return Err(Error::TruncatedData.at(i)),
                         }
                     }
+                    let invalid = |name| Error::InvalidSequence("xml".to_string(), name).at(i);
                     if name.len() < 2 {
-                        return Err(Error::InvalidSequence("xml".to_string(), name));
+                        return Err(invalid(name));
                     }
                     j += match (name[0], name[1]) {
                         (b'#', b'x') | (b'#', b'X') => {
                             if name.len() < 3 {
-                                return Err(Error::InvalidSequence("xml".to_string(), name));
+                                return Err(invalid(name));
                             }
-                            Self::process_char(&name[2..], &mut dst[j..], 16)?
+                            Self::process_char(&name[2..], &mut dst[j..], 16)
+                                .map_err(|e| e.at(i))?
                         }
-                        (b'#', _) => Self::process_char(&name[1..], &mut dst[j..], 10)?,
+                        (b'#', _) => Self::process_char(&name[1..], &mut dst[j..], 10)
+                            .map_err(|e| e.at(i))?,
                         _ => {
                             match name.as_slice() {
                                 b"lt" => dst[j] = b'<',
@@ -188,7 +188,7 @@ impl Codec for Decoder {
                                 b"apos" => dst[j] = b'\'',
                                 b"quot" => dst[j] = b'"',
                                 b"amp" => dst[j] = b'&',
-                                _ => return Err(Error::InvalidSequence("xml".to_string(), name)),
+                                _ => return Err(invalid(name)),
                             };
                             1
                         }
@@ -221,7 +221,7 @@ mod tests {
 
     fn check(name: &str, inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![10, 11, 12, 512] {
+        for &i in &[10, 11, 12, 512] {
             let c = Chain::new(&reg, name, i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, "-xml", i, true);
@@ -233,7 +233,7 @@ mod tests {
 
     fn check_decode(inp: &[u8], outp: &[u8]) {
         let reg = CodecRegistry::new();
-        for i in vec![10, 11, 12, 512] {
+        for &i in &[10, 11, 12, 512] {
             let c = Chain::new(&reg, "-xml", i, true);
             assert_eq!(c.transform(inp.to_vec()).unwrap(), outp);
             let c = Chain::new(&reg, "-xml", i, false);
@@ -249,7 +249,7 @@ mod tests {
                     let c = Chain::new(&reg, "-xml", i, b);
                     match c.transform($inp.to_vec()) {
                         Ok(_) => panic!("got success for invalid sequence"),
-                        Err(e) => match e.get_ref().unwrap().downcast_ref::<Error>() {
+                        Err(e) => match tests::codec_error(&e) {
                             Some(&$x) => (),
                             Some(e) => panic!("got wrong error: {:?}", e),
                             None => panic!("No internal error?"),
@@ -300,4 +300,15 @@ mod tests {
         check_failure!(b"&#x2", Error::TruncatedData);
         check_failure!(b"&#2", Error::TruncatedData);
     }
+
+    #[test]
+    fn reports_position() {
+        let reg = CodecRegistry::new();
+        for &i in &[10, 11, 12, 512] {
+            let c = Chain::new(&reg, "-xml", i, true);
+            let err = c.transform(b"abc&lt;abc&abc;".to_vec()).unwrap_err();
+            let pos = tests::error_position(&err).unwrap();
+            assert_eq!(pos.offset, 10);
+        }
+    }
 }
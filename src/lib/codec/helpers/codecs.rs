@@ -1,9 +1,14 @@
+#![allow(clippy::manual_div_ceil)]
+
+use codec::helpers::simd::{self, Ranges};
 use codec::{Codec, Error, FlushState, Status};
 use std::cmp;
 
 pub struct StatelessEncoder<F> {
     f: F,
     bufsize: usize,
+    // The number of input bytes which produce a given number of output bytes, if fixed.
+    ratio: Option<(usize, usize)>,
 }
 
 impl<F> StatelessEncoder<F>
@@ -11,7 +16,20 @@ where
     F: Fn(&[u8], &mut [u8]) -> (usize, usize),
 {
     pub fn new(f: F, bufsize: usize) -> Self {
-        StatelessEncoder { f, bufsize }
+        StatelessEncoder {
+            f,
+            bufsize,
+            ratio: None,
+        }
+    }
+
+    /// Specify that every `inp` bytes of input produce exactly `out` bytes of output, so that the
+    /// size of the output can be computed in advance.
+    pub fn with_ratio(self, inp: usize, out: usize) -> Self {
+        StatelessEncoder {
+            ratio: Some((inp, out)),
+            ..self
+        }
     }
 }
 
@@ -36,6 +54,18 @@ where
     fn buffer_size(&self) -> usize {
         self.bufsize
     }
+
+    fn max_output_size(&self, len: usize) -> Option<usize> {
+        self.ratio.map(|(i, o)| (len + i - 1) / i * o)
+    }
+
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) -> bool {
+        true
+    }
 }
 
 pub struct PaddedEncoder<T> {
@@ -140,6 +170,25 @@ where
     fn buffer_size(&self) -> usize {
         self.osize
     }
+
+    fn max_output_size(&self, len: usize) -> Option<usize> {
+        // The final partial unit is encoded as a complete one, less any padding we omit.
+        let size = self
+            .enc
+            .max_output_size((len + self.isize - 1) / self.isize * self.isize)?;
+        match (self.pad, len % self.isize) {
+            (None, rem) if rem > 0 => Some(size - self.pad_bytes_needed(rem)),
+            _ => Some(size),
+        }
+    }
+
+    fn parallel_safe(&self) -> bool {
+        self.enc.parallel_safe()
+    }
+
+    fn reset(&mut self) -> bool {
+        self.enc.reset()
+    }
 }
 
 pub struct PaddedDecoder<T> {
@@ -245,6 +294,14 @@ impl<T: Codec + FilteredDecoder> Codec for PaddedDecoder<T> {
     fn buffer_size(&self) -> usize {
         self.osize
     }
+
+    fn max_output_size(&self, len: usize) -> Option<usize> {
+        self.codec.max_output_size(len)
+    }
+
+    fn reset(&mut self) -> bool {
+        self.codec.reset()
+    }
 }
 
 pub struct ChunkedDecoder {
@@ -253,6 +310,7 @@ pub struct ChunkedDecoder {
     outsize: usize,
     name: &'static str,
     table: &'static [i8; 256],
+    ranges: Option<Ranges>,
 }
 
 impl ChunkedDecoder {
@@ -269,6 +327,7 @@ impl ChunkedDecoder {
             outsize,
             name,
             table,
+            ranges: Ranges::new(table),
         }
     }
 }
@@ -313,8 +372,13 @@ impl FilteredDecoder for ChunkedDecoder {
     ) -> Result<Status, Error> {
         let (is, os) = (self.inpsize, self.outsize);
         let n = cmp::min(inp.len() / is, outp.len() / os);
-        for (i, j) in (0..n).map(|x| (x * is, x * os)) {
-            self.process_chunk(&inp[i..i + is], &mut outp[j..j + os])?;
+        let start = match self.ranges {
+            Some(ref r) => simd::decode(inp, outp, os * 8 / is, r).0 / is,
+            None => 0,
+        };
+        for (i, j) in (start..n).map(|x| (x * is, x * os)) {
+            self.process_chunk(&inp[i..i + is], &mut outp[j..j + os])
+                .map_err(|e| e.at(i))?;
         }
 
         match f {
@@ -340,6 +404,15 @@ impl Codec for ChunkedDecoder {
     fn buffer_size(&self) -> usize {
         self.outsize
     }
+
+    fn max_output_size(&self, len: usize) -> Option<usize> {
+        // A final partial chunk produces a complete one, which a padded decoder may trim.
+        Some((len + self.inpsize - 1) / self.inpsize * self.outsize)
+    }
+
+    fn reset(&mut self) -> bool {
+        true
+    }
 }
 
 pub struct AffixEncoder<T> {
@@ -416,6 +489,12 @@ impl<T: Codec> Codec for AffixEncoder<T> {
     fn buffer_size(&self) -> usize {
         cmp::max(self.prefix.len(), self.suffix.len())
     }
+
+    fn reset(&mut self) -> bool {
+        self.start = false;
+        self.end = false;
+        self.codec.reset()
+    }
 }
 
 /// A trait to help implement non-strict decoding.
@@ -451,6 +530,14 @@ pub trait FilteredDecoder {
             Ok(Status::SeqError(a, b)) => Ok(Status::SeqError(offsets[a], b)),
             Ok(Status::BufError(a, b)) => Ok(Status::BufError(offsets[a], b)),
             Ok(Status::StreamEnd(a, b)) => Ok(Status::StreamEnd(offsets[a], b)),
+            // Report the error at the unfiltered offset of the filtered byte it was found at.
+            Err(Error::AtOffset(pos, e)) => {
+                let at = pos.offset as usize;
+                match offsets.get(at + 1) {
+                    Some(&off) => Err((*e).at(off - 1)),
+                    None => Err((*e).at(offsets[offsets.len() - 1])),
+                }
+            }
             Err(e) => Err(e),
         }
     }
@@ -486,8 +573,9 @@ pub trait FilteredDecoder {
 #[cfg(test)]
 mod tests {
     use super::{AffixEncoder, PaddedDecoder, PaddedEncoder, StatelessEncoder};
-    use codec::{Codec, Error, FlushState, Status};
+    use codec::{Codec, CodecWriter, Error, FlushState, Status};
     use std::cmp;
+    use std::io::Write;
 
     // Test objects.
     pub struct TestCodec {}
@@ -632,4 +720,25 @@ mod tests {
             assert_eq!(&buf[0..j], outp);
         }
     }
+
+    #[test]
+    fn affix_works_with_writer() {
+        for &size in &[2, 3, 4, 512] {
+            let mut w = CodecWriter::new(Vec::new(), affix_encoder(), size);
+            for chunk in b"abcdefghijklmnopqrstuvwxyz".chunks(5) {
+                w.write_all(chunk).unwrap();
+            }
+            assert_eq!(
+                w.finish().unwrap(),
+                b"<~abcdefghijklmnopqrstuvwxyz~>".to_vec()
+            );
+
+            let mut out = Vec::new();
+            {
+                let mut w = CodecWriter::new(&mut out, affix_encoder(), size);
+                w.write_all(b"abc").unwrap();
+            }
+            assert_eq!(out, b"<~abc~>");
+        }
+    }
 }
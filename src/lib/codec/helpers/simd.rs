@@ -0,0 +1,762 @@
+//! Vectorized kernels for the base64, base32, and hex families of codecs.
+//!
+//! Each kernel handles as many whole blocks as fit in its input and output and returns the number
+//! of bytes it consumed and produced; the caller finishes the remainder with its table-driven
+//! code, which is also the portable fallback.  Decoding stops before the first block containing a
+//! byte outside the alphabet, so that the table-driven code reports any error exactly as before.
+
+// Constant thread-local initializers need a newer Rust than we support.
+#![allow(unknown_lints)]
+#![allow(clippy::missing_const_for_thread_local)]
+
+use std::cell::Cell;
+
+/// The instruction set used for the kernels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Portable,
+    Sse41,
+    Avx2,
+}
+
+thread_local! {
+    static FORCED: Cell<Option<Level>> = Cell::new(None);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detect() -> Level {
+    if is_x86_feature_detected!("avx2") {
+        Level::Avx2
+    } else if is_x86_feature_detected!("sse4.1") {
+        Level::Sse41
+    } else {
+        Level::Portable
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn detect() -> Level {
+    Level::Portable
+}
+
+/// The level to use on this thread: the best one this CPU supports, unless set by `with_level`.
+pub fn level() -> Level {
+    FORCED.with(Cell::get).unwrap_or_else(detect)
+}
+
+/// All the levels supported by this CPU.
+pub fn levels() -> Vec<Level> {
+    match detect() {
+        Level::Avx2 => vec![Level::Portable, Level::Sse41, Level::Avx2],
+        Level::Sse41 => vec![Level::Portable, Level::Sse41],
+        Level::Portable => vec![Level::Portable],
+    }
+}
+
+/// Run `f` with the kernels on this thread limited to `level`, for testing.
+pub fn with_level<T, F: FnOnce() -> T>(level: Level, f: F) -> T {
+    FORCED.with(|l| l.set(Some(level)));
+    let r = f();
+    FORCED.with(|l| l.set(None));
+    r
+}
+
+const MAX_RANGES: usize = 8;
+
+/// An alphabet for decoding, as runs of consecutive bytes which decode to consecutive values.
+#[derive(Clone, Copy)]
+pub struct Ranges {
+    // The first byte, the number of bytes, and the value of the first byte of each run.
+    runs: [(u8, u8, u8); MAX_RANGES],
+    len: usize,
+}
+
+impl Ranges {
+    /// Compute the ranges for a reverse lookup table, if it is simple enough to be vectorized.
+    pub fn new(table: &[i8; 256]) -> Option<Ranges> {
+        let mut r = Ranges {
+            runs: [(0, 0, 0); MAX_RANGES],
+            len: 0,
+        };
+        for (c, &v) in table.iter().enumerate() {
+            if v < 0 {
+                continue;
+            }
+            // The comparisons are signed, so the bytes around each run must be ASCII as well.
+            if c == 0 || c >= 0x7f {
+                return None;
+            }
+            let (c, v) = (c as u8, v as u8);
+            if r.len > 0 {
+                let last = &mut r.runs[r.len - 1];
+                if last.0 + last.1 == c && last.2 + last.1 == v {
+                    last.1 += 1;
+                    continue;
+                }
+            }
+            if r.len == MAX_RANGES {
+                return None;
+            }
+            r.runs[r.len] = (c, 1, v);
+            r.len += 1;
+        }
+        Some(r)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64", test))]
+    fn runs(&self) -> &[(u8, u8, u8)] {
+        &self.runs[..self.len]
+    }
+}
+
+/// Encode whole blocks of `inp` into `out` with `table`, which has 16, 32, or 64 entries.
+pub fn encode(inp: &[u8], out: &mut [u8], table: &[u8]) -> (usize, usize) {
+    match level() {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Avx2 => unsafe { x86::encode_avx2(inp, out, table) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Sse41 => unsafe { x86::encode_sse41(inp, out, table) },
+        _ => (0, 0),
+    }
+}
+
+/// Decode whole blocks of `inp` into `out`, where each character of `ranges` carries `bits` bits.
+pub fn decode(inp: &[u8], out: &mut [u8], bits: usize, ranges: &Ranges) -> (usize, usize) {
+    match level() {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Avx2 => unsafe { x86::decode_avx2(inp, out, bits, ranges) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Sse41 => unsafe { x86::decode_sse41(inp, out, bits, ranges) },
+        _ => (0, 0),
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::{Ranges, MAX_RANGES};
+    use std::cmp;
+
+    // Bytes in a shuffle which produce zero.
+    const Z: u8 = 0x80;
+
+    /// The operations the kernels need, all of which work independently on each 128-bit lane.
+    ///
+    /// The kernels are written once in terms of this trait and instantiated inside functions
+    /// compiled for each instruction set, into which these are always inlined.
+    trait Vector: Copy {
+        const LANES: usize;
+        const MASK: u32;
+
+        unsafe fn splat(x: u8) -> Self;
+        unsafe fn splat16(x: u16) -> Self;
+        unsafe fn splat32(x: u32) -> Self;
+        /// Load the same 16 bytes into each lane.
+        unsafe fn lane(x: &[u8]) -> Self;
+        /// Load 16 bytes into each lane, with the lanes starting `stride` bytes apart.
+        unsafe fn load(src: &[u8], stride: usize) -> Self;
+        /// Store each lane as 16 bytes, with the lanes starting `stride` bytes apart.
+        unsafe fn store(self, dst: &mut [u8], stride: usize);
+        unsafe fn and(self, other: Self) -> Self;
+        unsafe fn or(self, other: Self) -> Self;
+        unsafe fn add8(self, other: Self) -> Self;
+        unsafe fn cmpeq8(self, other: Self) -> Self;
+        unsafe fn cmpgt8(self, other: Self) -> Self;
+        /// Choose the bytes of `other` where `mask` is set and those of `self` elsewhere.
+        unsafe fn blend(self, other: Self, mask: Self) -> Self;
+        /// Look up each byte of `idx` in the 16 bytes of the corresponding lane of `self`.
+        unsafe fn shuffle(self, idx: Self) -> Self;
+        unsafe fn mulhi16(self, other: Self) -> Self;
+        unsafe fn mullo16(self, other: Self) -> Self;
+        unsafe fn maddubs(self, other: Self) -> Self;
+        unsafe fn madd(self, other: Self) -> Self;
+        unsafe fn packus16(self, other: Self) -> Self;
+        unsafe fn unpacklo8(self, other: Self) -> Self;
+        unsafe fn unpackhi8(self, other: Self) -> Self;
+        unsafe fn movemask(self) -> u32;
+    }
+
+    #[derive(Clone, Copy)]
+    struct Sse(__m128i);
+
+    impl Vector for Sse {
+        const LANES: usize = 1;
+        const MASK: u32 = 0xffff;
+
+        #[inline(always)]
+        unsafe fn splat(x: u8) -> Self {
+            Sse(_mm_set1_epi8(x as i8))
+        }
+
+        #[inline(always)]
+        unsafe fn splat16(x: u16) -> Self {
+            Sse(_mm_set1_epi16(x as i16))
+        }
+
+        #[inline(always)]
+        unsafe fn splat32(x: u32) -> Self {
+            Sse(_mm_set1_epi32(x as i32))
+        }
+
+        #[inline(always)]
+        unsafe fn lane(x: &[u8]) -> Self {
+            debug_assert_eq!(x.len(), 16);
+            Sse(_mm_loadu_si128(x.as_ptr() as *const __m128i))
+        }
+
+        #[inline(always)]
+        unsafe fn load(src: &[u8], _stride: usize) -> Self {
+            debug_assert!(src.len() >= 16);
+            Sse(_mm_loadu_si128(src.as_ptr() as *const __m128i))
+        }
+
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [u8], _stride: usize) {
+            debug_assert!(dst.len() >= 16);
+            _mm_storeu_si128(dst.as_mut_ptr() as *mut __m128i, self.0)
+        }
+
+        #[inline(always)]
+        unsafe fn and(self, other: Self) -> Self {
+            Sse(_mm_and_si128(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn or(self, other: Self) -> Self {
+            Sse(_mm_or_si128(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn add8(self, other: Self) -> Self {
+            Sse(_mm_add_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn cmpeq8(self, other: Self) -> Self {
+            Sse(_mm_cmpeq_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn cmpgt8(self, other: Self) -> Self {
+            Sse(_mm_cmpgt_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn blend(self, other: Self, mask: Self) -> Self {
+            Sse(_mm_blendv_epi8(self.0, other.0, mask.0))
+        }
+
+        #[inline(always)]
+        unsafe fn shuffle(self, idx: Self) -> Self {
+            Sse(_mm_shuffle_epi8(self.0, idx.0))
+        }
+
+        #[inline(always)]
+        unsafe fn mulhi16(self, other: Self) -> Self {
+            Sse(_mm_mulhi_epu16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn mullo16(self, other: Self) -> Self {
+            Sse(_mm_mullo_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn maddubs(self, other: Self) -> Self {
+            Sse(_mm_maddubs_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn madd(self, other: Self) -> Self {
+            Sse(_mm_madd_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn packus16(self, other: Self) -> Self {
+            Sse(_mm_packus_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn unpacklo8(self, other: Self) -> Self {
+            Sse(_mm_unpacklo_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn unpackhi8(self, other: Self) -> Self {
+            Sse(_mm_unpackhi_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn movemask(self) -> u32 {
+            _mm_movemask_epi8(self.0) as u32
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Avx(__m256i);
+
+    impl Vector for Avx {
+        const LANES: usize = 2;
+        const MASK: u32 = 0xffff_ffff;
+
+        #[inline(always)]
+        unsafe fn splat(x: u8) -> Self {
+            Avx(_mm256_set1_epi8(x as i8))
+        }
+
+        #[inline(always)]
+        unsafe fn splat16(x: u16) -> Self {
+            Avx(_mm256_set1_epi16(x as i16))
+        }
+
+        #[inline(always)]
+        unsafe fn splat32(x: u32) -> Self {
+            Avx(_mm256_set1_epi32(x as i32))
+        }
+
+        #[inline(always)]
+        unsafe fn lane(x: &[u8]) -> Self {
+            debug_assert_eq!(x.len(), 16);
+            Avx(_mm256_broadcastsi128_si256(_mm_loadu_si128(
+                x.as_ptr() as *const __m128i
+            )))
+        }
+
+        #[inline(always)]
+        unsafe fn load(src: &[u8], stride: usize) -> Self {
+            debug_assert!(src.len() >= stride + 16);
+            let lo = _mm_loadu_si128(src.as_ptr() as *const __m128i);
+            let hi = _mm_loadu_si128(src.as_ptr().add(stride) as *const __m128i);
+            Avx(_mm256_inserti128_si256(_mm256_castsi128_si256(lo), hi, 1))
+        }
+
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [u8], stride: usize) {
+            debug_assert!(dst.len() >= stride + 16);
+            let p = dst.as_mut_ptr();
+            _mm_storeu_si128(p as *mut __m128i, _mm256_castsi256_si128(self.0));
+            _mm_storeu_si128(
+                p.add(stride) as *mut __m128i,
+                _mm256_extracti128_si256(self.0, 1),
+            );
+        }
+
+        #[inline(always)]
+        unsafe fn and(self, other: Self) -> Self {
+            Avx(_mm256_and_si256(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn or(self, other: Self) -> Self {
+            Avx(_mm256_or_si256(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn add8(self, other: Self) -> Self {
+            Avx(_mm256_add_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn cmpeq8(self, other: Self) -> Self {
+            Avx(_mm256_cmpeq_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn cmpgt8(self, other: Self) -> Self {
+            Avx(_mm256_cmpgt_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn blend(self, other: Self, mask: Self) -> Self {
+            Avx(_mm256_blendv_epi8(self.0, other.0, mask.0))
+        }
+
+        #[inline(always)]
+        unsafe fn shuffle(self, idx: Self) -> Self {
+            Avx(_mm256_shuffle_epi8(self.0, idx.0))
+        }
+
+        #[inline(always)]
+        unsafe fn mulhi16(self, other: Self) -> Self {
+            Avx(_mm256_mulhi_epu16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn mullo16(self, other: Self) -> Self {
+            Avx(_mm256_mullo_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn maddubs(self, other: Self) -> Self {
+            Avx(_mm256_maddubs_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn madd(self, other: Self) -> Self {
+            Avx(_mm256_madd_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn packus16(self, other: Self) -> Self {
+            Avx(_mm256_packus_epi16(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn unpacklo8(self, other: Self) -> Self {
+            Avx(_mm256_unpacklo_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn unpackhi8(self, other: Self) -> Self {
+            Avx(_mm256_unpackhi_epi8(self.0, other.0))
+        }
+
+        #[inline(always)]
+        unsafe fn movemask(self) -> u32 {
+            _mm256_movemask_epi8(self.0) as u32
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn encode_avx2(inp: &[u8], out: &mut [u8], table: &[u8]) -> (usize, usize) {
+        encode::<Avx>(inp, out, table)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn encode_sse41(inp: &[u8], out: &mut [u8], table: &[u8]) -> (usize, usize) {
+        encode::<Sse>(inp, out, table)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn decode_avx2(
+        inp: &[u8],
+        out: &mut [u8],
+        bits: usize,
+        ranges: &Ranges,
+    ) -> (usize, usize) {
+        decode::<Avx>(inp, out, bits, ranges)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn decode_sse41(
+        inp: &[u8],
+        out: &mut [u8],
+        bits: usize,
+        ranges: &Ranges,
+    ) -> (usize, usize) {
+        decode::<Sse>(inp, out, bits, ranges)
+    }
+
+    /// The number of bytes a vector touches when each lane covers `unit` bytes of a buffer.
+    #[inline(always)]
+    fn span<V: Vector>(unit: usize) -> usize {
+        unit * (V::LANES - 1) + cmp::max(unit, 16)
+    }
+
+    /// Call `f` on successive blocks of `is` input and `os` output bytes per lane until the buffers
+    /// run out or `f` declines a block.
+    #[inline(always)]
+    unsafe fn blocks<V: Vector, F: FnMut(&[u8], &mut [u8]) -> bool>(
+        inp: &[u8],
+        out: &mut [u8],
+        is: usize,
+        os: usize,
+        mut f: F,
+    ) -> (usize, usize) {
+        let (ispan, ospan) = (span::<V>(is), span::<V>(os));
+        let (mut i, mut j) = (0, 0);
+        while i + ispan <= inp.len() && j + ospan <= out.len() {
+            if !f(&inp[i..i + ispan], &mut out[j..j + ospan]) {
+                break;
+            }
+            i += is * V::LANES;
+            j += os * V::LANES;
+        }
+        (i, j)
+    }
+
+    /// Look up each byte of `idx` in a table split into 16-byte pieces.
+    #[inline(always)]
+    unsafe fn lookup<V: Vector>(tables: &[V], idx: V) -> V {
+        let mut res = tables[0].shuffle(idx);
+        if tables.len() > 1 {
+            let piece = idx.mulhi16(V::splat16(1 << 12)).and(V::splat(0x0f));
+            for (k, t) in tables.iter().enumerate().skip(1) {
+                res = res.blend(t.shuffle(idx), piece.cmpeq8(V::splat(k as u8)));
+            }
+        }
+        res
+    }
+
+    #[inline(always)]
+    unsafe fn encode<V: Vector>(inp: &[u8], out: &mut [u8], table: &[u8]) -> (usize, usize) {
+        let mut tables = [V::splat(0); 4];
+        for (t, piece) in tables.iter_mut().zip(table.chunks(16)) {
+            *t = V::lane(piece);
+        }
+        let tables = &tables[..table.len() / 16];
+        match table.len() {
+            16 => {
+                let nibble = V::splat(0x0f);
+                let shift = V::splat16(1 << 12);
+                blocks::<V, _>(inp, out, 16, 32, |src, dst| {
+                    let x = V::load(src, 16);
+                    let hi = lookup(tables, x.mulhi16(shift).and(nibble));
+                    let lo = lookup(tables, x.and(nibble));
+                    hi.unpacklo8(lo).store(dst, 32);
+                    hi.unpackhi8(lo).store(&mut dst[16..], 32);
+                    true
+                })
+            }
+            32 => {
+                // Each lane holds two groups of five bytes.  For each character, take the two
+                // bytes holding its bits as a big-endian word and shift its bits to the top five
+                // bits of the word.
+                let first = V::lane(&[1, 0, 1, 0, 2, 1, 2, 1, 3, 2, 4, 3, 4, 3, 4, 3]);
+                let second = V::lane(&[6, 5, 6, 5, 7, 6, 7, 6, 8, 7, 9, 8, 9, 8, 9, 8]);
+                let align = V::lane(&[1, 0, 32, 0, 4, 0, 128, 0, 16, 0, 2, 0, 64, 0, 0, 8]);
+                let shift = V::splat16(1 << 5);
+                blocks::<V, _>(inp, out, 10, 16, |src, dst| {
+                    let x = V::load(src, 10);
+                    let a = x.shuffle(first).mullo16(align).mulhi16(shift);
+                    let b = x.shuffle(second).mullo16(align).mulhi16(shift);
+                    lookup(tables, a.packus16(b)).store(dst, 16);
+                    true
+                })
+            }
+            64 => {
+                // Each lane holds four groups of three bytes, spread so that each 32-bit word
+                // holds one group, and then each 6-bit value is moved into its own byte.
+                let spread = V::lane(&[1, 0, 2, 1, 4, 3, 5, 4, 7, 6, 8, 7, 10, 9, 11, 10]);
+                let (mask_ac, mul_ac) = (V::splat32(0x0fc0_fc00), V::splat32(0x0400_0040));
+                let (mask_bd, mul_bd) = (V::splat32(0x003f_03f0), V::splat32(0x0100_0010));
+                blocks::<V, _>(inp, out, 12, 16, |src, dst| {
+                    let x = V::load(src, 12).shuffle(spread);
+                    let ac = x.and(mask_ac).mulhi16(mul_ac);
+                    let bd = x.and(mask_bd).mullo16(mul_bd);
+                    lookup(tables, ac.or(bd)).store(dst, 16);
+                    true
+                })
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Convert each byte of `x` to its value, or return `None` if any byte is not in `ranges`.
+    #[inline(always)]
+    unsafe fn translate<V: Vector>(ranges: &[(V, V, V)], x: V) -> Option<V> {
+        let (mut vals, mut valid) = (V::splat(0), V::splat(0));
+        for &(below, above, offset) in ranges {
+            let m = x.cmpgt8(below).and(above.cmpgt8(x));
+            vals = vals.or(m.and(x.add8(offset)));
+            valid = valid.or(m);
+        }
+        if valid.movemask() == V::MASK {
+            Some(vals)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn decode<V: Vector>(
+        inp: &[u8],
+        out: &mut [u8],
+        bits: usize,
+        ranges: &Ranges,
+    ) -> (usize, usize) {
+        let zero = V::splat(0);
+        let mut rs = [(zero, zero, zero); MAX_RANGES];
+        for (r, &(first, len, value)) in rs.iter_mut().zip(ranges.runs()) {
+            *r = (
+                V::splat(first - 1),
+                V::splat(first + len),
+                V::splat(value.wrapping_sub(first)),
+            );
+        }
+        let rs = &rs[..ranges.runs().len()];
+        match bits {
+            4 => {
+                let pairs = V::splat16(0x0110);
+                let pack = V::lane(&[0, 2, 4, 6, 8, 10, 12, 14, Z, Z, Z, Z, Z, Z, Z, Z]);
+                blocks::<V, _>(inp, out, 16, 8, |src, dst| {
+                    match translate(rs, V::load(src, 16)) {
+                        Some(v) => {
+                            v.maddubs(pairs).shuffle(pack).store(dst, 8);
+                            true
+                        }
+                        None => false,
+                    }
+                })
+            }
+            5 => {
+                // Combine each group of eight values into two words of 20 bits, the first shifted
+                // up by four bits so that both words can be split into bytes with shuffles.
+                let pairs = V::splat16(0x0120);
+                let quads = V::lane(&[
+                    0x00, 0x40, 0x10, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x40, 0x10, 0x00, 0x00,
+                    0x04, 0x01, 0x00,
+                ]);
+                let hi = V::lane(&[2, 1, 0, Z, Z, 10, 9, 8, Z, Z, Z, Z, Z, Z, Z, Z]);
+                let lo = V::lane(&[Z, Z, 6, 5, 4, Z, Z, 14, 13, 12, Z, Z, Z, Z, Z, Z]);
+                blocks::<V, _>(inp, out, 16, 10, |src, dst| {
+                    match translate(rs, V::load(src, 16)) {
+                        Some(v) => {
+                            let x = v.maddubs(pairs).madd(quads);
+                            x.shuffle(hi).or(x.shuffle(lo)).store(dst, 10);
+                            true
+                        }
+                        None => false,
+                    }
+                })
+            }
+            6 => {
+                let pairs = V::splat32(0x0140_0140);
+                let quads = V::splat32(0x0001_1000);
+                let pack = V::lane(&[2, 1, 0, 6, 5, 4, 10, 9, 8, 14, 13, 12, Z, Z, Z, Z]);
+                blocks::<V, _>(inp, out, 16, 12, |src, dst| {
+                    match translate(rs, V::load(src, 16)) {
+                        Some(v) => {
+                            v.maddubs(pairs).madd(quads).shuffle(pack).store(dst, 12);
+                            true
+                        }
+                        None => false,
+                    }
+                })
+            }
+            _ => (0, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, levels, with_level, Level, Ranges};
+    use codec::codecs::{base32, base64, hex};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 167 + i / 256) as u8).collect()
+    }
+
+    // Encode with the kernels and finish with a simple table lookup, as the codecs do.
+    fn encode_at(level: Level, inp: &[u8], table: &[u8], is: usize, os: usize) -> Vec<u8> {
+        let bits = 8 * is / os;
+        let mut out = vec![0u8; inp.len() / is * os];
+        let (a, b) = with_level(level, || encode(inp, &mut out, table));
+        assert_eq!(a / is * os, b);
+        for (c, o) in inp[a..].chunks(is).zip(out[b..].chunks_mut(os)) {
+            let x = c.iter().fold(0u64, |acc, &v| (acc << 8) | u64::from(v));
+            for (k, v) in o.iter_mut().enumerate() {
+                *v = table[(x >> ((os - 1 - k) * bits)) as usize & ((1 << bits) - 1)];
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn computes_ranges() {
+        let r = Ranges::new(&base64::REV).unwrap();
+        assert_eq!(
+            r.runs(),
+            &[
+                (b'+', 1, 62),
+                (b'/', 1, 63),
+                (b'0', 10, 52),
+                (b'=', 1, 0),
+                (b'A', 26, 0),
+                (b'a', 26, 26)
+            ]
+        );
+        let r = Ranges::new(&hex::REV).unwrap();
+        assert_eq!(r.runs(), &[(b'0', 10, 0), (b'A', 6, 10), (b'a', 6, 10)]);
+        let mut table = [-1i8; 256];
+        table[0x80] = 0;
+        assert!(Ranges::new(&table).is_none());
+        let mut table = [-1i8; 256];
+        for i in 0..9 {
+            table[0x20 + i * 2] = i as i8;
+        }
+        assert!(Ranges::new(&table).is_none());
+    }
+
+    #[test]
+    fn encodes_identically_at_all_levels() {
+        let tables: &[(&[u8], usize, usize)] = &[
+            (&base64::BASE64, 3, 4),
+            (&base64::URL64, 3, 4),
+            (&base32::BASE32, 5, 8),
+            (&base32::BASE32HEX, 5, 8),
+            (&hex::LOWER, 1, 2),
+            (&hex::UPPER, 1, 2),
+            (&hex::MODHEX, 1, 2),
+        ];
+        for &(table, is, os) in tables {
+            for len in (0..300).map(|n| n * is) {
+                let inp = data(len);
+                let expected = encode_at(Level::Portable, &inp, table, is, os);
+                for &level in &levels() {
+                    assert_eq!(
+                        encode_at(level, &inp, table, is, os),
+                        expected,
+                        "{:?} {} bytes",
+                        level,
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_identically_at_all_levels() {
+        let tables: &[(&[u8], &[i8; 256], usize, usize)] = &[
+            (&base64::BASE64, &base64::REV, 3, 4),
+            (&base64::URL64, &base64::URLREV, 3, 4),
+            (&base32::BASE32, &base32::REV, 5, 8),
+            (&base32::BASE32HEX, &base32::REVHEX, 5, 8),
+            (&hex::LOWER, &hex::REV, 1, 2),
+            (&hex::UPPER, &hex::REV, 1, 2),
+            (&hex::MODHEX, &hex::MODHEXREV, 1, 2),
+        ];
+        for &(table, rev, is, os) in tables {
+            let ranges = Ranges::new(rev).unwrap();
+            let bits = 8 * is / os;
+            // Inputs that end just before, on, and just after the kernels' block boundaries.
+            for &len in &[3, 4, 5, 7, 8, 9, 15, 16, 17, 31, 32, 33] {
+                let inp = data(len * is);
+                let encoded = encode_at(Level::Portable, &inp, table, is, os);
+                for &level in &levels() {
+                    let mut out = vec![0u8; inp.len()];
+                    let (a, b) = with_level(level, || decode(&encoded, &mut out, bits, &ranges));
+                    assert_eq!(a / os * is, b);
+                    assert_eq!(&out[..b], &inp[..b], "{:?} {} bytes", level, len);
+                }
+            }
+
+            let inp = data(150 * is);
+            let encoded = encode_at(Level::Portable, &inp, table, is, os);
+            for &level in &levels() {
+                let mut out = vec![0u8; inp.len()];
+                let (a, b) = with_level(level, || decode(&encoded, &mut out, bits, &ranges));
+                assert_eq!(a / os * is, b);
+                assert_eq!(&out[..b], &inp[..b], "{:?}", level);
+                if level != Level::Portable {
+                    assert!(a >= encoded.len() - 64, "{:?} stopped at {}", level, a);
+                }
+
+                // The kernels must stop before any block containing an invalid byte.
+                for &pos in &[0, 1, 15, 16, 17, 63, 100, 200] {
+                    let mut bad = encoded.clone();
+                    bad[pos] = b'!';
+                    let (a, b) = with_level(level, || decode(&bad, &mut out, bits, &ranges));
+                    assert!(a <= pos, "{:?} decoded past {} to {}", level, pos, a);
+                    assert_eq!(&out[..b], &inp[..b], "{:?}", level);
+                }
+            }
+        }
+    }
+}
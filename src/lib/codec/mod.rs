@@ -1,34 +1,69 @@
 #![allow(clippy::upper_case_acronyms)]
 
+#[cfg(feature = "async")]
+pub mod asyncio;
 pub mod codecs;
 pub mod helpers;
+pub mod parallel;
 pub mod registry;
+pub mod schema;
 pub mod tests;
 
 use std;
+use std::cmp;
 use std::collections::BTreeMap;
 use std::convert;
 use std::error;
 use std::fmt;
 use std::io;
 use std::io::prelude::*;
-use std::ops;
+
+use codec::parallel::ParallelReader;
 
 pub const DEFAULT_BUFFER_SIZE: usize = 65536;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     IO(io::Error),
     InvalidSequence(String, Vec<u8>),
     TruncatedData,
     ExtraData,
     ForwardOnly(String),
+    ReverseOnly(String),
     UnknownCodec(String),
     MissingArgument(String),
     UnknownArgument(String),
     InvalidArgument(String, String),
     IncompatibleParameters(String, String),
     SmallBuffer,
+    InvalidAlias(String, String),
+    /// A codec produced more output than the given limit allows.
+    OutputLimitExceeded(u64),
+    /// An error in the data at the given position in a codec's input.
+    AtOffset(Position, Box<Error>),
+}
+
+/// A position in the input to a codec.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// The zero-based byte offset.
+    pub offset: u64,
+    /// The one-based line and column (in bytes), for codecs whose input is made up of lines.
+    pub line: Option<(u64, u64)>,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some((line, col)) => write!(
+                f,
+                "{}",
+                tr!("byte {} (line {}, column {})", self.offset, line, col)
+            ),
+            None => write!(f, "{}", tr!("byte {}", self.offset)),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -46,6 +81,9 @@ impl fmt::Display for Error {
             Error::ForwardOnly(ref name) => {
                 write!(f, "{}", tr!("no reverse transform for {}", name))
             }
+            Error::ReverseOnly(ref name) => {
+                write!(f, "{}", tr!("no forward transform for {}", name))
+            }
             Error::MissingArgument(ref name) => {
                 write!(f, "{}", tr!("missing argument for {}", name))
             }
@@ -64,6 +102,13 @@ impl fmt::Display for Error {
             Error::SmallBuffer => {
                 write!(f, "{}", tr!("buffer is too small to make forward progress"))
             }
+            Error::InvalidAlias(ref name, ref msg) => {
+                write!(f, "{}", tr!("invalid alias '{}': {}", name, msg))
+            }
+            Error::OutputLimitExceeded(limit) => {
+                write!(f, "{}", tr!("output exceeds the limit of {} bytes", limit))
+            }
+            Error::AtOffset(pos, ref e) => write!(f, "{}", tr!("at {}: {}", pos, e)),
         }
     }
 }
@@ -72,6 +117,7 @@ impl error::Error for Error {
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             Error::IO(ref e) => Some(e),
+            Error::AtOffset(_, ref e) => Some(e.as_ref()),
             _ => None,
         }
     }
@@ -82,12 +128,44 @@ impl error::Error for Error {
     }
 }
 
+impl Error {
+    /// Record that this error occurred at `offset` in the input passed to `Codec::transform`.
+    ///
+    /// If the error already has an offset within a part of the input starting at `offset`, the
+    /// two are added, so that helpers can place errors relative to the data they are given.
+    fn at(self, offset: usize) -> Self {
+        let (offset, e) = match self {
+            Error::AtOffset(pos, e) => (pos.offset + offset as u64, e),
+            e => (offset as u64, Box::new(e)),
+        };
+        Error::AtOffset(Position { offset, line: None }, e)
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::IO(ref e) => e.kind(),
+            Error::InvalidSequence(_, _) | Error::OutputLimitExceeded(_) => {
+                io::ErrorKind::InvalidData
+            }
+            Error::AtOffset(_, ref e) => e.kind(),
+            _ => io::ErrorKind::InvalidInput,
+        }
+    }
+
+    /// Returns the error, ignoring any offset at which it occurred.
+    pub fn without_offset(&self) -> &Error {
+        match *self {
+            Error::AtOffset(_, ref e) => e.without_offset(),
+            _ => self,
+        }
+    }
+}
+
 impl convert::From<Error> for io::Error {
     fn from(err: Error) -> Self {
         match err {
             Error::IO(e) => e,
-            Error::InvalidSequence(_, _) => io::Error::new(io::ErrorKind::InvalidData, err),
-            _ => io::Error::new(io::ErrorKind::InvalidInput, err),
+            _ => io::Error::new(err.kind(), err),
         }
     }
 }
@@ -106,6 +184,14 @@ impl Direction {
             Direction::Reverse => Direction::Forward,
         }
     }
+    /// Returns this direction as applied within a context operating in the direction `outer`.
+    #[must_use]
+    pub fn compose(self, outer: Direction) -> Self {
+        match outer {
+            Direction::Forward => self,
+            Direction::Reverse => self.invert(),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -142,21 +228,134 @@ pub enum FlushState {
     None,
 }
 
-pub trait CodecTransform {
-    fn factory(&self, r: Box<io::BufRead>, s: CodecSettings) -> Result<Box<io::BufRead>, Error>;
-    fn options(&self) -> BTreeMap<String, String>;
+/// Defines the default `factory` or `factory_send`, which run the transform's codec over the
+/// reader and differ only in whether the readers are `Send`.
+macro_rules! default_factory {
+    ($(#[$attr:meta])* $factory:ident, $into_bufread:ident $(, $bound:ident)*) => {
+        $(#[$attr])*
+        fn $factory(
+            &self,
+            r: Box<io::BufRead $(+ $bound)*>,
+            s: CodecSettings,
+        ) -> Result<Box<io::BufRead $(+ $bound)*>, Error> {
+            let bufsize = s.bufsize;
+            match self.codec(s.clone())? {
+                Some(ref c) if s.threads > 1 && c.parallel_safe() => {
+                    Ok(Box::new(parallel_reader(self, r, s)?))
+                }
+                Some(c) => Ok(c.$into_bufread(r, bufsize)),
+                None => panic!(
+                    "transform {} has neither a factory nor a codec",
+                    self.name()
+                ),
+            }
+        }
+    };
+}
+
+/// A transform which can be used in a chain.
+///
+/// A transform must implement at least one of `factory` and `codec`.  Transforms which are
+/// implemented by a single codec should implement `codec`, which allows them to be driven other
+/// than by a blocking reader; those which wrap other readers, such as the compression transforms,
+/// implement `factory` instead.
+pub trait CodecTransform: Send + Sync {
+    default_factory!(factory, into_bufread);
+    default_factory!(
+        /// As for `factory`, but for a reader which can be sent to another thread.
+        ///
+        /// Transforms which implement `factory` must implement this as well.
+        factory_send,
+        into_bufread_send,
+        Send
+    );
+    /// Returns the codec implementing this transform with the given settings, or `None` if it is
+    /// not implemented by a single codec.
+    fn codec(&self, _s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        Ok(None)
+    }
+    fn schema(&self) -> schema::OptionSchema;
+    fn options(&self) -> BTreeMap<String, String> {
+        self.schema().descriptions()
+    }
     fn can_reverse(&self) -> bool;
     fn name(&self) -> &'static str;
 }
 
+/// The smallest amount of input given to each thread at once when transforming in parallel, so
+/// that the cost of starting threads is small in comparison.
+const PARALLEL_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Returns a reader which transforms `r` with one instance of the transform's codec for each
+/// thread the settings allow.
+fn parallel_reader<T: CodecTransform + ?Sized, R: io::BufRead>(
+    t: &T,
+    r: R,
+    s: CodecSettings,
+) -> Result<ParallelReader<Box<Codec + Send>, R>, Error> {
+    let mut codecs = Vec::with_capacity(s.threads);
+    for _ in 0..s.threads {
+        match t.codec(s.clone())? {
+            Some(c) => codecs.push(c),
+            None => unreachable!(),
+        }
+    }
+    let block = cmp::max(s.bufsize, PARALLEL_BLOCK_SIZE);
+    Ok(ParallelReader::new(r, codecs, block))
+}
+
+/// The settings for a single transform.
+///
+/// More settings may be added in future, so this is created with `CodecSettings::new` and the
+/// `with_*` methods rather than directly.
+#[derive(Clone)]
+#[non_exhaustive]
 pub struct CodecSettings {
     pub bufsize: usize,
     pub strict: bool,
     pub args: BTreeMap<String, Option<String>>,
     pub dir: Direction,
+    /// The number of threads a codec may use.  Only codecs which are `Codec::parallel_safe` use
+    /// more than one.
+    pub threads: usize,
+    /// The most output a codec may produce, if it is limited.
+    pub max_output: Option<u64>,
 }
 
 impl CodecSettings {
+    /// Settings for a transform run in the direction `dir` with the given arguments, using a
+    /// single thread and with no limit on its output.
+    pub fn new(
+        bufsize: usize,
+        strict: bool,
+        args: BTreeMap<String, Option<String>>,
+        dir: Direction,
+    ) -> Self {
+        CodecSettings {
+            bufsize,
+            strict,
+            args,
+            dir,
+            threads: 1,
+            max_output: None,
+        }
+    }
+
+    /// Allow codecs which are `Codec::parallel_safe` to use up to `threads` threads.
+    #[must_use]
+    pub fn with_threads(self, threads: usize) -> Self {
+        CodecSettings { threads, ..self }
+    }
+
+    /// Fail once the codec has produced more than `limit` bytes of output.
+    #[must_use]
+    pub fn with_max_output(self, limit: u64) -> Self {
+        CodecSettings {
+            max_output: Some(limit),
+            ..self
+        }
+    }
+
     fn int_arg<T: std::str::FromStr + ToString + Ord + From<u8> + Copy>(
         &self,
         name: &str,
@@ -171,20 +370,16 @@ impl CodecSettings {
         }
     }
 
+    /// Returns the value of a length argument, where zero means no length.
     fn length_arg<T: std::str::FromStr + ToString + Ord + From<u8> + Copy>(
         &self,
         name: &str,
-        min: T,
-        default: Option<T>,
     ) -> Result<Option<T>, Error> {
         let zero: T = 0u8.into();
-        let val: Option<T> = match self.int_arg(name)? {
-            Some(x) if x == zero => None,
-            Some(x) if x >= min => Some(x),
-            Some(x) => return Err(Error::InvalidArgument(name.to_string(), x.to_string())),
-            None => default,
-        };
-        Ok(val)
+        match self.int_arg(name)? {
+            Some(x) if x == zero => Ok(None),
+            x => Ok(x),
+        }
     }
 
     fn bool_arg(&self, name: &str) -> Result<bool, Error> {
@@ -194,6 +389,36 @@ impl CodecSettings {
             None => Ok(false),
         }
     }
+
+    fn str_arg(&self, name: &str) -> Result<Option<&str>, Error> {
+        match self.args.get(name) {
+            Some(None) => Err(Error::MissingArgument(name.to_string())),
+            Some(Some(val)) => Ok(Some(val)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value of an argument written in hexadecimal.
+    fn hex_arg(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let val = match self.str_arg(name)? {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+        let invalid = || Error::InvalidArgument(name.to_string(), val.to_string());
+        if val.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        val.as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|s| u8::from_str_radix(s, 16).ok())
+                    .ok_or_else(invalid)
+            })
+            .collect::<Result<Vec<u8>, Error>>()
+            .map(Some)
+    }
 }
 
 pub trait Codec {
@@ -205,10 +430,77 @@ pub trait Codec {
     ) -> Result<Status, Error>;
     fn chunk_size(&self) -> usize;
     fn buffer_size(&self) -> usize;
+    /// Whether the input to this codec is made up of lines, so that errors should report the line
+    /// and column as well as the byte offset.
+    fn line_oriented(&self) -> bool {
+        false
+    }
+    /// Returns the largest number of bytes this codec can produce from `len` bytes of input, if
+    /// it is known.  For codecs which map fixed-size units of input to fixed-size units of
+    /// output, this is exact for valid input.
+    fn max_output_size(&self, _len: usize) -> Option<usize> {
+        None
+    }
+    /// Whether this codec keeps no state between units of `chunk_size` bytes, so that its input
+    /// can be split on any multiple of that size and each part transformed by a separate instance,
+    /// producing the same output.
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+    /// Return this codec to the state it was in when it was created, so that it can transform
+    /// another input, whether or not its last input was transformed successfully.
+    ///
+    /// Returns false if the codec cannot be reset, in which case it must not be used again.
+    fn reset(&mut self) -> bool {
+        false
+    }
+}
+
+impl<C: Codec + ?Sized> Codec for Box<C> {
+    fn transform(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: FlushState,
+    ) -> Result<Status, Error> {
+        (**self).transform(input, output, flush)
+    }
+
+    fn chunk_size(&self) -> usize {
+        (**self).chunk_size()
+    }
+
+    fn buffer_size(&self) -> usize {
+        (**self).buffer_size()
+    }
+
+    fn line_oriented(&self) -> bool {
+        (**self).line_oriented()
+    }
+
+    fn max_output_size(&self, len: usize) -> Option<usize> {
+        (**self).max_output_size(len)
+    }
+
+    fn parallel_safe(&self) -> bool {
+        (**self).parallel_safe()
+    }
+
+    fn reset(&mut self) -> bool {
+        (**self).reset()
+    }
 }
 
 pub trait TransformableCodec<'a, C> {
     fn into_bufread(self, r: Box<io::BufRead>, bufsize: usize) -> Box<io::BufRead + 'a>;
+    fn into_bufread_send(
+        self,
+        r: Box<io::BufRead + Send>,
+        bufsize: usize,
+    ) -> Box<io::BufRead + Send + 'a>
+    where
+        Self: Send;
+    fn into_writer<W: Write + 'a>(self, w: W, bufsize: usize) -> Box<io::Write + 'a>;
 }
 
 impl<'a, C> TransformableCodec<'a, C> for C
@@ -218,125 +510,275 @@ where
     fn into_bufread(self, r: Box<io::BufRead>, bufsize: usize) -> Box<io::BufRead + 'a> {
         Box::new(Transform::new(r, self, bufsize))
     }
+
+    fn into_bufread_send(
+        self,
+        r: Box<io::BufRead + Send>,
+        bufsize: usize,
+    ) -> Box<io::BufRead + Send + 'a>
+    where
+        Self: Send,
+    {
+        Box::new(Transform::new(r, self, bufsize))
+    }
+
+    fn into_writer<W: Write + 'a>(self, w: W, bufsize: usize) -> Box<io::Write + 'a> {
+        Box::new(CodecWriter::new(w, self, bufsize))
+    }
+}
+
+/// The position of the start of the unconsumed input to a codec.
+#[derive(Default)]
+struct InputPosition {
+    // The number of bytes of input the codec has consumed.
+    consumed: u64,
+    // The number of newlines consumed and the number of bytes since the last one.  These are only
+    // tracked for line-oriented codecs.
+    lines: u64,
+    col: u64,
+}
+
+impl InputPosition {
+    /// Returns the line and column counts after the given data.
+    fn count_lines(data: &[u8], lines: u64, col: u64) -> (u64, u64) {
+        match data.iter().rposition(|&b| b == b'\n') {
+            Some(i) => (
+                lines + data.iter().filter(|&&b| b == b'\n').count() as u64,
+                (data.len() - i - 1) as u64,
+            ),
+            None => (lines, col + data.len() as u64),
+        }
+    }
+
+    /// Record that the codec has consumed `data`.
+    fn advance(&mut self, data: &[u8], line_oriented: bool) {
+        if line_oriented {
+            let (lines, col) = Self::count_lines(data, self.lines, self.col);
+            self.lines = lines;
+            self.col = col;
+        }
+        self.consumed += data.len() as u64;
+    }
+
+    /// Returns the position of the byte at offset `at` in the unconsumed input `data`.
+    fn position(&self, data: &[u8], at: usize, line_oriented: bool) -> Position {
+        let line = if line_oriented {
+            let (lines, col) = Self::count_lines(&data[..at], self.lines, self.col);
+            Some((lines + 1, col + 1))
+        } else {
+            None
+        };
+        Position {
+            offset: self.consumed + at as u64,
+            line,
+        }
+    }
+
+    /// Attach the position of an error in the data to it, given the unconsumed input which was
+    /// passed to the codec.
+    ///
+    /// Errors which the codec has placed with `Error::at` are reported at that offset in the
+    /// input; other errors in the data are reported at the start of the input.
+    fn locate(&self, e: Error, data: &[u8], line_oriented: bool) -> Error {
+        let (at, e) = match e {
+            Error::AtOffset(pos, e) => (cmp::min(pos.offset, data.len() as u64) as usize, *e),
+            Error::InvalidSequence(_, _) | Error::TruncatedData | Error::ExtraData => (0, e),
+            _ => return e,
+        };
+        Error::AtOffset(self.position(data, at, line_oriented), Box::new(e))
+    }
 }
 
+/// A reader which runs a codec over the data from another reader and provides its output as a
+/// buffer.
+///
+/// The codec is run directly over the data in the underlying reader's buffer.  Input is only
+/// copied when the codec needs more data than that buffer holds, such as when a sequence spans the
+/// end of it, and then only until the codec has consumed the copied part.
 pub struct CodecReader<R: BufRead, C: Codec> {
     r: R,
     codec: C,
-    buf: Vec<u8>,
-    off: usize,
+    // Input which has been consumed from the reader but not yet by the codec.
+    carry: Vec<u8>,
+    cap: usize,
+    out: Vec<u8>,
+    // The range of `out` which has not yet been returned to the caller.
+    out_pos: usize,
+    out_len: usize,
+    pos: InputPosition,
+    eof: bool,
+    done: bool,
 }
 
 impl<R: BufRead, C: Codec> CodecReader<R, C> {
     fn new(r: R, c: C, bufsize: usize) -> Self {
+        // A codec may need to see the start of the next chunk before it can finish one.
+        let cap = cmp::max(bufsize, c.chunk_size() * 2);
+        let outsize = cmp::max(bufsize, c.buffer_size());
         CodecReader {
             r,
             codec: c,
-            buf: vec![0u8; bufsize],
-            off: 0,
+            carry: Vec::new(),
+            cap,
+            out: vec![0u8; outsize],
+            out_pos: 0,
+            out_len: 0,
+            pos: InputPosition::default(),
+            eof: false,
+            done: false,
         }
     }
 
-    #[cfg(rustc_1_37)]
-    fn memmove<B>(sl: &mut [u8], src: B, dest: usize)
-    where
-        B: ops::RangeBounds<usize> + IntoIterator<Item = usize>,
-    {
-        sl.copy_within(src, dest)
+    /// Interpret the result of the codec on `data`, returning the amount of input consumed and
+    /// output written.
+    fn check(
+        pos: &InputPosition,
+        ret: Result<Status, Error>,
+        data: &[u8],
+        flush: FlushState,
+        line_oriented: bool,
+    ) -> io::Result<(usize, usize)> {
+        match ret {
+            Ok(Status::BufError(_, 0)) => Err(io::Error::from(Error::SmallBuffer)),
+            Ok(Status::SeqError(0, _)) if flush == FlushState::Finish => {
+                Err(pos.locate(Error::TruncatedData, data, line_oriented).into())
+            }
+            Ok(st) => Ok(st.unpack()),
+            Err(e) => Err(pos.locate(e, data, line_oriented).into()),
+        }
     }
 
-    #[cfg(all(not(rustc_1_37), has_range_bounds))]
-    fn memmove<B>(sl: &mut [u8], src: B, dest: usize)
-    where
-        B: ops::RangeBounds<usize> + IntoIterator<Item = usize>,
-    {
-        match src.start_bound() {
-            ops::Bound::Included(&x) if x == dest => return,
-            _ => (),
+    /// Run the codec over the reader's buffer, returning the amount of output written.
+    fn transform_in_place(&mut self) -> io::Result<usize> {
+        let line_oriented = self.codec.line_oriented();
+        let (consumed, written, avail) = {
+            let data = if self.eof {
+                &[][..]
+            } else {
+                self.r.fill_buf()?
+            };
+            self.eof = data.is_empty();
+            let flush = if self.eof {
+                FlushState::Finish
+            } else {
+                FlushState::None
+            };
+            let ret = self.codec.transform(data, &mut self.out, flush);
+            let (consumed, written) = Self::check(&self.pos, ret, data, flush, line_oriented)?;
+            self.pos.advance(&data[..consumed], line_oriented);
+            (consumed, written, data.len())
         };
-        for (j, i) in src.into_iter().enumerate() {
-            sl[j] = sl[i + dest];
+        if consumed == 0 && written == 0 && !self.eof {
+            // The codec needs more data than the reader has buffered, so keep what there is and
+            // ask for more.
+            let data = self.r.fill_buf()?;
+            self.carry.extend_from_slice(data);
+            self.r.consume(avail);
+        } else {
+            self.r.consume(consumed);
         }
+        Ok(written)
     }
 
-    #[cfg(all(not(rustc_1_37), not(has_range_bounds)))]
-    fn memmove(sl: &mut [u8], src: ops::Range<usize>, dest: usize) {
-        for (j, i) in src.into_iter().enumerate() {
-            sl[j] = sl[i + dest];
+    /// Run the codec over the carried input, followed by as much of the reader's buffer as is
+    /// needed to make progress, returning the amount of output written.
+    fn transform_carried(&mut self) -> io::Result<usize> {
+        let line_oriented = self.codec.line_oriented();
+        let carried = self.carry.len();
+        if !self.eof {
+            // Only copy as much as we've already carried, so that the amount copied stays
+            // proportional to what the codec needs.
+            let want = cmp::max(carried, self.codec.chunk_size());
+            let data = self.r.fill_buf()?;
+            let n = cmp::min(cmp::min(data.len(), want), self.cap.saturating_sub(carried));
+            if data.is_empty() {
+                self.eof = true;
+            } else if n == 0 {
+                // The codec can make no progress even with a full buffer.
+                return Err(io::Error::from(Error::SmallBuffer));
+            }
+            self.carry.extend_from_slice(&data[..n]);
         }
-    }
-}
-
-// This function and associated types derived from the flate2 crate.
-impl<R: BufRead, C: Codec> Read for CodecReader<R, C> {
-    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
-        if dst.len() < self.codec.buffer_size() {
-            return Err(io::Error::from(Error::SmallBuffer));
+        let flush = if self.eof {
+            FlushState::Finish
+        } else {
+            FlushState::None
+        };
+        let ret = self.codec.transform(&self.carry, &mut self.out, flush);
+        let (consumed, written) = Self::check(&self.pos, ret, &self.carry, flush, line_oriented)?;
+        self.pos.advance(&self.carry[..consumed], line_oriented);
+        if consumed >= carried {
+            // The codec has used all of the carried data, so we can go back to using the reader's
+            // buffer directly.
+            self.r.consume(consumed - carried);
+            self.carry.clear();
+        } else if consumed == 0 && written == 0 && !self.eof {
+            // The codec needs still more data, so keep what we've copied.
+            let n = self.carry.len() - carried;
+            self.r.consume(n);
+        } else {
+            self.carry.truncate(carried);
+            self.carry.drain(..consumed);
         }
+        Ok(written)
+    }
 
-        let obj = &mut self.r;
-        loop {
-            let (ret, eof);
-            let last = {
-                let read = obj.read(&mut self.buf[self.off..])?;
-                let input = &self.buf[..self.off + read];
-                eof = read == 0;
-
-                let flush = if eof {
-                    FlushState::Finish
-                } else {
-                    FlushState::None
-                };
-                ret = self.codec.transform(input, dst, flush);
-                self.off + read
+    /// Run the codec until it produces some output or reaches the end of the data.
+    fn fill_output(&mut self) -> io::Result<()> {
+        while self.out_pos == self.out_len && !self.done {
+            let written = if self.carry.is_empty() {
+                self.transform_in_place()?
+            } else {
+                self.transform_carried()?
             };
+            self.out_pos = 0;
+            self.out_len = written;
+            self.done = self.eof && written == 0;
+        }
+        Ok(())
+    }
+}
 
-            match ret {
-                Ok(Status::Ok(consumed, _))
-                | Ok(Status::SeqError(consumed, _))
-                | Ok(Status::StreamEnd(consumed, _)) => {
-                    Self::memmove(&mut self.buf, consumed..last, 0);
-                    self.off = last - consumed;
-                }
-                _ => (),
-            }
+impl<R: BufRead, C: Codec> BufRead for CodecReader<R, C> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill_output()?;
+        Ok(&self.out[self.out_pos..self.out_len])
+    }
 
-            match ret {
-                // If we haven't ready any data and we haven't hit EOF yet,
-                // then we need to keep asking for more data because if we
-                // return that 0 bytes of data have been read then it will
-                // be interpreted as EOF.
-                Ok(Status::Ok(_, 0)) | Ok(Status::SeqError(_, 0)) if !eof && !dst.is_empty() => {
-                    continue
-                }
-                Ok(Status::BufError(_, 0)) => return Err(io::Error::from(Error::SmallBuffer)),
-                Ok(Status::SeqError(0, _)) if eof => {
-                    return Err(io::Error::from(Error::TruncatedData))
-                }
-                Ok(Status::Ok(_, read))
-                | Ok(Status::BufError(_, read))
-                | Ok(Status::SeqError(_, read))
-                | Ok(Status::StreamEnd(_, read)) => return Ok(read),
+    fn consume(&mut self, amt: usize) {
+        self.out_pos = cmp::min(self.out_pos + amt, self.out_len);
+    }
+}
 
-                Err(e) => return Err(io::Error::from(e)),
-            }
-        }
+impl<R: BufRead, C: Codec> Read for CodecReader<R, C> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = cmp::min(data.len(), dst.len());
+            dst[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
     }
 }
 
-pub struct Transform<C: Codec> {
-    b: io::BufReader<CodecReader<Box<io::BufRead>, C>>,
+/// A buffered reader which transforms the data from another reader with a codec.
+///
+/// This is `Send` if the reader and the codec are.
+pub struct Transform<C: Codec, R: BufRead = Box<io::BufRead>> {
+    b: CodecReader<R, C>,
 }
 
-impl<C: Codec> Transform<C> {
-    pub fn new(r: Box<io::BufRead>, c: C, bufsize: usize) -> Self {
+impl<C: Codec, R: BufRead> Transform<C, R> {
+    pub fn new(r: R, c: C, bufsize: usize) -> Self {
         Transform {
-            b: io::BufReader::with_capacity(bufsize, CodecReader::new(r, c, bufsize)),
+            b: CodecReader::new(r, c, bufsize),
         }
     }
 }
 
-impl<C: Codec> io::BufRead for Transform<C> {
+impl<C: Codec, R: BufRead> io::BufRead for Transform<C, R> {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
         self.b.fill_buf()
     }
@@ -346,8 +788,260 @@ impl<C: Codec> io::BufRead for Transform<C> {
     }
 }
 
-impl<C: Codec> io::Read for Transform<C> {
+impl<C: Codec, R: BufRead> io::Read for Transform<C, R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.b.read(buf)
     }
 }
+
+/// A codec which fails with `Error::OutputLimitExceeded` once another codec has produced more
+/// than a given number of bytes.
+pub struct LimitedCodec<C: Codec> {
+    codec: C,
+    limit: u64,
+    written: u64,
+}
+
+impl<C: Codec> LimitedCodec<C> {
+    pub fn new(codec: C, limit: u64) -> Self {
+        LimitedCodec {
+            codec,
+            limit,
+            written: 0,
+        }
+    }
+}
+
+impl<C: Codec> Codec for LimitedCodec<C> {
+    fn transform(&mut self, inp: &[u8], outp: &mut [u8], f: FlushState) -> Result<Status, Error> {
+        let st = self.codec.transform(inp, outp, f)?;
+        self.written += st.unpack().1 as u64;
+        if self.written > self.limit {
+            return Err(Error::OutputLimitExceeded(self.limit));
+        }
+        Ok(st)
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.codec.chunk_size()
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.codec.buffer_size()
+    }
+
+    fn line_oriented(&self) -> bool {
+        self.codec.line_oriented()
+    }
+
+    fn max_output_size(&self, len: usize) -> Option<usize> {
+        self.codec.max_output_size(len)
+    }
+
+    fn reset(&mut self) -> bool {
+        self.written = 0;
+        self.codec.reset()
+    }
+}
+
+/// A reader which fails with `Error::OutputLimitExceeded` if another reader provides more than a
+/// given number of bytes.
+pub struct LimitedReader<R: BufRead> {
+    r: R,
+    limit: u64,
+    read: u64,
+}
+
+impl<R: BufRead> LimitedReader<R> {
+    pub fn new(r: R, limit: u64) -> Self {
+        LimitedReader { r, limit, read: 0 }
+    }
+}
+
+impl<R: BufRead> io::BufRead for LimitedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let left = self.limit - self.read;
+        let data = self.r.fill_buf()?;
+        if left == 0 && !data.is_empty() {
+            return Err(Error::OutputLimitExceeded(self.limit).into());
+        }
+        Ok(&data[..cmp::min(data.len() as u64, left) as usize])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read += amt as u64;
+        self.r.consume(amt)
+    }
+}
+
+impl<R: BufRead> io::Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = cmp::min(data.len(), buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// Transform all of `inp` with `codec` at once, without any intermediate buffers.
+///
+/// The output is allocated at the size given by `Codec::max_output_size`, if there is one, and
+/// grown as needed otherwise.
+pub fn transform_slice<C: Codec + ?Sized>(codec: &mut C, inp: &[u8]) -> Result<Vec<u8>, Error> {
+    // Some codecs need more than their buffer size to make progress, so leave a little more
+    // space at the end of the output, as a reader's buffer would.
+    let reserve = cmp::max(codec.buffer_size(), 64);
+    let size = codec.max_output_size(inp.len()).unwrap_or(inp.len());
+    let mut out = vec![0u8; size + reserve];
+    let line_oriented = codec.line_oriented();
+    let mut pos = InputPosition::default();
+    let (mut ipos, mut opos) = (0, 0);
+    // As when reading, process as much of the input as possible before signalling the end of it.
+    let mut flush = FlushState::None;
+    loop {
+        if out.len() - opos < reserve {
+            let len = cmp::max(out.len() * 2, opos + reserve);
+            out.resize(len, 0);
+        }
+        let data = &inp[ipos..];
+        let ret = codec.transform(data, &mut out[opos..], flush);
+        let (consumed, written) = match ret {
+            Ok(Status::SeqError(0, _)) if flush == FlushState::Finish => {
+                return Err(pos.locate(Error::TruncatedData, data, line_oriented))
+            }
+            Ok(Status::BufError(0, 0)) => {
+                let len = out.len() * 2;
+                out.resize(len, 0);
+                continue;
+            }
+            Ok(st) => st.unpack(),
+            Err(e) => return Err(pos.locate(e, data, line_oriented)),
+        };
+        pos.advance(&data[..consumed], line_oriented);
+        ipos += consumed;
+        opos += written;
+        match ret {
+            Ok(Status::StreamEnd(_, _)) => break,
+            _ if consumed == 0 && written == 0 && flush == FlushState::None => {
+                flush = FlushState::Finish
+            }
+            _ if consumed == 0 && written == 0 => break,
+            _ => (),
+        }
+    }
+    out.truncate(opos);
+    Ok(out)
+}
+
+/// A writer which transforms the data written to it with a codec and writes the result to another
+/// writer.
+///
+/// The end of the input is signalled to the codec by `finish` or `try_finish`, or if neither has
+/// been called, when the writer is dropped, in which case any errors are ignored.
+pub struct CodecWriter<W: Write, C: Codec> {
+    w: Option<W>,
+    codec: C,
+    // Input which has been written but not yet consumed by the codec.
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    pos: InputPosition,
+    done: bool,
+}
+
+impl<W: Write, C: Codec> CodecWriter<W, C> {
+    pub fn new(w: W, c: C, bufsize: usize) -> Self {
+        CodecWriter {
+            w: Some(w),
+            codec: c,
+            buf: Vec::with_capacity(bufsize),
+            out: vec![0u8; bufsize],
+            pos: InputPosition::default(),
+            done: false,
+        }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.w.as_ref().unwrap()
+    }
+
+    /// Signal the end of the input to the codec and write all remaining output, without consuming
+    /// the writer.  Writing data afterwards is an error.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.process(FlushState::Finish)?;
+        self.done = true;
+        self.w.as_mut().unwrap().flush()
+    }
+
+    /// Signal the end of the input to the codec, write all remaining output, and return the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.w.take().unwrap())
+    }
+
+    /// Run the codec over the pending input until it can make no more progress.
+    fn process(&mut self, flush: FlushState) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        if self.out.len() < self.codec.buffer_size() {
+            return Err(io::Error::from(Error::SmallBuffer));
+        }
+        let line_oriented = self.codec.line_oriented();
+        loop {
+            let ret = self.codec.transform(&self.buf, &mut self.out, flush);
+            let (consumed, written) = match ret {
+                Ok(Status::SeqError(0, _)) if flush == FlushState::Finish => {
+                    return Err(self
+                        .pos
+                        .locate(Error::TruncatedData, &self.buf, line_oriented)
+                        .into())
+                }
+                Ok(Status::BufError(0, 0)) => return Err(io::Error::from(Error::SmallBuffer)),
+                Ok(st) => st.unpack(),
+                Err(e) => return Err(self.pos.locate(e, &self.buf, line_oriented).into()),
+            };
+            self.w.as_mut().unwrap().write_all(&self.out[..written])?;
+            self.pos.advance(&self.buf[..consumed], line_oriented);
+            self.buf.drain(..consumed);
+            match ret {
+                Ok(Status::StreamEnd(_, _)) => {
+                    self.done = true;
+                    return Ok(());
+                }
+                _ if consumed == 0 && written == 0 => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<W: Write, C: Codec> Write for CodecWriter<W, C> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Err(io::Error::from(Error::ExtraData));
+        }
+        // Accept at most a buffer's worth at a time so that the pending input stays small.
+        let n = cmp::min(data.len(), self.out.len());
+        self.buf.extend_from_slice(&data[..n]);
+        self.process(FlushState::None)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write, C: Codec> Drop for CodecWriter<W, C> {
+    fn drop(&mut self) {
+        if self.w.is_some() && !self.done {
+            let _ = self.try_finish();
+        }
+    }
+}
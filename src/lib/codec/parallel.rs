@@ -0,0 +1,354 @@
+#![allow(clippy::manual_div_ceil)]
+
+use codec::{transform_slice, Codec, Error, Position};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::ops::Range;
+use std::panic;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+/// A thread which transforms each block it is sent with its own instance of a codec.
+struct Worker {
+    jobs: mpsc::Sender<(Arc<Vec<u8>>, Range<usize>)>,
+    results: mpsc::Receiver<Result<Vec<u8>, Error>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new<C: Codec + Send + 'static>(mut codec: C) -> Self {
+        let (jobs, rx) = mpsc::channel::<(Arc<Vec<u8>>, Range<usize>)>();
+        let (tx, results) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            for (inp, range) in rx {
+                let res = transform_slice(&mut codec, &inp[range]);
+                // Release the input first, so that the reader can reuse its buffer.
+                drop(inp);
+                if tx.send(res).is_err() {
+                    break;
+                }
+            }
+        });
+        Worker {
+            jobs,
+            results,
+            handle: Some(handle),
+        }
+    }
+
+    /// Start transforming the given part of `inp`.
+    fn send(&self, inp: &Arc<Vec<u8>>, range: Range<usize>) {
+        // If the thread has panicked, `result` reports it.
+        let _ = self.jobs.send((inp.clone(), range));
+    }
+
+    /// Wait for the output of the block last sent, resuming any panic from the thread.
+    fn result(&mut self) -> Result<Vec<u8>, Error> {
+        match self.results.recv() {
+            Ok(res) => res,
+            Err(_) => match self.handle.take().map(|h| h.join()) {
+                Some(Err(e)) => panic::resume_unwind(e),
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+/// A buffered reader which transforms the data from another reader with several instances of a
+/// codec at once, each on its own thread.
+///
+/// The input is read in batches of one block per codec.  Each block except the last is a multiple
+/// of the codec's chunk size, so this only produces the same output as a `Transform` for codecs
+/// which are `Codec::parallel_safe`.  The output of each batch is provided in the order of the
+/// input.
+///
+/// The first codec runs on the thread reading; each of the others runs on a thread of its own,
+/// started the first time a batch needs it and fed a block at a time for as long as the reader
+/// lives.
+pub struct ParallelReader<C: Codec + Send + 'static, R: BufRead = Box<io::BufRead>> {
+    r: R,
+    codec: C,
+    workers: Vec<Worker>,
+    // The codecs whose threads have not been started yet.
+    idle: Vec<C>,
+    block: usize,
+    // Shared with the threads transforming it, so that each need not copy its block.
+    inp: Arc<Vec<u8>>,
+    // The output of each block of the current batch which has not been read yet.
+    out: VecDeque<Vec<u8>>,
+    out_pos: usize,
+    // The number of bytes of input transformed so far, for reporting errors.
+    consumed: u64,
+    done: bool,
+}
+
+impl<C: Codec + Send + 'static, R: BufRead> ParallelReader<C, R> {
+    /// Create a reader which gives about `blocksize` bytes of input at a time to each of `codecs`.
+    pub fn new(r: R, codecs: Vec<C>, blocksize: usize) -> Self {
+        let mut codecs = codecs.into_iter();
+        let codec = match codecs.next() {
+            Some(c) => c,
+            None => panic!("a parallel reader needs at least one codec"),
+        };
+        let chunk = codec.chunk_size();
+        let block = cmp::max(blocksize / chunk, 1) * chunk;
+        ParallelReader {
+            r,
+            codec,
+            workers: Vec::new(),
+            idle: codecs.rev().collect(),
+            block,
+            inp: Arc::new(Vec::new()),
+            out: VecDeque::new(),
+            out_pos: 0,
+            consumed: 0,
+            done: false,
+        }
+    }
+
+    /// Returns the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+
+    /// Read up to one block for each codec, returning true if the input has ended.
+    ///
+    /// If reading fails, such as when no more input is available yet, what has been read so far is
+    /// kept for the next attempt.
+    fn read_batch(&mut self) -> io::Result<bool> {
+        let want = self.block * (1 + self.workers.len() + self.idle.len());
+        let inp = Self::buffer(&mut self.inp);
+        while inp.len() < want {
+            let n = {
+                let data = self.r.fill_buf()?;
+                if data.is_empty() {
+                    return Ok(true);
+                }
+                let n = cmp::min(data.len(), want - inp.len());
+                inp.extend_from_slice(&data[..n]);
+                n
+            };
+            self.r.consume(n);
+        }
+        Ok(false)
+    }
+
+    /// Returns the input buffer, which no worker shares once it has sent its output.
+    fn buffer(inp: &mut Arc<Vec<u8>>) -> &mut Vec<u8> {
+        match Arc::get_mut(inp) {
+            Some(inp) => inp,
+            None => unreachable!(),
+        }
+    }
+
+    /// Adjust the position of an error in a block to be relative to the start of the input.
+    fn locate(e: Error, start: u64) -> Error {
+        match e {
+            Error::AtOffset(pos, e) => Error::AtOffset(
+                Position {
+                    offset: pos.offset + start,
+                    line: None,
+                },
+                e,
+            ),
+            e => e,
+        }
+    }
+
+    /// Transform one batch of input, using the current thread for the first block.
+    fn transform_batch(&mut self) -> io::Result<()> {
+        let block = self.block;
+        let len = self.inp.len();
+        let nblocks = (len + block - 1) / block;
+        if nblocks == 0 {
+            return Ok(());
+        }
+        while self.workers.len() < nblocks - 1 {
+            let codec = self.idle.pop().unwrap();
+            self.workers.push(Worker::new(codec));
+        }
+        let range = |i: usize| i * block..cmp::min(len, (i + 1) * block);
+        for (i, w) in (1..nblocks).zip(&self.workers) {
+            w.send(&self.inp, range(i));
+        }
+        let mut results = Vec::with_capacity(nblocks);
+        results.push(transform_slice(&mut self.codec, &self.inp[range(0)]));
+        for w in self.workers.iter_mut().take(nblocks - 1) {
+            results.push(w.result());
+        }
+
+        let mut err = None;
+        for (i, res) in results.into_iter().enumerate() {
+            match res {
+                Ok(out) if err.is_none() => self.out.push_back(out),
+                Ok(_) => (),
+                Err(e) => {
+                    if err.is_none() {
+                        err = Some(Self::locate(e, self.consumed + (i * block) as u64));
+                    }
+                }
+            }
+        }
+        self.consumed += len as u64;
+        Self::buffer(&mut self.inp).clear();
+        match err {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Transform batches until there is some output or the input has ended.
+    fn fill_output(&mut self) -> io::Result<()> {
+        loop {
+            match self.out.front() {
+                Some(out) if self.out_pos < out.len() => return Ok(()),
+                Some(_) => {
+                    self.out.pop_front();
+                    self.out_pos = 0;
+                }
+                None if self.done => return Ok(()),
+                None => {
+                    self.done = self.read_batch()?;
+                    self.transform_batch()?;
+                }
+            }
+        }
+    }
+}
+
+impl<C: Codec + Send + 'static, R: BufRead> BufRead for ParallelReader<C, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill_output()?;
+        match self.out.front() {
+            Some(out) => Ok(&out[self.out_pos..]),
+            None => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(out) = self.out.front() {
+            self.out_pos = cmp::min(self.out_pos + amt, out.len());
+        }
+    }
+}
+
+impl<C: Codec + Send + 'static, R: BufRead> Read for ParallelReader<C, R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = cmp::min(data.len(), dst.len());
+            dst[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParallelReader;
+    use codec::registry::CodecRegistry;
+    use codec::tests;
+    use codec::{Codec, CodecSettings, Direction, Error};
+    use std::collections::BTreeMap;
+    use std::io::{self, Read};
+
+    fn codecs(name: &str, args: &[&str], n: usize) -> Vec<Box<Codec + Send>> {
+        let reg = CodecRegistry::new();
+        let t = reg.get(name).unwrap();
+        let args: BTreeMap<_, _> = args.iter().map(|a| (a.to_string(), None)).collect();
+        (0..n)
+            .map(|_| {
+                let s = CodecSettings {
+                    bufsize: 512,
+                    strict: true,
+                    args: t
+                        .schema()
+                        .resolve(args.clone(), Direction::Forward)
+                        .unwrap(),
+                    dir: Direction::Forward,
+                    threads: 1,
+                    max_output: None,
+                };
+                t.codec(s).unwrap().unwrap()
+            })
+            .collect()
+    }
+
+    fn read_all(codecs: Vec<Box<Codec + Send>>, block: usize, inp: &[u8]) -> io::Result<Vec<u8>> {
+        let mut r = ParallelReader::new(io::BufReader::with_capacity(7, inp), codecs, block);
+        let mut out = Vec::new();
+        r.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn matches_single_threaded_output() {
+        let cases: &[(&str, &[&str])] = &[
+            ("base64", &[]),
+            ("base64", &["nopad"]),
+            ("base32", &[]),
+            ("hex", &["upper"]),
+            ("url64", &[]),
+            ("uri", &[]),
+            ("xml", &[]),
+            ("crlf", &[]),
+        ];
+        let inp: Vec<u8> = tests::BYTE_SEQ.iter().cycle().take(5000).cloned().collect();
+        for &(name, args) in cases {
+            let expected = read_all(codecs(name, args, 1), 1 << 20, &inp).unwrap();
+            for &threads in &[1, 2, 3, 8] {
+                for &block in &[1, 5, 16, 100, 4096] {
+                    for &len in &[0, 1, 2, 3, 4, 99, 100, 101, 1000, 5000] {
+                        let expected = match len {
+                            5000 => expected.clone(),
+                            _ => read_all(codecs(name, args, 1), 1 << 20, &inp[..len]).unwrap(),
+                        };
+                        assert_eq!(
+                            read_all(codecs(name, args, threads), block, &inp[..len]).unwrap(),
+                            expected,
+                            "{} {:?} threads {} block {} length {}",
+                            name,
+                            args,
+                            threads,
+                            block,
+                            len
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reports_position_in_whole_input() {
+        // Decoding is not parallel-safe in general, but hex decoding is in strict mode, which
+        // lets us check that errors are located relative to the start of the input.
+        let reg = CodecRegistry::new();
+        let t = reg.get("hex").unwrap();
+        let codecs: Vec<Box<Codec + Send>> = (0..3)
+            .map(|_| {
+                let s = CodecSettings {
+                    bufsize: 512,
+                    strict: true,
+                    args: BTreeMap::new(),
+                    dir: Direction::Reverse,
+                    threads: 1,
+                    max_output: None,
+                };
+                t.codec(s).unwrap().unwrap()
+            })
+            .collect();
+        let mut inp = b"00".repeat(100);
+        inp[151] = b'x';
+        let err = read_all(codecs, 20, &inp).unwrap_err();
+        assert_eq!(tests::error_position(&err).unwrap().offset, 150);
+        match tests::codec_error(&err) {
+            Some(Error::InvalidSequence(_, seq)) => assert_eq!(seq, b"0x"),
+            _ => panic!("wrong error: {:?}", err),
+        }
+    }
+}
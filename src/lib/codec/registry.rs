@@ -1,16 +1,56 @@
+use chain::Chain;
 use codec;
+use codec::Codec;
 use codec::CodecSettings;
 use codec::CodecTransform;
+use codec::Direction;
 use codec::Error;
+use codec::LimitedCodec;
+use codec::LimitedReader;
 use std::collections::btree_map;
 use std::collections::BTreeMap;
 use std::io;
 
 type Iter<'a> = btree_map::Iter<'a, &'static str, Box<CodecTransform>>;
+type AliasIter<'a> = btree_map::Iter<'a, String, String>;
 
 #[derive(Default)]
 pub struct CodecRegistry {
     map: BTreeMap<&'static str, Box<CodecTransform>>,
+    aliases: BTreeMap<String, String>,
+}
+
+/// Defines `create` or `create_send`, which are the same but for the bounds on their readers.
+macro_rules! create_fn {
+    ($(#[$attr:meta])* $create:ident, $factory:ident, $build:ident $(, $bound:ident)*) => {
+        $(#[$attr])*
+        pub fn $create(
+            &self,
+            name: &str,
+            r: Box<io::BufRead $(+ $bound)*>,
+            s: CodecSettings,
+        ) -> Result<Box<io::BufRead $(+ $bound)*>, Error> {
+            let limit = s.max_output;
+            let r = match (self.map.get(name), self.aliases.get(name)) {
+                (Some(t), _) => {
+                    if s.dir == Direction::Reverse && !t.can_reverse() {
+                        return Err(Error::ForwardOnly(String::from(name)));
+                    }
+                    let s = CodecSettings {
+                        args: t.schema().resolve(s.args, s.dir)?,
+                        ..s
+                    };
+                    t.$factory(r, s)?
+                }
+                (None, Some(chain)) => self.alias(chain, &s).$build(r).map_err(Error::IO)?,
+                (None, None) => return Err(Error::UnknownCodec(String::from(name))),
+            };
+            match limit {
+                Some(limit) => Ok(Box::new(LimitedReader::new(r, limit))),
+                None => Ok(r),
+            }
+        }
+    };
 }
 
 impl CodecRegistry {
@@ -37,10 +77,20 @@ impl CodecRegistry {
             "base64",
             Box::new(codec::codecs::base64::Base64TransformFactory::new()),
         );
+        #[cfg(feature = "brotli")]
+        map.insert(
+            "brotli",
+            Box::new(codec::codecs::compress::BrotliTransformFactory::new()),
+        );
         map.insert(
             "bubblebabble",
             Box::new(codec::codecs::bubblebabble::TransformFactory::new()),
         );
+        #[cfg(feature = "bzip2")]
+        map.insert(
+            "bzip2",
+            Box::new(codec::codecs::compress::Bzip2TransformFactory::new()),
+        );
         map.insert(
             "checksum",
             Box::new(codec::codecs::checksum::TransformFactory::new()),
@@ -49,6 +99,10 @@ impl CodecRegistry {
             "crlf",
             Box::new(codec::codecs::crlf::TransformFactory::new()),
         );
+        map.insert(
+            "decompress",
+            Box::new(codec::codecs::compress::DecompressTransformFactory::new()),
+        );
         map.insert(
             "deflate",
             Box::new(codec::codecs::compress::DeflateTransformFactory::new()),
@@ -71,6 +125,17 @@ impl CodecRegistry {
             Box::new(codec::codecs::identity::TransformFactory::new()),
         );
         map.insert("lf", Box::new(codec::codecs::lf::TransformFactory::new()));
+        map.insert("lzw", Box::new(codec::codecs::lzw::TransformFactory::new()));
+        #[cfg(feature = "lz4")]
+        map.insert(
+            "lz4",
+            Box::new(codec::codecs::compress::Lz4TransformFactory::new()),
+        );
+        #[cfg(feature = "xz")]
+        map.insert(
+            "lzma",
+            Box::new(codec::codecs::compress::LzmaTransformFactory::new()),
+        );
         map.insert(
             "modhex",
             Box::new(codec::codecs::hex::ModHexTransformFactory::new()),
@@ -107,12 +172,25 @@ impl CodecRegistry {
             "xml",
             Box::new(codec::codecs::xml::XMLTransformFactory::new()),
         );
+        #[cfg(feature = "xz")]
+        map.insert(
+            "xz",
+            Box::new(codec::codecs::compress::XzTransformFactory::new()),
+        );
         map.insert(
             "zlib",
             Box::new(codec::codecs::compress::ZlibTransformFactory::new()),
         );
+        #[cfg(feature = "zstd")]
+        map.insert(
+            "zstd",
+            Box::new(codec::codecs::compress::ZstdTransformFactory::new()),
+        );
 
-        CodecRegistry { map }
+        CodecRegistry {
+            map,
+            aliases: BTreeMap::new(),
+        }
     }
 
     pub fn insert(&mut self, k: &'static str, f: Box<CodecTransform>) {
@@ -123,22 +201,208 @@ impl CodecRegistry {
         self.map.iter()
     }
 
-    pub fn create(
+    pub fn get(&self, name: &str) -> Option<&CodecTransform> {
+        self.map.get(name).map(|t| t.as_ref())
+    }
+
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.aliases.contains_key(name)
+    }
+
+    pub fn aliases(&self) -> AliasIter<'_> {
+        self.aliases.iter()
+    }
+
+    /// Define `name` as an alias for `chain`.
+    ///
+    /// The alias may be used like a codec, including being reversed.  Arguments passed to the
+    /// alias are substituted for references of the form `$name` in the chain.  An alias may not
+    /// have the same name as a codec or another alias, nor may it refer to itself.
+    pub fn insert_alias(&mut self, name: &str, chain: &str) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::InvalidAlias(name.to_string(), msg));
+        if name.is_empty()
+            || name.starts_with('-')
+            || name
+                .chars()
+                .any(|c| c.is_whitespace() || ":,(){}|='\"\\$".contains(c))
+        {
+            return invalid(tr!("not a valid name"));
+        }
+        if self.map.contains_key(name) {
+            return invalid(tr!("conflicts with a built-in codec"));
+        }
+        if self.aliases.contains_key(name) {
+            return invalid(tr!("defined more than once"));
+        }
+        if let Err(e) = Chain::transform_names(chain) {
+            return invalid(e.to_string());
+        }
+        self.aliases.insert(name.to_string(), chain.to_string());
+        if self.alias_cycle(name, &mut Vec::new()) {
+            self.aliases.remove(name);
+            return invalid(tr!("refers to itself"));
+        }
+        Ok(())
+    }
+
+    /// Load aliases from a file.
+    ///
+    /// Each line contains a name, an equals sign, and a chain.  Blank lines and lines starting
+    /// with `#` are ignored.  Errors in an alias include the number of the line it is on.
+    pub fn load_aliases<R: io::BufRead>(&mut self, r: R) -> Result<(), Error> {
+        for (i, line) in r.lines().enumerate() {
+            let line = line.map_err(Error::IO)?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(off) => self
+                    .insert_alias(line[..off].trim(), line[off + 1..].trim())
+                    .map_err(|e| match e {
+                        Error::InvalidAlias(name, msg) => {
+                            Error::InvalidAlias(name, tr!("line {}: {}", i + 1, msg))
+                        }
+                        e => e,
+                    })?,
+                None => {
+                    return Err(Error::InvalidAlias(
+                        line.to_string(),
+                        tr!(
+                            "line {}: expected a name and a chain separated by '='",
+                            i + 1
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn alias_cycle(&self, name: &str, seen: &mut Vec<String>) -> bool {
+        if seen.iter().any(|s| s == name) {
+            return true;
+        }
+        let names = match self.aliases.get(name).map(|c| Chain::transform_names(c)) {
+            Some(Ok(names)) => names,
+            _ => return false,
+        };
+        seen.push(name.to_string());
+        let found = names.iter().any(|n| self.alias_cycle(n, seen));
+        seen.pop();
+        found
+    }
+
+    create_fn!(
+        /// Create a reader which applies the named transform or alias to `r`.
+        create,
+        factory,
+        build
+    );
+
+    create_fn!(
+        /// As for `create`, but for a reader which can be sent to another thread.
+        create_send,
+        factory_send,
+        build_send,
+        Send
+    );
+
+    /// Returns the chain for an alias, with the given settings.
+    fn alias<'a>(&'a self, chain: &'a str, s: &CodecSettings) -> Chain<'a> {
+        let mut c = Chain::new(self, chain, s.bufsize, s.strict)
+            .with_arguments(s.args.clone())
+            .with_threads(s.threads);
+        if let Some(limit) = s.max_output {
+            c = c.with_max_output(limit);
+        }
+        if s.dir == Direction::Reverse {
+            c = c.reverse();
+        }
+        c
+    }
+
+    /// Encode `inp` with the named transform in a single step.
+    ///
+    /// Transforms implemented by a single codec are run directly over the input with
+    /// `codec::transform_slice`; others, such as aliases, are run as a chain.  `strict` has the
+    /// same meaning as for a chain.
+    ///
+    /// There are deliberately no free `codec::encode` and `codec::decode` functions: creating a
+    /// registry costs more than transforming a small input, so one registry should be created and
+    /// used for many calls.
+    pub fn encode(
+        &self,
+        name: &str,
+        args: BTreeMap<String, Option<String>>,
+        inp: &[u8],
+        strict: bool,
+    ) -> Result<Vec<u8>, Error> {
+        self.transform_slice(name, args, inp, strict, Direction::Forward)
+    }
+
+    /// Decode `inp` with the named transform in a single step.
+    ///
+    /// See `encode` for details.
+    pub fn decode(
         &self,
         name: &str,
-        r: Box<io::BufRead>,
-        s: CodecSettings,
-    ) -> Result<Box<io::BufRead>, Error> {
-        match self.map.get(name) {
-            Some(t) => t.factory(r, s),
-            None => Err(Error::UnknownCodec(String::from(name))),
+        args: BTreeMap<String, Option<String>>,
+        inp: &[u8],
+        strict: bool,
+    ) -> Result<Vec<u8>, Error> {
+        self.transform_slice(name, args, inp, strict, Direction::Reverse)
+    }
+
+    fn transform_slice(
+        &self,
+        name: &str,
+        args: BTreeMap<String, Option<String>>,
+        inp: &[u8],
+        strict: bool,
+        dir: Direction,
+    ) -> Result<Vec<u8>, Error> {
+        let s = CodecSettings::new(codec::DEFAULT_BUFFER_SIZE, strict, args, dir);
+        if let Some(mut c) = self.codec(name, s.clone())? {
+            return codec::transform_slice(&mut c, inp);
+        }
+        let mut r = self.create(name, Box::new(io::Cursor::new(inp.to_vec())), s)?;
+        let mut out = io::Cursor::new(Vec::with_capacity(inp.len()));
+        io::copy(&mut r, &mut out).map_err(Error::IO)?;
+        Ok(out.into_inner())
+    }
+
+    /// Returns the codec implementing the named transform with the given settings, or `None` if
+    /// it is an alias or is not implemented by a single codec.
+    pub fn codec(&self, name: &str, s: CodecSettings) -> Result<Option<Box<Codec + Send>>, Error> {
+        match (self.map.get(name), self.aliases.get(name)) {
+            (Some(t), _) => {
+                if s.dir == Direction::Reverse && !t.can_reverse() {
+                    return Err(Error::ForwardOnly(String::from(name)));
+                }
+                let limit = s.max_output;
+                let s = CodecSettings {
+                    args: t.schema().resolve(s.args, s.dir)?,
+                    ..s
+                };
+                Ok(t.codec(s)?.map(|c| match limit {
+                    Some(limit) => Box::new(LimitedCodec::new(c, limit)) as Box<Codec + Send>,
+                    None => c,
+                }))
+            }
+            (None, Some(_)) => Ok(None),
+            (None, None) => Err(Error::UnknownCodec(String::from(name))),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use chain::Chain;
+    use codec;
     use codec::registry::CodecRegistry;
+    use codec::schema::OptionSchema;
+    use codec::tests;
     use codec::CodecSettings;
     use codec::CodecTransform;
     use codec::Direction;
@@ -146,6 +410,8 @@ mod tests {
     use std::collections::BTreeMap;
     use std::io;
     use std::io::Read;
+    use std::sync::Arc;
+    use std::thread;
 
     struct TestCodecFactory {}
 
@@ -155,11 +421,19 @@ mod tests {
             _r: Box<io::BufRead>,
             _s: CodecSettings,
         ) -> Result<Box<io::BufRead>, Error> {
-            return Ok(Box::new(io::Cursor::new(vec![0x61, 0x62, 0x63])));
+            Ok(Box::new(io::Cursor::new(vec![0x61, 0x62, 0x63])))
+        }
+
+        fn factory_send(
+            &self,
+            _r: Box<io::BufRead + Send>,
+            _s: CodecSettings,
+        ) -> Result<Box<io::BufRead + Send>, Error> {
+            Ok(Box::new(io::Cursor::new(vec![0x61, 0x62, 0x63])))
         }
 
-        fn options(&self) -> BTreeMap<String, String> {
-            BTreeMap::new()
+        fn schema(&self) -> OptionSchema {
+            OptionSchema::new()
         }
 
         fn can_reverse(&self) -> bool {
@@ -172,12 +446,7 @@ mod tests {
     }
 
     fn codec_settings() -> CodecSettings {
-        CodecSettings {
-            args: BTreeMap::new(),
-            bufsize: 512,
-            dir: Direction::Forward,
-            strict: true,
-        }
+        CodecSettings::new(512, true, BTreeMap::new(), Direction::Forward)
     }
 
     #[test]
@@ -206,6 +475,179 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_be_shared_between_threads() {
+        let mut cr = CodecRegistry::new();
+        cr.insert("random", Box::new(TestCodecFactory {}));
+        cr.insert_alias("double", "hex:base64").unwrap();
+        let cr = Arc::new(cr);
+
+        let threads: Vec<_> = ["random", "double", "hex:-hex"]
+            .iter()
+            .map(|chain| {
+                let cr = cr.clone();
+                thread::spawn(move || transform(&cr, chain, b"abc").unwrap())
+            })
+            .collect();
+        let res: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(
+            res,
+            vec![b"abc".to_vec(), b"NjE2MjYz".to_vec(), b"abc".to_vec()]
+        );
+
+        let r = Box::new(io::Cursor::new(b"abc".to_vec()));
+        let mut r = cr.create_send("double", r, codec_settings()).unwrap();
+        let out = thread::spawn(move || {
+            let mut out = io::Cursor::new(Vec::new());
+            io::copy(&mut r, &mut out).map(|_| out.into_inner())
+        });
+        assert_eq!(out.join().unwrap().unwrap(), b"NjE2MjYz");
+    }
+
+    #[test]
+    fn transforms_in_one_step() {
+        let cr = CodecRegistry::new();
+        let mut args = BTreeMap::new();
+        assert_eq!(
+            cr.encode("hex", args.clone(), b"abc", true).unwrap(),
+            b"616263"
+        );
+        assert_eq!(
+            cr.decode("hex", args.clone(), b"616263", true).unwrap(),
+            b"abc"
+        );
+        assert_eq!(
+            cr.encode("gzip", args.clone(), b"abc", true)
+                .and_then(|v| cr.decode("gzip", args.clone(), &v, true))
+                .unwrap(),
+            b"abc"
+        );
+        match cr.decode("base64", args.clone(), b"YWJj$GVm", true) {
+            Err(Error::AtOffset(pos, _)) => assert_eq!(pos.offset, 4),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(cr.decode("base64", args.clone(), b"YWJj\n", true).is_err());
+        assert_eq!(
+            cr.decode("base64", args.clone(), b"YWJj\n", false).unwrap(),
+            b"abc"
+        );
+        args.insert("nopad".to_string(), None);
+        assert_eq!(cr.encode("base64", args, b"abcd", true).unwrap(), b"YWJjZA");
+        let mut args = BTreeMap::new();
+        args.insert("upper".to_string(), None);
+        assert_eq!(cr.decode("hex", args, b"616263", true).unwrap(), b"abc");
+
+        for (name, inp) in &[("base64", 4), ("base32", 6), ("url64", 5), ("url64", 4)] {
+            let mut s = codec_settings();
+            let c = cr.codec(name, s.clone()).unwrap().unwrap();
+            let enc = cr
+                .encode(name, BTreeMap::new(), &vec![0u8; *inp], true)
+                .unwrap();
+            assert_eq!(c.max_output_size(*inp), Some(enc.len()), "{}", name);
+            s.dir = Direction::Reverse;
+            let c = cr.codec(name, s).unwrap().unwrap();
+            assert!(c.max_output_size(enc.len()).unwrap() >= *inp, "{}", name);
+        }
+
+        let s = codec_settings().with_max_output(6);
+        let mut c = cr.codec("hex", s.clone()).unwrap().unwrap();
+        assert_eq!(codec::transform_slice(&mut c, b"abc").unwrap(), b"616263");
+        let mut c = cr.codec("hex", s).unwrap().unwrap();
+        match codec::transform_slice(&mut c, b"abcd") {
+            Err(Error::OutputLimitExceeded(6)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    fn transform(cr: &CodecRegistry, chain: &str, inp: &[u8]) -> Result<Vec<u8>, io::Error> {
+        Chain::new(cr, chain, 512, true).transform(inp.to_vec())
+    }
+
+    #[test]
+    fn resolves_aliases() {
+        let mut cr = CodecRegistry::new();
+        cr.load_aliases(io::Cursor::new(
+            "# Comment.\n\nb64hex = -base64:hex\n  twice=(hash($algo):hex($case)){2} \n",
+        ))
+        .unwrap();
+        cr.insert_alias("nested", "-b64hex:twice(algo=md5,case=lower)")
+            .unwrap();
+
+        let aliases: Vec<_> = cr.aliases().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(aliases, vec!["b64hex", "nested", "twice"]);
+
+        assert_eq!(transform(&cr, "b64hex", b"Zm9v").unwrap(), b"666f6f");
+        assert_eq!(transform(&cr, "-b64hex", b"666f6f").unwrap(), b"Zm9v");
+        assert_eq!(
+            transform(&cr, "twice(algo=sha256,case=upper)", b"").unwrap(),
+            transform(&cr, "hash(sha256):hex(upper):hash(sha256):hex(upper)", b"").unwrap()
+        );
+        assert_eq!(
+            transform(&cr, "nested", b"666f6f").unwrap(),
+            transform(&cr, "-hex:base64:hash(md5):hex:hash(md5):hex", b"666f6f").unwrap()
+        );
+
+        match transform(&cr, "twice(algo=md5)", b"") {
+            Err(e) => match tests::codec_error(&e) {
+                Some(Error::MissingArgument(name)) => assert_eq!(name, "case"),
+                _ => panic!("wrong error: {:?}", e),
+            },
+            Ok(_) => panic!("unexpected success"),
+        }
+        for chain in &["twice", "-twice", "hex:twice"] {
+            match transform(&cr, chain, b"") {
+                Err(e) => match tests::codec_error(&e) {
+                    Some(Error::MissingArgument(name)) if name == "algo" || name == "case" => (),
+                    _ => panic!("wrong error for {}: {:?}", chain, e),
+                },
+                Ok(_) => panic!("unexpected success for {}", chain),
+            }
+        }
+        match transform(&cr, "b64hex(bogus=1)", b"") {
+            Err(e) => match tests::codec_error(&e) {
+                Some(Error::UnknownArgument(name)) => assert_eq!(name, "bogus"),
+                _ => panic!("wrong error: {:?}", e),
+            },
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_aliases() {
+        let mut cr = CodecRegistry::new();
+        cr.insert_alias("a", "b:hex").unwrap();
+        cr.insert_alias("b", "base64").unwrap();
+        cr.insert_alias("x", "y").unwrap();
+        for &(name, chain) in &[
+            ("hex", "base64"),
+            ("a", "hex"),
+            ("-c", "hex"),
+            ("c d", "hex"),
+            ("c:d", "hex"),
+            ("$c", "hex"),
+            ("", "hex"),
+            ("c", "hex("),
+            ("c", "-c"),
+            ("y", "(hex|-x)"),
+        ] {
+            match cr.insert_alias(name, chain) {
+                Err(Error::InvalidAlias(n, _)) => assert_eq!(n, name),
+                r => panic!("unexpected result for {} = {}: {:?}", name, chain, r),
+            }
+        }
+        cr.insert_alias("c", "(a|hex):a").unwrap();
+        match cr.load_aliases(io::Cursor::new("d\n")) {
+            Err(Error::InvalidAlias(..)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match cr.load_aliases(io::Cursor::new("# comment\n\nd = e:hex(\n")) {
+            Err(e @ Error::InvalidAlias(..)) => {
+                assert!(e.to_string().starts_with("invalid alias 'd': line 3: "))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
     #[test]
     fn iterates_in_sorted_order() {
         let cr = CodecRegistry::new();
@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+use std::slice;
+
+use codec::Direction;
+use codec::Error;
+
+/// The kind of value an option takes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionKind {
+    /// An option which takes no value.
+    Flag,
+    /// An integer in the given inclusive range.  If there is no maximum, any integer at least as
+    /// large as the minimum is accepted.
+    Integer { min: u64, max: Option<u64> },
+    /// A length, which is either zero (meaning no length) or at least the given minimum.
+    Length(u64),
+    /// One of a fixed set of values.
+    Enum(&'static [&'static str]),
+    /// A byte sequence written in hexadecimal.
+    Hex,
+    /// Any text.
+    Text,
+}
+
+/// The description of a single option to a codec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub kind: OptionKind,
+    pub description: String,
+    /// The value used if the option is not specified.
+    pub default: Option<&'static str>,
+    /// The direction this option applies to, or `None` if it applies to both.
+    ///
+    /// Options which don't apply to the direction in use are checked and then ignored, so that a
+    /// transform can always be reversed with the same options.
+    pub dir: Option<Direction>,
+}
+
+impl OptionSpec {
+    /// Whether this option applies to a transform run in the direction `dir`.
+    pub fn applies_to(&self, dir: Direction) -> bool {
+        match self.dir {
+            Some(d) => d == dir,
+            None => true,
+        }
+    }
+
+    /// Check a value provided for this option.
+    fn check(&self, val: Option<&str>) -> Result<(), Error> {
+        let name = self.name;
+        let invalid = |v: &str| Error::InvalidArgument(name.to_string(), v.to_string());
+        let val = match (&self.kind, val) {
+            (&OptionKind::Flag, None) => return Ok(()),
+            (&OptionKind::Flag, Some(v)) => return Err(invalid(v)),
+            (_, None) => return Err(Error::MissingArgument(name.to_string())),
+            (_, Some(v)) => v,
+        };
+        let ok = match self.kind {
+            OptionKind::Flag => unreachable!(),
+            OptionKind::Integer { min, max } => match val.parse::<u64>() {
+                Ok(x) => match max {
+                    Some(max) => x >= min && x <= max,
+                    None => x >= min,
+                },
+                Err(_) => false,
+            },
+            OptionKind::Length(min) => match val.parse::<u64>() {
+                Ok(x) => x == 0 || x >= min,
+                Err(_) => false,
+            },
+            OptionKind::Enum(values) => values.contains(&val),
+            OptionKind::Hex => val.len() % 2 == 0 && val.bytes().all(|b| b.is_ascii_hexdigit()),
+            OptionKind::Text => true,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(invalid(val))
+        }
+    }
+}
+
+/// The options a codec accepts and the constraints between them.
+///
+/// This is built up by chaining calls; methods such as `default` and `direction` modify the option
+/// most recently added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OptionSchema {
+    options: Vec<OptionSpec>,
+    exclusive: Vec<Vec<&'static str>>,
+    required: Vec<(&'static str, Vec<&'static str>)>,
+}
+
+impl OptionSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an option which takes a value of the given kind.
+    #[must_use]
+    pub fn option(mut self, name: &'static str, kind: OptionKind, description: String) -> Self {
+        self.options.push(OptionSpec {
+            name,
+            kind,
+            description,
+            default: None,
+            dir: None,
+        });
+        self
+    }
+
+    /// Add an option which takes no value.
+    #[must_use]
+    pub fn flag(self, name: &'static str, description: String) -> Self {
+        self.option(name, OptionKind::Flag, description)
+    }
+
+    /// Set the default value of the last option added.
+    #[must_use]
+    pub fn default_value(mut self, val: &'static str) -> Self {
+        if let Some(opt) = self.options.last_mut() {
+            opt.default = Some(val);
+        }
+        self
+    }
+
+    /// Specify that the last option added applies only to the given direction.
+    #[must_use]
+    pub fn direction(mut self, dir: Direction) -> Self {
+        if let Some(opt) = self.options.last_mut() {
+            opt.dir = Some(dir);
+        }
+        self
+    }
+
+    /// Specify that at most one of the given options may be used.
+    #[must_use]
+    pub fn exclusive(mut self, names: &[&'static str]) -> Self {
+        self.exclusive.push(names.to_vec());
+        self
+    }
+
+    /// Specify that exactly one of the given options must be used.  If none is, the error
+    /// mentions `what`.
+    #[must_use]
+    pub fn one_of(mut self, what: &'static str, names: &[&'static str]) -> Self {
+        self.exclusive.push(names.to_vec());
+        self.required.push((what, names.to_vec()));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OptionSpec> {
+        self.options.iter().find(|o| o.name == name)
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, OptionSpec> {
+        self.options.iter()
+    }
+
+    /// Returns a description of each option, keyed by name.
+    pub fn descriptions(&self) -> BTreeMap<String, String> {
+        self.options
+            .iter()
+            .map(|o| (o.name.to_string(), o.description.clone()))
+            .collect()
+    }
+
+    /// Remove any arguments which apply only to the direction opposite to `dir`.
+    pub fn retain_direction(
+        &self,
+        mut args: BTreeMap<String, Option<String>>,
+        dir: Direction,
+    ) -> BTreeMap<String, Option<String>> {
+        args.retain(|k, _| match self.get(k) {
+            Some(opt) => opt.applies_to(dir),
+            None => true,
+        });
+        args
+    }
+
+    /// Check a set of arguments for a transform run in the direction `dir` against this schema
+    /// and fill in the defaults for any options not specified.
+    ///
+    /// Arguments which apply only to the other direction are checked like any other, but are then
+    /// removed.
+    pub fn resolve(
+        &self,
+        args: BTreeMap<String, Option<String>>,
+        dir: Direction,
+    ) -> Result<BTreeMap<String, Option<String>>, Error> {
+        for (k, v) in &args {
+            match self.get(k) {
+                Some(opt) => opt.check(v.as_ref().map(|s| s.as_str()))?,
+                None => return Err(Error::UnknownArgument(k.clone())),
+            }
+        }
+        for group in &self.exclusive {
+            let present: Vec<_> = group.iter().filter(|&&n| args.contains_key(n)).collect();
+            if present.len() > 1 {
+                return Err(Error::IncompatibleParameters(
+                    present[0].to_string(),
+                    present[1].to_string(),
+                ));
+            }
+        }
+        for (what, group) in &self.required {
+            if !group.iter().any(|&n| args.contains_key(n)) {
+                return Err(Error::MissingArgument(what.to_string()));
+            }
+        }
+        let mut args = self.retain_direction(args, dir);
+        for opt in self.options.iter().filter(|o| o.applies_to(dir)) {
+            if let Some(val) = opt.default {
+                args.entry(opt.name.to_string())
+                    .or_insert_with(|| Some(val.to_string()));
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::schema::{OptionKind, OptionSchema};
+    use codec::Direction;
+    use codec::Error;
+    use std::collections::BTreeMap;
+
+    fn args(v: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        v.iter()
+            .map(|&(k, v)| (k.to_string(), v.map(|s| s.to_string())))
+            .collect()
+    }
+
+    fn schema() -> OptionSchema {
+        OptionSchema::new()
+            .flag("lower", "lowercase".to_string())
+            .direction(Direction::Forward)
+            .flag("upper", "uppercase".to_string())
+            .direction(Direction::Forward)
+            .exclusive(&["lower", "upper"])
+            .flag("skip", "skip".to_string())
+            .direction(Direction::Reverse)
+            .option("length", OptionKind::Length(4), "length".to_string())
+            .default_value("76")
+            .option(
+                "count",
+                OptionKind::Integer {
+                    min: 1,
+                    max: Some(10),
+                },
+                "count".to_string(),
+            )
+            .option("mode", OptionKind::Enum(&["a", "b"]), "mode".to_string())
+            .option("key", OptionKind::Hex, "key".to_string())
+            .option("note", OptionKind::Text, "note".to_string())
+            .flag("x", "x".to_string())
+            .flag("y", "y".to_string())
+            .one_of("thing", &["x", "y"])
+    }
+
+    #[test]
+    fn resolves_valid_arguments() {
+        let s = schema();
+        assert_eq!(
+            s.resolve(args(&[("x", None)]), Direction::Forward).unwrap(),
+            args(&[("x", None), ("length", Some("76"))])
+        );
+        assert_eq!(
+            s.resolve(
+                args(&[
+                    ("y", None),
+                    ("upper", None),
+                    ("length", Some("0")),
+                    ("count", Some("10")),
+                    ("mode", Some("b")),
+                    ("key", Some("00fF")),
+                    ("note", Some("a=b, c")),
+                ]),
+                Direction::Forward
+            )
+            .unwrap(),
+            args(&[
+                ("y", None),
+                ("upper", None),
+                ("length", Some("0")),
+                ("count", Some("10")),
+                ("mode", Some("b")),
+                ("key", Some("00fF")),
+                ("note", Some("a=b, c")),
+            ])
+        );
+        assert_eq!(s.get("upper").unwrap().dir, Some(Direction::Forward));
+        assert_eq!(s.get("length").unwrap().dir, None);
+        assert_eq!(s.descriptions().len(), 10);
+        assert_eq!(
+            s.resolve(args(&[("x", None), ("skip", None)]), Direction::Reverse)
+                .unwrap(),
+            args(&[("x", None), ("skip", None), ("length", Some("76"))])
+        );
+    }
+
+    #[test]
+    fn ignores_arguments_for_other_direction() {
+        let s = schema();
+        assert_eq!(
+            s.resolve(args(&[("x", None), ("upper", None)]), Direction::Reverse)
+                .unwrap(),
+            args(&[("x", None), ("length", Some("76"))])
+        );
+        assert_eq!(
+            s.resolve(args(&[("x", None), ("skip", None)]), Direction::Forward)
+                .unwrap(),
+            args(&[("x", None), ("length", Some("76"))])
+        );
+        // They must still be valid, though.
+        assert_eq!(
+            format!(
+                "{:?}",
+                s.resolve(
+                    args(&[("x", None), ("upper", Some("1"))]),
+                    Direction::Reverse
+                )
+                .unwrap_err()
+            ),
+            format!("{:?}", Error::InvalidArgument("upper".into(), "1".into()))
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                s.resolve(
+                    args(&[("x", None), ("upper", None), ("lower", None)]),
+                    Direction::Reverse
+                )
+                .unwrap_err()
+            ),
+            format!(
+                "{:?}",
+                Error::IncompatibleParameters("lower".into(), "upper".into())
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let s = schema();
+        type Case<'a> = (&'a [(&'a str, Option<&'a str>)], Error);
+        let cases: &[Case] = &[
+            (
+                &[("x", None), ("bogus", None)],
+                Error::UnknownArgument("bogus".into()),
+            ),
+            (
+                &[("x", Some("1"))],
+                Error::InvalidArgument("x".into(), "1".into()),
+            ),
+            (
+                &[("x", None), ("length", None)],
+                Error::MissingArgument("length".into()),
+            ),
+            (
+                &[("x", None), ("length", Some("3"))],
+                Error::InvalidArgument("length".into(), "3".into()),
+            ),
+            (
+                &[("x", None), ("count", Some("0"))],
+                Error::InvalidArgument("count".into(), "0".into()),
+            ),
+            (
+                &[("x", None), ("count", Some("11"))],
+                Error::InvalidArgument("count".into(), "11".into()),
+            ),
+            (
+                &[("x", None), ("count", Some("-1"))],
+                Error::InvalidArgument("count".into(), "-1".into()),
+            ),
+            (
+                &[("x", None), ("mode", Some("c"))],
+                Error::InvalidArgument("mode".into(), "c".into()),
+            ),
+            (
+                &[("x", None), ("note", None)],
+                Error::MissingArgument("note".into()),
+            ),
+            (
+                &[("x", None), ("key", Some("abc"))],
+                Error::InvalidArgument("key".into(), "abc".into()),
+            ),
+            (
+                &[("x", None), ("key", Some("zz"))],
+                Error::InvalidArgument("key".into(), "zz".into()),
+            ),
+            (
+                &[("x", None), ("lower", None), ("upper", None)],
+                Error::IncompatibleParameters("lower".into(), "upper".into()),
+            ),
+            (
+                &[("x", None), ("y", None)],
+                Error::IncompatibleParameters("x".into(), "y".into()),
+            ),
+            (&[("lower", None)], Error::MissingArgument("thing".into())),
+        ];
+        for (a, expected) in cases {
+            let err = s.resolve(args(a), Direction::Forward).unwrap_err();
+            assert_eq!(format!("{:?}", err), format!("{:?}", expected));
+        }
+    }
+}
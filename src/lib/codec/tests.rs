@@ -1,11 +1,16 @@
 /// A variety of useful test case generators.
 use chain::Chain;
+use chain::StageError;
+use codec::helpers::simd;
 use codec::registry::CodecRegistry;
-use codec::{CodecSettings, CodecTransform, Direction, Error};
+use codec::schema::OptionKind;
+use codec::{Codec, CodecSettings, CodecTransform, Direction, Error, FlushState, Position, Status};
 use rand_chacha::ChaChaRng;
 use rand_core::{RngCore, SeedableRng};
+use std::cmp;
 use std::collections::BTreeMap;
 use std::env;
+use std::error;
 use std::io;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -13,6 +18,163 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub const BYTE_SEQ : &[u8] = b"\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x10\x11\x12\x13\x14\x15\x16\x17\x18\x19\x1a\x1b\x1c\x1d\x1e\x1f\x20\x21\x22\x23\x24\x25\x26\x27\x28\x29\x2a\x2b\x2c\x2d\x2e\x2f\x30\x31\x32\x33\x34\x35\x36\x37\x38\x39\x3a\x3b\x3c\x3d\x3e\x3f\x40\x41\x42\x43\x44\x45\x46\x47\x48\x49\x4a\x4b\x4c\x4d\x4e\x4f\x50\x51\x52\x53\x54\x55\x56\x57\x58\x59\x5a\x5b\x5c\x5d\x5e\x5f\x60\x61\x62\x63\x64\x65\x66\x67\x68\x69\x6a\x6b\x6c\x6d\x6e\x6f\x70\x71\x72\x73\x74\x75\x76\x77\x78\x79\x7a\x7b\x7c\x7d\x7e\x7f\x80\x81\x82\x83\x84\x85\x86\x87\x88\x89\x8a\x8b\x8c\x8d\x8e\x8f\x90\x91\x92\x93\x94\x95\x96\x97\x98\x99\x9a\x9b\x9c\x9d\x9e\x9f\xa0\xa1\xa2\xa3\xa4\xa5\xa6\xa7\xa8\xa9\xaa\xab\xac\xad\xae\xaf\xb0\xb1\xb2\xb3\xb4\xb5\xb6\xb7\xb8\xb9\xba\xbb\xbc\xbd\xbe\xbf\xc0\xc1\xc2\xc3\xc4\xc5\xc6\xc7\xc8\xc9\xca\xcb\xcc\xcd\xce\xcf\xd0\xd1\xd2\xd3\xd4\xd5\xd6\xd7\xd8\xd9\xda\xdb\xdc\xdd\xde\xdf\xe0\xe1\xe2\xe3\xe4\xe5\xe6\xe7\xe8\xe9\xea\xeb\xec\xed\xee\xef\xf0\xf1\xf2\xf3\xf4\xf5\xf6\xf7\xf8\xf9\xfa\xfb\xfc\xfd\xfe\xff";
 
 // Test helpers.
+
+/// Returns the error of type `E` underlying an error from a chain, looking through any stage
+/// information.
+pub fn inner_error<E: error::Error + 'static>(e: &io::Error) -> Option<&E> {
+    let inner = e.get_ref()?;
+    match inner.downcast_ref::<StageError>() {
+        Some(s) => inner_error(&s.error),
+        None => inner.downcast_ref::<E>(),
+    }
+}
+
+/// Returns the codec error underlying an error from a chain, looking through any stage and offset
+/// information.
+pub fn codec_error(e: &io::Error) -> Option<&Error> {
+    inner_error::<Error>(e).map(Error::without_offset)
+}
+
+/// Returns the position in a codec's input of the error underlying an error from a chain.
+pub fn error_position(e: &io::Error) -> Option<Position> {
+    match inner_error::<Error>(e) {
+        Some(&Error::AtOffset(pos, _)) => Some(pos),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "async")]
+pub use self::asynchelpers::*;
+
+/// Helpers for testing the asynchronous adapters without an executor.
+#[cfg(feature = "async")]
+mod asynchelpers {
+    use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+    use std::cmp;
+    use std::io;
+    use std::pin::Pin;
+    use std::ptr;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(ptr::null(), &VTABLE)
+    }
+
+    /// Poll `f` until it is ready, spinning rather than waiting to be woken.
+    pub fn block_on<T, F: FnMut(&mut Context) -> Poll<T>>(mut f: F) -> T {
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(v) = f(&mut cx) {
+                return v;
+            }
+        }
+    }
+
+    /// A reader which returns at most three bytes at a time and is pending on every other poll.
+    pub struct ChoppyReader<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl<'a> ChoppyReader<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            ChoppyReader { data, ready: false }
+        }
+    }
+
+    impl<'a> AsyncBufRead for ChoppyReader<'a> {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+            let this = self.get_mut();
+            this.ready = !this.ready;
+            if !this.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(Ok(&this.data[..cmp::min(this.data.len(), 3)]))
+        }
+
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            let this = self.get_mut();
+            this.data = &this.data[amt..];
+        }
+    }
+
+    impl<'a> AsyncRead for ChoppyReader<'a> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = match self.as_mut().poll_fill_buf(cx) {
+                Poll::Ready(Ok(data)) => {
+                    let n = cmp::min(data.len(), buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    n
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.consume(n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    /// A writer which accepts at most three bytes at a time and is pending on every other poll.
+    #[derive(Default)]
+    pub struct ChoppyWriter {
+        pub data: Vec<u8>,
+        pub closed: bool,
+        ready: bool,
+    }
+
+    impl ChoppyWriter {
+        fn poll_ready(&mut self, cx: &mut Context) -> Poll<()> {
+            self.ready = !self.ready;
+            if self.ready {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncWrite for ChoppyWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if this.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let n = cmp::min(buf.len(), 3);
+            this.data.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            self.get_mut().poll_ready(cx).map(Ok)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if this.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
 fn prng(time: bool) -> ChaChaRng {
     let seed = if time {
         if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
@@ -75,6 +237,45 @@ fn matches_pattern_bytes<F: Fn(&[u8]) -> bool>(name: &'static str, inp: &[u8], d
     }
 }
 
+/// Transforms `inp` with the named codec directly, giving it output buffers of only its stated
+/// buffer size, and checks that the result is `outp`.
+pub fn check_buffer_size(name: &str, inp: &[u8], outp: &[u8]) {
+    let reg = CodecRegistry::new();
+    let (codec_name, dir) = match name.starts_with('-') {
+        true => (&name[1..], Direction::Reverse),
+        false => (name, Direction::Forward),
+    };
+    let s = CodecSettings {
+        bufsize: 8,
+        strict: true,
+        args: BTreeMap::new(),
+        dir,
+        threads: 1,
+        max_output: None,
+    };
+    let mut codec = reg.codec(codec_name, s).unwrap().unwrap();
+    let size = codec.buffer_size();
+    let mut res = Vec::new();
+    let mut pos = 0;
+    let mut flush = FlushState::None;
+    loop {
+        let mut buf = vec![0u8; size];
+        let st = codec.transform(&inp[pos..], &mut buf, flush).unwrap();
+        let (consumed, written) = st.unpack();
+        res.extend_from_slice(&buf[..written]);
+        pos += consumed;
+        match st {
+            Status::StreamEnd(_, _) => break,
+            _ if consumed == 0 && written == 0 && flush == FlushState::None => {
+                flush = FlushState::Finish
+            }
+            _ if consumed == 0 && written == 0 => break,
+            _ => (),
+        }
+    }
+    assert_eq!(res, outp, "{} with {}-byte output buffers", name, size);
+}
+
 pub fn round_trip(name: &'static str) {
     for p in &[true, false] {
         let mut prng = prng(*p);
@@ -87,6 +288,37 @@ pub fn round_trip(name: &'static str) {
     }
     round_trip_with_fill(name, 32768);
     round_trip_bytes(name, BYTE_SEQ, "all-bytes", None);
+    one_shot(name, BYTE_SEQ);
+    one_shot(name, &vec![0x5a; 32768]);
+}
+
+/// Checks that each set of vectorized kernels this CPU supports encodes and decodes exactly like
+/// the portable code on inputs ending on either side of the kernels' block boundaries.
+pub fn vectorized_matches_portable(name: &'static str) {
+    let reg = CodecRegistry::new();
+    let (codec, args) = single_transform(name).unwrap();
+    for &len in &[0, 1, 11, 12, 13, 23, 24, 25, 47, 48, 49, 95, 96, 97, 1000] {
+        let inp: Vec<u8> = (0..len).map(|i| (i * 167 + i / 256) as u8).collect();
+        let run = |level| {
+            simd::with_level(level, || {
+                let enc = reg.encode(codec, args.clone(), &inp, true).unwrap();
+                let dec = reg.decode(codec, args.clone(), &enc, true).unwrap();
+                (enc, dec)
+            })
+        };
+        let (enc, dec) = run(simd::Level::Portable);
+        assert_eq!(dec, inp, "{} ({} bytes, portable)", name, len);
+        for &level in &simd::levels() {
+            assert_eq!(
+                run(level),
+                (enc.clone(), dec.clone()),
+                "{} ({} bytes, {:?})",
+                name,
+                len,
+                level
+            );
+        }
+    }
 }
 
 pub fn round_trip_stripped_whitespace(name: &'static str) {
@@ -106,6 +338,43 @@ fn round_trip_with_fill(name: &'static str, sz: usize) {
     round_trip_bytes(name, &v, "fill", None);
 }
 
+/// Split a chain consisting of a single transform with flag arguments into its name and
+/// arguments.
+fn single_transform(chain: &str) -> Option<(&str, BTreeMap<String, Option<String>>)> {
+    if chain.contains(|c| ":(){}".contains(c)) {
+        return None;
+    }
+    let mut parts = chain.split(',');
+    let name = parts.next()?;
+    Some((name, parts.map(|arg| (arg.to_string(), None)).collect()))
+}
+
+/// Checks that the one-shot registry functions agree with a chain for a single transform.
+fn one_shot(name: &'static str, inp: &[u8]) {
+    let (codec, args) = match single_transform(name) {
+        Some(t) => t,
+        None => return,
+    };
+    let reg = CodecRegistry::new();
+    let outp = Chain::new(&reg, name, 512, true)
+        .transform(inp.to_vec())
+        .unwrap();
+    assert_eq!(
+        reg.encode(codec, args.clone(), inp, true).unwrap(),
+        outp,
+        "one-shot encode {} ({} bytes)",
+        name,
+        inp.len()
+    );
+    assert_eq!(
+        reg.decode(codec, args, &outp, true).unwrap(),
+        inp,
+        "one-shot decode {} ({} bytes)",
+        name,
+        inp.len()
+    );
+}
+
 fn round_trip_bytes(name: &'static str, inp: &[u8], desc: &str, trailing: Option<&[u8]>) {
     let reg = CodecRegistry::new();
     for &i in &[64, 65, 66, 67, 512] {
@@ -185,11 +454,21 @@ pub fn basic_configuration(name: &str) {
         None => panic!("Can't find {}", name),
     };
 
-    for (arg, _) in transform.options() {
-        match instantiate_with_arg(transform, &arg, None) {
+    match instantiate_with_arg(transform, "no-such-option", None) {
+        Err(Error::UnknownArgument(ref arg)) if arg == "no-such-option" => (),
+        Err(e) => panic!(
+            "Unexpected error instantiating with unknown argument: {}",
+            e
+        ),
+        Ok(_) => panic!("Successfully instantiated with unknown argument"),
+    }
+
+    let schema = transform.schema();
+    for opt in schema.iter() {
+        match instantiate_with_arg(transform, opt.name, None) {
             Ok(_) => (),
             Err(Error::MissingArgument(_)) => {
-                instantiate_with_arg(transform, &arg, Some("512".to_string()))
+                instantiate_with_arg(transform, opt.name, Some(sample_value(&opt.kind)))
                     .expect("Can instantiate with each arg");
             }
             Err(e) => panic!("Unexpected error instantiating with argument: {}", e),
@@ -197,6 +476,21 @@ pub fn basic_configuration(name: &str) {
     }
 }
 
+/// Returns a valid value for an option of the given kind.
+fn sample_value(kind: &OptionKind) -> String {
+    match *kind {
+        OptionKind::Integer { min, max } => {
+            let val = cmp::max(512, min);
+            max.map_or(val, |max| cmp::min(val, max))
+        }
+        OptionKind::Length(min) => cmp::max(512, min),
+        OptionKind::Enum(values) => return values[0].to_string(),
+        OptionKind::Hex => return "00".to_string(),
+        OptionKind::Flag | OptionKind::Text => 512,
+    }
+    .to_string()
+}
+
 pub fn basic_configuration_without_options(name: &str) {
     let reg = CodecRegistry::new();
 
@@ -217,6 +511,8 @@ pub fn basic_configuration_without_options(name: &str) {
         strict: true,
         args: BTreeMap::new(),
         dir: Direction::Reverse,
+        threads: 1,
+        max_output: None,
     };
     if transform.can_reverse() {
         match instantiate(transform, settings) {
@@ -246,6 +542,8 @@ fn instantiate_with_arg(
         strict: true,
         args,
         dir: Direction::Forward,
+        threads: 1,
+        max_output: None,
     };
 
     instantiate(transform, settings)
@@ -255,5 +553,14 @@ fn instantiate(
     transform: &CodecTransform,
     settings: CodecSettings,
 ) -> Result<Box<io::BufRead>, Error> {
+    // Check the arguments as the registry would, unless the codec should refuse to be reversed.
+    let settings = if settings.dir == Direction::Forward || transform.can_reverse() {
+        CodecSettings {
+            args: transform.schema().resolve(settings.args, settings.dir)?,
+            ..settings
+        }
+    } else {
+        settings
+    };
     transform.factory(Box::new(io::Cursor::new("abc")), settings)
 }
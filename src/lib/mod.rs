@@ -2,9 +2,17 @@
 #![allow(bare_trait_objects)]
 
 extern crate blake2;
+#[cfg(feature = "brotli")]
+extern crate brotli_lib as brotli;
+#[cfg(feature = "bzip2")]
+extern crate bzip2_lib as bzip2;
 extern crate clap;
 extern crate digest;
 extern crate flate2;
+#[cfg(feature = "async")]
+extern crate futures_io;
+#[cfg(feature = "lz4")]
+extern crate lz4_lib as lz4;
 extern crate md5;
 #[cfg(test)]
 #[macro_use]
@@ -16,5 +24,9 @@ extern crate sha2;
 extern crate sha3;
 #[macro_use]
 extern crate tr;
+#[cfg(feature = "xz")]
+extern crate xz2;
+#[cfg(feature = "zstd")]
+extern crate zstd_lib as zstd;
 pub mod chain;
 pub mod codec;
@@ -22,9 +22,11 @@ extern crate tr;
 use muter::chain;
 use muter::codec;
 use muter::codec::registry::CodecRegistry;
+use std::env;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
+use std::path::PathBuf;
 use std::process;
 
 use clap::{App, Arg, ArgMatches};
@@ -45,34 +47,248 @@ fn source(values: Vec<&OsStr>, bufsize: usize) -> io::Result<Box<io::BufRead>> {
     )))
 }
 
-fn create_chain(reg: &CodecRegistry, m: ArgMatches) -> io::Result<Box<io::BufRead>> {
-    let chain = m.value_of("chain").unwrap();
-    let sources = match m.values_of_os("INPUT") {
-        Some(x) => x.collect(),
-        None => vec![],
+/// Returns the path to the alias file and whether it was explicitly specified.
+fn alias_file() -> Option<(PathBuf, bool)> {
+    if let Some(path) = env::var_os("MUTER_CONFIG") {
+        return Some((PathBuf::from(path), true));
+    }
+    let dir = match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) => PathBuf::from(home).join(".config"),
+        (_, None) => return None,
+    };
+    Some((dir.join("muter").join("aliases"), false))
+}
+
+/// Load the aliases from the alias file, if any, returning a message naming the file on failure.
+///
+/// A missing alias file is an error only if it was explicitly specified.
+fn load_aliases(reg: &mut CodecRegistry) -> Result<(), String> {
+    let (path, explicit) = match alias_file() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let res = match fs::File::open(&path) {
+        Ok(f) => reg
+            .load_aliases(io::BufReader::new(f))
+            .map_err(io::Error::from),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && !explicit => Ok(()),
+        Err(e) => Err(e),
     };
-    let bufsize = match m
+    res.map_err(|e| match e.get_ref() {
+        Some(err) => tr!("alias file {}: {}", path.display(), err),
+        None => tr!("cannot read alias file {}: {}", path.display(), e),
+    })
+}
+
+fn buffer_size(m: &ArgMatches) -> io::Result<usize> {
+    match m
         .value_of("buffer-size")
         .map(|val| val.parse())
         .unwrap_or(Ok(BUFFER_SIZE))
     {
-        Ok(x) => x,
-        Err(_) => {
-            return Err(muter::codec::Error::InvalidArgument(
-                "buffer-size".to_string(),
-                m.value_of("buffer-size").unwrap().to_string(),
-            )
-            .into())
-        }
-    };
-    let mut c = chain::Chain::new(reg, chain, bufsize, !m.is_present("no-strict"));
+        Ok(x) => Ok(x),
+        Err(_) => Err(muter::codec::Error::InvalidArgument(
+            "buffer-size".to_string(),
+            m.value_of("buffer-size").unwrap().to_string(),
+        )
+        .into()),
+    }
+}
+
+fn threads(m: &ArgMatches) -> io::Result<usize> {
+    match m
+        .value_of("threads")
+        .map(|val| val.parse())
+        .unwrap_or(Ok(1))
+    {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err(muter::codec::Error::InvalidArgument(
+            "threads".to_string(),
+            m.value_of("threads").unwrap().to_string(),
+        )
+        .into()),
+    }
+}
+
+fn max_output(m: &ArgMatches) -> io::Result<Option<u64>> {
+    match m.value_of("max-output").map(|val| val.parse()) {
+        Some(Ok(x)) => Ok(Some(x)),
+        Some(Err(_)) => Err(muter::codec::Error::InvalidArgument(
+            "max-output".to_string(),
+            m.value_of("max-output").unwrap().to_string(),
+        )
+        .into()),
+        None => Ok(None),
+    }
+}
+
+fn chain<'a>(reg: &'a CodecRegistry, m: &'a ArgMatches) -> io::Result<chain::Chain<'a>> {
+    let chain = m.value_of("chain").unwrap();
+    let mut c = chain::Chain::new(reg, chain, buffer_size(m)?, !m.is_present("no-strict"))
+        .with_threads(threads(m)?);
+    if let Some(limit) = max_output(m)? {
+        c = c.with_max_output(limit);
+    }
     if m.is_present("reverse") {
         c = c.reverse();
     }
-    c.build(source(sources, bufsize)?)
+    if m.is_present("verbose") {
+        c = c.on_alternative(|alt| eprintln!("muter: {}", tr!("using alternative '{}'", alt)));
+    }
+    Ok(c)
+}
+
+fn create_chain(reg: &CodecRegistry, m: ArgMatches) -> io::Result<Box<io::BufRead>> {
+    let sources = match m.values_of_os("INPUT") {
+        Some(x) => x.collect(),
+        None => vec![],
+    };
+    let bufsize = buffer_size(&m)?;
+    chain(reg, &m)?.build(source(sources, bufsize)?)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn direction_name(dir: codec::Direction) -> &'static str {
+    match dir {
+        codec::Direction::Forward => "forward",
+        codec::Direction::Reverse => "reverse",
+    }
+}
+
+fn explain_text(stages: &[chain::ExplainedStage], indent: usize, out: &mut String) {
+    for stage in stages {
+        match *stage {
+            chain::ExplainedStage::Transform {
+                ref name,
+                dir,
+                ref args,
+            } => {
+                out.push_str(&format!(
+                    "{:indent$}{} ({})\n",
+                    "",
+                    name,
+                    direction_name(dir),
+                    indent = indent
+                ));
+                for (k, v) in args {
+                    match *v {
+                        Some(ref v) => out.push_str(&format!(
+                            "{:indent$}  {} = {}\n",
+                            "",
+                            k,
+                            v,
+                            indent = indent
+                        )),
+                        None => out.push_str(&format!("{:indent$}  {}\n", "", k, indent = indent)),
+                    }
+                }
+            }
+            chain::ExplainedStage::Alternation(ref alts) => {
+                out.push_str(&format!(
+                    "{:indent$}{}\n",
+                    "",
+                    tr!("first matching alternative of:"),
+                    indent = indent
+                ));
+                for (text, stages) in alts {
+                    out.push_str(&format!("{:indent$}  '{}':\n", "", text, indent = indent));
+                    explain_text(stages, indent + 4, out);
+                }
+            }
+        }
+    }
+}
+
+fn explain_json(stages: &[chain::ExplainedStage]) -> String {
+    let v: Vec<String> = stages
+        .iter()
+        .map(|stage| match *stage {
+            chain::ExplainedStage::Transform {
+                ref name,
+                dir,
+                ref args,
+            } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(k, v)| {
+                        let v = match *v {
+                            Some(ref v) => json_string(v),
+                            None => "null".to_string(),
+                        };
+                        format!("{}:{}", json_string(k), v)
+                    })
+                    .collect();
+                format!(
+                    "{{\"name\":{},\"direction\":\"{}\",\"options\":{{{}}}}}",
+                    json_string(name),
+                    direction_name(dir),
+                    args.join(",")
+                )
+            }
+            chain::ExplainedStage::Alternation(ref alts) => {
+                let alts: Vec<String> = alts
+                    .iter()
+                    .map(|(text, stages)| {
+                        format!(
+                            "{{\"text\":{},\"stages\":{}}}",
+                            json_string(text),
+                            explain_json(stages)
+                        )
+                    })
+                    .collect();
+                format!("{{\"alternation\":[{}]}}", alts.join(","))
+            }
+        })
+        .collect();
+    format!("[{}]", v.join(","))
+}
+
+fn explain(reg: &CodecRegistry, m: &ArgMatches) -> io::Result<()> {
+    let c = chain(reg, m)?.compile()?;
+    c.validate()?;
+    let stages = c.explain();
+    let s = match m.value_of("explain") {
+        Some("json") => explain_json(&stages) + "\n",
+        _ => {
+            let mut s = String::new();
+            explain_text(&stages, 0, &mut s);
+            s
+        }
+    };
+    io::Write::write_all(&mut io::stdout(), s.as_bytes())
+}
+
+/// Returns the text of the chain in which an error occurred with a line of carets under the stage
+/// which failed.
+fn stage_caret(err: &chain::StageError) -> String {
+    let (start, end) = err.span;
+    format!(
+        "  {}\n  {:start$}{}",
+        err.chain,
+        "",
+        "^".repeat(end.saturating_sub(start).max(1)),
+        start = start
+    )
 }
 
 fn process(reg: &CodecRegistry, m: ArgMatches) -> io::Result<()> {
+    if m.is_present("explain") {
+        return explain(reg, &m);
+    }
     let mut transform = create_chain(reg, m)?;
     std::io::copy(&mut transform, &mut io::stdout())?;
     Ok(())
@@ -86,10 +302,23 @@ specification in CHAIN.
 CHAIN is a colon-separated list of encoding transform.  A transform can be
 prefixed with - to reverse it (if possible).  A transform can be followed by one
 or more comma-separated parenthesized arguments as well.  Instead of
-parentheses, a single comma may be used.
+parentheses, a single comma may be used.  An argument value containing special
+characters may be quoted with single quotes (taken literally) or double quotes
+(in which a backslash escapes the next character), or each special character may
+be preceded by a backslash.
+
+A colon-separated list of transforms may be grouped in parentheses and used in
+place of a single transform; prefixing the group with - reverses the entire
+group.  A transform or group may be followed by a repetition count in braces.
+A group may contain several sub-chains separated by |, in which case the entire
+input is run through each in turn and the output of the first one to succeed is
+used.
 
 For example, '-hex:hash(sha256):base64' (or '-hex:hash,sha256:base64') decodes a
 hex-encoded string, hashes it with SHA-256, and converts the result to base64.
+Similarly, '-(gzip:base64):hex' decodes base64, decompresses the result, and
+converts it to hex, and 'base64{3}' encodes data with base64 three times.
+'-(hex|base64)' decodes data that is either hex or base64 encoded.
 
 If --reverse is specified, reverse the order of transforms in order and in sense.
 
@@ -103,12 +332,29 @@ The following transforms are available:
         }
     }
     s += &v.join("\n");
+    if reg.aliases().next().is_some() {
+        s += &tr!("
+
+The following aliases are defined:
+");
+        let v: Vec<String> = reg
+            .aliases()
+            .map(|(name, chain)| format!("  {} = {}", name, chain))
+            .collect();
+        s += &v.join("\n");
+    }
     s
 }
 
 fn main() {
     tr_init!(format!("{}/locale", env!("sharedir")));
-    let reg = CodecRegistry::new();
+    let mut reg = CodecRegistry::new();
+    // Report a broken alias file right away, but only exit once the arguments have been parsed,
+    // so that --help still works.
+    let alias_err = load_aliases(&mut reg).err();
+    if let Some(ref msg) = alias_err {
+        eprintln!("muter: {}", msg);
+    }
     let help = help(&reg);
     let matches = App::new("muter")
         .about(tr!("Encodes and decodes byte sequences").as_str())
@@ -134,6 +380,19 @@ fn main() {
                 .takes_value(true)
                 .help(tr!("Size of buffer").as_str()),
         )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .help(tr!("Number of threads to use for each stage which supports it").as_str()),
+        )
+        .arg(
+            Arg::with_name("max-output")
+                .long("max-output")
+                .value_name(tr!("SIZE").as_str())
+                .takes_value(true)
+                .help(tr!("Fail if any stage produces more than SIZE bytes").as_str()),
+        )
         .arg(
             Arg::with_name("strict")
                 .long("strict")
@@ -145,6 +404,25 @@ fn main() {
                 .conflicts_with("strict")
                 .help(tr!("Enable non-strict decoding").as_str()),
         )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help(tr!("Report which alternative of each alternation matched").as_str()),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .value_name(tr!("FORMAT").as_str())
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["text", "json"])
+                .help(
+                    tr!("Check the chain and describe each stage instead of processing input")
+                        .as_str(),
+                ),
+        )
         .arg(
             Arg::with_name("INPUT")
                 .help(tr!("Input files to process").as_str())
@@ -153,9 +431,19 @@ fn main() {
         )
         .after_help(&*help)
         .get_matches();
+    if alias_err.is_some() {
+        process::exit(2);
+    }
     if let Err(e) = process(&reg, matches) {
         if let Some(err) = e.get_ref() {
-            eprintln!("muter: {}", err);
+            match err.downcast_ref::<chain::StageError>() {
+                // The chain is shown under the message, so it isn't repeated in it.
+                Some(err) => {
+                    eprintln!("muter: {}: {}", err.stage(), err.error);
+                    eprintln!("{}", stage_caret(err));
+                }
+                None => eprintln!("muter: {}", err),
+            }
             process::exit(2);
         } else if e.kind() == io::ErrorKind::BrokenPipe {
             process::exit(141);